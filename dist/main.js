@@ -361,47 +361,151 @@ function updateDebugPanel(results) {
     setUserStatus(results);
 }
 
+// Shared text: the view version the textarea was last synced to, and whether
+// an edit is on its way to the backend (further typing waits for it)
+let sharedVersion = 0;
+let editInFlight = false;
+let editPending = false;
+let remoteChanged = false;
+
+// Replace the textarea content, keeping the caret next to the same text
+function showSharedText(text) {
+    if (!textarea || textarea.value === text) return;
+    const old = textarea.value;
+    let prefix = 0;
+    while (prefix < old.length && prefix < text.length && old[prefix] === text[prefix]) {
+        prefix++;
+    }
+    const shift = text.length - old.length;
+    const adjust = (offset) => (offset > prefix ? Math.max(prefix, offset + shift) : offset);
+    const [start, end] = [adjust(textarea.selectionStart), adjust(textarea.selectionEnd)];
+    textarea.value = text;
+    textarea.setSelectionRange(start, end);
+    updateByteCounter();
+}
+
+// Send the textarea to the backend as an edit of the view it was based on
+async function sendSharedEdit() {
+    if (editInFlight) {
+        editPending = true;
+        return;
+    }
+    editInFlight = true;
+    try {
+        do {
+            editPending = false;
+            let view = await invoke('edit_shared_text', { version: sharedVersion, text: textarea.value });
+            sharedVersion = view.version;
+            // Peers' edits that arrived while this one was sent
+            if (remoteChanged && !editPending) {
+                remoteChanged = false;
+                view = await invoke('get_shared_text');
+            }
+            // Typing that happened meanwhile is sent next, against the view it was based on
+            if (!editPending) {
+                sharedVersion = view.version;
+                showSharedText(view.text);
+            }
+        } while (editPending);
+        // Only trusted peers edit along; say so rather than look synced with nobody
+        const peers = await invoke('get_shared_text_peers');
+        if (peers.length > 0) {
+            setStatus(`Shared text synced with ${peers.length} peer${peers.length === 1 ? '' : 's'}`, '#38a169');
+        } else {
+            setStatus('Shared text kept on this device: set a peer to auto-accept to edit together', '#dd6b20');
+        }
+    } catch (error) {
+        console.error('Failed to edit shared text:', error);
+        const errorMessage = (error && (error.message || error.toString())) || 'Failed to send text';
+        setStatus(errorMessage, '#e53e3e');
+    } finally {
+        editInFlight = false;
+    }
+}
+
 // Text area event handler
 function setupTextAreaHandler() {
     if (!textarea) {
         console.error('Textarea not found');
         return;
     }
-    
-    let lastText = '';
-    textarea.addEventListener('input', async () => {
-        const currentText = textarea.value;
-        
+
+    if (invoke) {
+        invoke('get_shared_text').then((view) => {
+            sharedVersion = view.version;
+            showSharedText(view.text);
+        }).catch((error) => console.error('Failed to load shared text:', error));
+        refreshInbox();
+    }
+
+    textarea.addEventListener('input', () => {
         // Update byte counter on every input
         updateByteCounter();
-        
-        // Only send if text has changed and we have Tauri APIs
-        if (currentText !== lastText && invoke) {
-            lastText = currentText;
-            
-            try {
-                // Send text to all discovered peers
-                await invoke('send_text_to_all_peers', { text: currentText });
-                console.log('Sent text to all peers:', currentText);
-                
-                // Check if there are any peers to send to
-                const peerCount = await invoke('get_peer_count');
-                if (peerCount === 0) {
-                    console.log('No peers available - text not sent');
-                    setStatus('No peers available - text not sent', '#e53e3e');
-                } else {
-                    setStatus(`Text sent to ${peerCount} peer(s)`, '#38a169');
-                }
-            } catch (error) {
-                console.error('Failed to send text:', error);
-                const errorMessage = (error && (error.message || error.toString())) || 'Failed to send text';
-                setStatus(errorMessage, '#e53e3e');
-            }
+        if (invoke) {
+            sendSharedEdit();
         }
     });
 }
 
+// Pending inbox: texts from peers that aren't auto-accepted wait here, oldest shown first
+const inbox = document.getElementById('inbox');
+const inboxText = document.getElementById('inbox-text');
+let pendingMessages = [];
+
+function showInbox() {
+    const next = pendingMessages[0];
+    if (!next) {
+        inbox.hidden = true;
+        return;
+    }
+    const sender = next.message.hostname || next.message.sender_id || 'peer';
+    const more = pendingMessages.length > 1 ? ` (+${pendingMessages.length - 1} more)` : '';
+    inboxText.textContent = `${sender} sent ${next.message.size} bytes${more}`;
+    inbox.hidden = false;
+}
+
+async function refreshInbox() {
+    if (!invoke) return;
+    try {
+        pendingMessages = await invoke('get_pending_messages');
+    } catch (error) {
+        console.error('Failed to load pending messages:', error);
+    }
+    showInbox();
+}
+
+async function decidePending(command) {
+    const next = pendingMessages[0];
+    if (!next || !invoke) return;
+    try {
+        // An accepted text arrives through the usual text-received event
+        await invoke(command, { id: next.id });
+    } catch (error) {
+        console.error(`Failed to ${command}:`, error);
+    }
+    await refreshInbox();
+}
+
+document.getElementById('inbox-accept').addEventListener('click', () => decidePending('accept_message'));
+document.getElementById('inbox-reject').addEventListener('click', () => decidePending('reject_message'));
+
 // Main initialization function
+// Message id of the chunked send currently in progress, for cancelling it
+let activeTransferId = null;
+
+document.addEventListener('keydown', async (event) => {
+    if (event.key !== 'Escape' || !activeTransferId) {
+        return;
+    }
+    if (invoke) {
+        try {
+            await invoke('cancel_transfer', { messageId: activeTransferId });
+        } catch (error) {
+            console.error('Failed to cancel transfer:', error);
+        }
+    }
+});
+
 async function initializeApp() {
     try {
         console.log('DOM loaded, starting application...');
@@ -440,14 +544,17 @@ async function initializeApp() {
                         window.__TAURI__.event.listen('text-received', (event) => {
                             console.log('Received text from peer:', event.payload);
                             if (textarea) {
-                                const incoming = typeof event.payload === 'string' ? event.payload : '';
+                                // Payload is { sender_id, hostname, source_addr, message_id, sent_at, size, chunked, text }
+                                const payload = event.payload || {};
+                                const incoming = typeof payload.text === 'string' ? payload.text : '';
+                                const sender = payload.hostname || payload.sender_id || 'peer';
                                 textarea.value = incoming;
                                 // Update byte counter when text is received
                                 updateByteCounter();
                                 // Auto-copy to clipboard (or clear if empty)
                                 if (incoming.trim()) {
                                     copyToClipboard(incoming);
-                                    setStatus('Received and copied to clipboard', '#38a169');
+                                    setStatus(`Received from ${sender} and copied to clipboard`, '#38a169');
                                 } else {
                                     // Write empty string to effectively clear clipboard
                                     copyToClipboard('');
@@ -455,6 +562,57 @@ async function initializeApp() {
                                 }
                             }
                         });
+                        window.__TAURI__.event.listen('message-pending', () => {
+                            refreshInbox();
+                            setStatus('Message waiting to be accepted', '#dd6b20');
+                        });
+                        window.__TAURI__.event.listen('shared-text-changed', (event) => {
+                            // Payload is { version, text }; local edits in flight pick it up when they return
+                            const view = event.payload || {};
+                            if (typeof view.text !== 'string') {
+                                return;
+                            }
+                            if (editInFlight) {
+                                remoteChanged = true;
+                                return;
+                            }
+                            sharedVersion = view.version;
+                            showSharedText(view.text);
+                            setStatus('Shared text updated by a peer', '#3182ce');
+                        });
+                        window.__TAURI__.event.listen('transfer-progress', (event) => {
+                            // Payload is { message_id, peer_id, direction, chunks_done, total_chunks, bytes_done, total_bytes, bytes_per_sec, eta_secs }
+                            const progress = event.payload || {};
+                            const sending = progress.direction === 'sent';
+                            if (!progress.total_chunks || progress.chunks_done >= progress.total_chunks) {
+                                if (sending && activeTransferId === progress.message_id) {
+                                    activeTransferId = null;
+                                }
+                                return;
+                            }
+                            if (sending) {
+                                activeTransferId = progress.message_id;
+                            }
+                            const percent = Math.floor((progress.chunks_done / progress.total_chunks) * 100);
+                            const verb = sending ? 'Sending' : 'Receiving';
+                            const eta = progress.eta_secs != null ? `, ${Math.ceil(progress.eta_secs)}s left` : '';
+                            const hint = sending ? ' (Esc to cancel)' : '';
+                            setStatus(`${verb}... ${percent}%${eta}${hint}`, '#3182ce');
+                        });
+                        window.__TAURI__.event.listen('transfer-cancelled', (event) => {
+                            const cancelled = event.payload || {};
+                            if (activeTransferId === cancelled.message_id) {
+                                activeTransferId = null;
+                            }
+                            const what = cancelled.direction === 'sent' ? 'Send' : 'Incoming transfer';
+                            setStatus(`${what} cancelled`, '#dd6b20');
+                        });
+                        window.__TAURI__.event.listen('transfer-failed', (event) => {
+                            // Payload is { message_id, peer_id, direction, reason }
+                            const failed = event.payload || {};
+                            console.warn('Rejected incoming message:', failed.reason);
+                            setStatus('Incoming message failed its integrity check and was discarded', '#e53e3e');
+                        });
                         console.log('Event listener set up successfully');
                     } catch (error) {
                         console.error('Failed to set up event listener:', error);
@@ -0,0 +1,470 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{params, Connection, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::discovery::MAX_MESSAGE_BYTES;
+
+/// Which list a rule is on
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessList {
+    /// Everything from the peer is ignored and it is hidden from the peer list
+    Block,
+    /// Once any peer is allowed, text is only accepted from allowed peers
+    Allow,
+}
+
+impl AccessList {
+    fn as_str(self) -> &'static str {
+        match self {
+            AccessList::Block => "block",
+            AccessList::Allow => "allow",
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self> {
+        match value {
+            "block" => Ok(AccessList::Block),
+            "allow" => Ok(AccessList::Allow),
+            other => Err(anyhow::anyhow!("Unknown access list {}", other)),
+        }
+    }
+}
+
+/// A peer on the block or allow list
+///
+/// Peer ids change every time LanShare starts, so a rule can also name the
+/// peer's IP address or hostname to keep matching it afterwards.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PeerRule {
+    pub list: AccessList,
+    pub peer_id: String,
+    pub ip: Option<IpAddr>,
+    pub hostname: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl PeerRule {
+    /// Whether the rule names this peer by id, address or hostname
+    pub fn matches(&self, peer_id: &str, ip: Option<IpAddr>, hostname: Option<&str>) -> bool {
+        names_peer((&self.peer_id, self.ip, self.hostname.as_deref()), peer_id, ip, hostname)
+    }
+}
+
+fn names_peer(named: (&str, Option<IpAddr>, Option<&str>), peer_id: &str, ip: Option<IpAddr>, hostname: Option<&str>) -> bool {
+    let (named_id, named_ip, named_host) = named;
+    named_id == peer_id
+        || named_ip.is_some_and(|named_ip| Some(named_ip) == ip)
+        || named_host.zip(hostname).is_some_and(|(named, host)| named.eq_ignore_ascii_case(host))
+}
+
+/// What a peer may do once it isn't blocked
+///
+/// There are no file or clipboard-request permissions: the protocol carries
+/// neither, so such flags would have nothing to enforce. They belong here
+/// once a message type for them exists.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Permissions {
+    /// Send texts
+    pub text: bool,
+    /// Live-mirror and collaboratively edit our text
+    pub live_mirror: bool,
+    /// Largest text accepted from the peer, in bytes
+    pub max_text_bytes: usize,
+    /// Apply texts right away instead of holding them in the inbox
+    pub auto_accept: bool,
+}
+
+impl Permissions {
+    /// Whether the shared text is exchanged with the peer: it may edit it,
+    /// and its edits apply without asking
+    pub fn shares_text(&self) -> bool {
+        self.live_mirror && self.auto_accept
+    }
+}
+
+impl Default for Permissions {
+    /// What peers without a policy of their own may do
+    fn default() -> Self {
+        Self {
+            text: true,
+            live_mirror: true,
+            max_text_bytes: MAX_MESSAGE_BYTES,
+            auto_accept: false,
+        }
+    }
+}
+
+/// Permissions for one peer, matched like a [`PeerRule`]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PeerPolicy {
+    pub peer_id: String,
+    pub ip: Option<IpAddr>,
+    pub hostname: Option<String>,
+    pub permissions: Permissions,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl PeerPolicy {
+    fn matches(&self, peer_id: &str, ip: Option<IpAddr>, hostname: Option<&str>) -> bool {
+        names_peer((&self.peer_id, self.ip, self.hostname.as_deref()), peer_id, ip, hostname)
+    }
+}
+
+/// SQLite-backed block and allow lists and per-peer permissions, cached for
+/// the receive path
+pub struct AccessStore {
+    conn: Mutex<Connection>,
+    rules: RwLock<Vec<PeerRule>>,
+    policies: RwLock<Vec<PeerPolicy>>,
+}
+
+impl AccessStore {
+    /// Open (or create) the access database at `path`
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create access list directory {}", dir.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open access database {}", path.display()))?;
+        Self::init(conn)
+    }
+
+    /// Open a throwaway store that lives only as long as the process
+    pub fn open_in_memory() -> Result<Self> {
+        let conn = Connection::open_in_memory()
+            .context("Failed to open in-memory access database")?;
+        Self::init(conn)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS peer_rules (
+                list TEXT NOT NULL,
+                peer_id TEXT NOT NULL,
+                ip TEXT,
+                hostname TEXT,
+                created_at TEXT NOT NULL,
+                PRIMARY KEY (list, peer_id)
+            );
+            CREATE TABLE IF NOT EXISTS peer_policies (
+                peer_id TEXT PRIMARY KEY,
+                ip TEXT,
+                hostname TEXT,
+                text INTEGER NOT NULL,
+                live_mirror INTEGER NOT NULL,
+                max_text_bytes INTEGER NOT NULL,
+                auto_accept INTEGER NOT NULL,
+                updated_at TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS settings (
+                name TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );",
+        ).context("Failed to initialize access schema")?;
+
+        let rules = {
+            let mut stmt = conn.prepare("SELECT list, peer_id, ip, hostname, created_at FROM peer_rules ORDER BY created_at")?;
+            let rules = stmt.query_map([], rule_from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            rules
+        };
+        let policies = {
+            let mut stmt = conn.prepare(
+                "SELECT peer_id, ip, hostname, text, live_mirror, max_text_bytes, auto_accept, updated_at
+                 FROM peer_policies ORDER BY updated_at",
+            )?;
+            let policies = stmt.query_map([], policy_from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            policies
+        };
+        Ok(Self { conn: Mutex::new(conn), rules: RwLock::new(rules), policies: RwLock::new(policies) })
+    }
+
+    /// Put `peer_id` on `list`, replacing its previous entry there
+    pub fn add(&self, list: AccessList, peer_id: &str, ip: Option<IpAddr>, hostname: Option<&str>) -> Result<PeerRule> {
+        let peer_id = peer_id.trim();
+        if peer_id.is_empty() {
+            return Err(anyhow::anyhow!("Peer id must not be empty"));
+        }
+        let rule = PeerRule {
+            list,
+            peer_id: peer_id.to_string(),
+            ip,
+            hostname: hostname.map(str::trim).filter(|host| !host.is_empty()).map(str::to_string),
+            created_at: Utc::now(),
+        };
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO peer_rules (list, peer_id, ip, hostname, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                list.as_str(),
+                rule.peer_id,
+                rule.ip.map(|ip| ip.to_string()),
+                rule.hostname,
+                rule.created_at.to_rfc3339_opts(SecondsFormat::Micros, true),
+            ],
+        )?;
+        let mut rules = self.rules.write().unwrap();
+        rules.retain(|existing| !(existing.list == list && existing.peer_id == rule.peer_id));
+        rules.push(rule.clone());
+        Ok(rule)
+    }
+
+    /// Take `peer_id` off `list`; returns whether it was on it
+    pub fn remove(&self, list: AccessList, peer_id: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute(
+            "DELETE FROM peer_rules WHERE list = ?1 AND peer_id = ?2",
+            params![list.as_str(), peer_id.trim()],
+        )? > 0;
+        self.rules.write().unwrap().retain(|rule| !(rule.list == list && rule.peer_id == peer_id.trim()));
+        Ok(removed)
+    }
+
+    /// Rules on `list`, or on both lists, oldest first
+    pub fn rules(&self, list: Option<AccessList>) -> Vec<PeerRule> {
+        self.rules.read().unwrap().iter()
+            .filter(|rule| list.is_none_or(|list| rule.list == list))
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_blocked(&self, peer_id: &str, ip: Option<IpAddr>, hostname: Option<&str>) -> bool {
+        self.rules.read().unwrap().iter()
+            .any(|rule| rule.list == AccessList::Block && rule.matches(peer_id, ip, hostname))
+    }
+
+    /// Whether text from this peer may be delivered: not blocked, and allowed
+    /// if the allow list is in use
+    pub fn accepts_text(&self, peer_id: &str, ip: Option<IpAddr>, hostname: Option<&str>) -> bool {
+        let rules = self.rules.read().unwrap();
+        let mut allow = rules.iter().filter(|rule| rule.list == AccessList::Allow).peekable();
+        let allowed = allow.peek().is_none() || allow.any(|rule| rule.matches(peer_id, ip, hostname));
+        allowed && !rules.iter().any(|rule| rule.list == AccessList::Block && rule.matches(peer_id, ip, hostname))
+    }
+
+    /// Give `peer_id` its own permissions, replacing any it had
+    pub fn set_policy(&self, peer_id: &str, ip: Option<IpAddr>, hostname: Option<&str>, permissions: Permissions) -> Result<PeerPolicy> {
+        let peer_id = peer_id.trim();
+        if peer_id.is_empty() {
+            return Err(anyhow::anyhow!("Peer id must not be empty"));
+        }
+        if permissions.max_text_bytes > MAX_MESSAGE_BYTES {
+            return Err(anyhow::anyhow!("Text size limit {} exceeds the maximum of {}", permissions.max_text_bytes, MAX_MESSAGE_BYTES));
+        }
+        let policy = PeerPolicy {
+            peer_id: peer_id.to_string(),
+            ip,
+            hostname: hostname.map(str::trim).filter(|host| !host.is_empty()).map(str::to_string),
+            permissions,
+            updated_at: Utc::now(),
+        };
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO peer_policies
+                 (peer_id, ip, hostname, text, live_mirror, max_text_bytes, auto_accept, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                policy.peer_id,
+                policy.ip.map(|ip| ip.to_string()),
+                policy.hostname,
+                permissions.text,
+                permissions.live_mirror,
+                permissions.max_text_bytes as i64,
+                permissions.auto_accept,
+                policy.updated_at.to_rfc3339_opts(SecondsFormat::Micros, true),
+            ],
+        )?;
+        let mut policies = self.policies.write().unwrap();
+        policies.retain(|existing| existing.peer_id != policy.peer_id);
+        policies.push(policy.clone());
+        Ok(policy)
+    }
+
+    /// Return `peer_id` to the default permissions; returns whether it had its own
+    pub fn remove_policy(&self, peer_id: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM peer_policies WHERE peer_id = ?1", params![peer_id.trim()])? > 0;
+        self.policies.write().unwrap().retain(|policy| policy.peer_id != peer_id.trim());
+        Ok(removed)
+    }
+
+    /// Peers with permissions of their own, least recently changed first
+    pub fn policies(&self) -> Vec<PeerPolicy> {
+        self.policies.read().unwrap().clone()
+    }
+
+    /// The key shared by the peers we talk to, if one is set
+    pub fn network_key(&self) -> Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT value FROM settings WHERE name = 'network_key'")?;
+        let mut rows = stmt.query_map([], |row| row.get(0))?;
+        Ok(rows.next().transpose()?)
+    }
+
+    /// Remember `key` as the network key; None or an empty key removes it
+    pub fn set_network_key(&self, key: Option<&str>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        match key.filter(|key| !key.is_empty()) {
+            Some(key) => conn.execute(
+                "INSERT OR REPLACE INTO settings (name, value) VALUES ('network_key', ?1)",
+                params![key],
+            )?,
+            None => conn.execute("DELETE FROM settings WHERE name = 'network_key'", [])?,
+        };
+        Ok(())
+    }
+
+    /// What this peer may do: its own policy by id, else the newest one
+    /// naming its address or hostname, else the defaults
+    pub fn permissions(&self, peer_id: &str, ip: Option<IpAddr>, hostname: Option<&str>) -> Permissions {
+        let policies = self.policies.read().unwrap();
+        policies.iter()
+            .find(|policy| policy.peer_id == peer_id)
+            .or_else(|| policies.iter().rev().find(|policy| policy.matches(peer_id, ip, hostname)))
+            .map(|policy| policy.permissions)
+            .unwrap_or_default()
+    }
+}
+
+fn rule_from_row(row: &Row<'_>) -> rusqlite::Result<PeerRule> {
+    let list: String = row.get(0)?;
+    let ip: Option<String> = row.get(2)?;
+    let created_at: String = row.get(4)?;
+    let to_sql_error = |e: anyhow::Error| rusqlite::Error::FromSqlConversionFailure(
+        0, rusqlite::types::Type::Text, e.into(),
+    );
+
+    Ok(PeerRule {
+        list: AccessList::parse(&list).map_err(to_sql_error)?,
+        peer_id: row.get(1)?,
+        ip: ip.map(|ip| ip.parse()).transpose().map_err(|e: std::net::AddrParseError| to_sql_error(e.into()))?,
+        hostname: row.get(3)?,
+        created_at: DateTime::parse_from_rfc3339(&created_at)
+            .map_err(|e| to_sql_error(e.into()))?
+            .with_timezone(&Utc),
+    })
+}
+
+fn policy_from_row(row: &Row<'_>) -> rusqlite::Result<PeerPolicy> {
+    let ip: Option<String> = row.get(1)?;
+    let max_text_bytes: i64 = row.get(5)?;
+    let updated_at: String = row.get(7)?;
+    let to_sql_error = |e: anyhow::Error| rusqlite::Error::FromSqlConversionFailure(
+        0, rusqlite::types::Type::Text, e.into(),
+    );
+
+    Ok(PeerPolicy {
+        peer_id: row.get(0)?,
+        ip: ip.map(|ip| ip.parse()).transpose().map_err(|e: std::net::AddrParseError| to_sql_error(e.into()))?,
+        hostname: row.get(2)?,
+        permissions: Permissions {
+            text: row.get(3)?,
+            live_mirror: row.get(4)?,
+            max_text_bytes: max_text_bytes as usize,
+            auto_accept: row.get(6)?,
+        },
+        updated_at: DateTime::parse_from_rfc3339(&updated_at)
+            .map_err(|e| to_sql_error(e.into()))?
+            .with_timezone(&Utc),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const IP: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7)));
+
+    #[test]
+    fn test_block_matches_id_address_or_hostname() {
+        let store = AccessStore::open_in_memory().unwrap();
+        store.add(AccessList::Block, " noisy ", IP, Some("Laptop")).unwrap();
+
+        assert!(store.is_blocked("noisy", None, None));
+        // The same machine after a restart, with a new peer id
+        assert!(store.is_blocked("restarted", IP, None));
+        assert!(store.is_blocked("restarted", None, Some("laptop")));
+        assert!(!store.is_blocked("someone-else", Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 8))), Some("desktop")));
+        assert!(!store.accepts_text("noisy", None, None));
+        assert!(store.accepts_text("someone-else", None, None));
+
+        assert!(store.remove(AccessList::Block, "noisy").unwrap());
+        assert!(!store.remove(AccessList::Block, "noisy").unwrap());
+        assert!(!store.is_blocked("noisy", IP, None));
+        assert!(store.add(AccessList::Block, "  ", None, None).is_err());
+    }
+
+    #[test]
+    fn test_allow_list_limits_text_once_used() {
+        let store = AccessStore::open_in_memory().unwrap();
+        store.add(AccessList::Allow, "mine", None, Some("desktop")).unwrap();
+        assert!(store.accepts_text("mine", None, None));
+        assert!(store.accepts_text("other-id", None, Some("DESKTOP")));
+        assert!(!store.accepts_text("stranger", IP, Some("laptop")));
+        // Allowing doesn't block anything else
+        assert!(!store.is_blocked("stranger", IP, None));
+
+        // Blocking wins over allowing
+        store.add(AccessList::Block, "mine", None, None).unwrap();
+        assert!(!store.accepts_text("mine", None, None));
+        assert_eq!(store.rules(Some(AccessList::Allow)).len(), 1);
+        assert_eq!(store.rules(None).len(), 2);
+    }
+
+    #[test]
+    fn test_permissions_fall_back_from_id_to_address_to_defaults() {
+        let store = AccessStore::open_in_memory().unwrap();
+        let trusted = Permissions { auto_accept: true, ..Permissions::default() };
+        let muted = Permissions { text: false, live_mirror: false, ..Permissions::default() };
+        store.set_policy("phone", IP, None, trusted).unwrap();
+        store.set_policy("old-phone-id", None, Some("phone"), muted).unwrap();
+
+        assert_eq!(store.permissions("phone", None, Some("phone")), trusted);
+        // After a restart the phone is matched by address or hostname instead
+        assert_eq!(store.permissions("restarted", IP, None), trusted);
+        assert_eq!(store.permissions("restarted", None, Some("Phone")), muted);
+        assert_eq!(store.permissions("stranger", None, None), Permissions::default());
+        // Policies don't turn on the allow list
+        assert!(store.accepts_text("stranger", None, None));
+
+        let too_big = Permissions { max_text_bytes: MAX_MESSAGE_BYTES + 1, ..Permissions::default() };
+        assert!(store.set_policy("phone", None, None, too_big).is_err());
+        assert!(store.remove_policy("phone").unwrap());
+        assert_eq!(store.permissions("phone", IP, None), Permissions::default());
+    }
+
+    #[test]
+    fn test_rules_persist_across_reopen() {
+        let dir = std::env::temp_dir().join(format!("lanshare-access-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("access.db");
+        {
+            let store = AccessStore::open(&path).unwrap();
+            store.add(AccessList::Block, "noisy", IP, None).unwrap();
+            store.add(AccessList::Block, "noisy", IP, Some("laptop")).unwrap();
+            store.set_policy("quiet", None, None, Permissions { max_text_bytes: 1024, ..Permissions::default() }).unwrap();
+        }
+        let store = AccessStore::open(&path).unwrap();
+        let rules = store.rules(None);
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].ip, IP);
+        assert_eq!(rules[0].hostname.as_deref(), Some("laptop"));
+        assert_eq!(store.permissions("quiet", None, None).max_text_bytes, 1024);
+        assert_eq!(store.network_key().unwrap(), None);
+        store.set_network_key(Some("secret")).unwrap();
+        drop(store);
+        let store = AccessStore::open(&path).unwrap();
+        assert_eq!(store.network_key().unwrap().as_deref(), Some("secret"));
+        store.set_network_key(None).unwrap();
+        assert_eq!(store.network_key().unwrap(), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
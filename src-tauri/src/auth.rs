@@ -0,0 +1,200 @@
+//! Message authentication and replay protection.
+//!
+//! With a network key set, every datagram is wrapped in an envelope carrying
+//! an HMAC-SHA256 of the message, and datagrams without a valid one are
+//! dropped. Every message carries a nonce and a timestamp; the listener
+//! rejects text and control messages with a nonce already seen from the same
+//! sender, and with a key set also those outside the freshness window.
+//! Without a key the nonce and timestamp could be rewritten by whoever
+//! captured the datagram, so only a key makes replays impossible rather than
+//! just inconvenient, and clocks that disagree by more than the window only
+//! cost texts when it does.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use sha2::Sha256;
+
+use crate::discovery::DiscoveryMessage;
+
+/// How far a message's timestamp may be from our clock, either way
+pub const FRESHNESS_WINDOW: Duration = Duration::seconds(60);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A message and the MAC of its exact bytes
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    mac: Vec<u8>,
+    message: Box<RawValue>,
+}
+
+/// Encodes and decodes datagrams, authenticating them when a key is set
+#[derive(Debug, Default)]
+pub struct MessageAuth {
+    key: RwLock<Option<Vec<u8>>>,
+}
+
+impl MessageAuth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `key` from now on; None or an empty key turns authentication off
+    pub fn set_key(&self, key: Option<&str>) {
+        *self.key.write().unwrap() = key.filter(|key| !key.is_empty()).map(|key| key.as_bytes().to_vec());
+    }
+
+    pub fn has_key(&self) -> bool {
+        self.key.read().unwrap().is_some()
+    }
+
+    /// Serialize `message`, sealed with a MAC when a key is set
+    pub fn encode(&self, message: &DiscoveryMessage) -> serde_json::Result<Vec<u8>> {
+        let key = self.key.read().unwrap();
+        let Some(key) = key.as_deref() else {
+            return serde_json::to_vec(message);
+        };
+        let message = serde_json::value::to_raw_value(message)?;
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(message.get().as_bytes());
+        serde_json::to_vec(&Sealed { mac: mac.finalize().into_bytes().to_vec(), message })
+    }
+
+    /// Parse a datagram; with a key set it must be sealed with that key
+    pub fn decode(&self, bytes: &[u8]) -> Result<DiscoveryMessage> {
+        let key = self.key.read().unwrap();
+        let Some(key) = key.as_deref() else {
+            return serde_json::from_slice(bytes).context("Failed to deserialize discovery message");
+        };
+        let sealed: Sealed = serde_json::from_slice(bytes).context("Message is not authenticated")?;
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(sealed.message.get().as_bytes());
+        mac.verify_slice(&sealed.mac).map_err(|_| anyhow::anyhow!("Message authentication failed"))?;
+        serde_json::from_str(sealed.message.get()).context("Failed to deserialize discovery message")
+    }
+}
+
+/// Whether `timestamp` is within the freshness window around `now`
+pub fn check_fresh(timestamp: DateTime<Utc>, now: DateTime<Utc>) -> Result<()> {
+    let age = now.signed_duration_since(timestamp);
+    if age.abs() > FRESHNESS_WINDOW {
+        return Err(anyhow::anyhow!("Message sent at {} is outside the {}s freshness window", timestamp, FRESHNESS_WINDOW.num_seconds()));
+    }
+    Ok(())
+}
+
+/// Nonces seen per sender within the freshness window
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    seen: Mutex<HashMap<String, HashMap<String, DateTime<Utc>>>>,
+}
+
+impl ReplayGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept a message from `sender` only if its nonce is new
+    ///
+    /// Freshness is checked separately with `check_fresh`; a nonce is
+    /// remembered until a message carrying it would no longer pass that.
+    pub fn check(&self, sender: &str, nonce: Option<&str>, timestamp: DateTime<Utc>) -> Result<()> {
+        self.check_at(sender, nonce, timestamp, Utc::now())
+    }
+
+    fn check_at(&self, sender: &str, nonce: Option<&str>, timestamp: DateTime<Utc>, now: DateTime<Utc>) -> Result<()> {
+        let nonce = nonce.ok_or_else(|| anyhow::anyhow!("Message has no nonce"))?;
+        let mut seen = self.seen.lock().unwrap();
+        let nonces = seen.entry(sender.to_string()).or_default();
+        if nonces.contains_key(nonce) {
+            return Err(anyhow::anyhow!("Nonce {} was already seen from {}", nonce, sender));
+        }
+        nonces.insert(nonce.to_string(), timestamp.max(now));
+        Ok(())
+    }
+
+    /// Forget nonces seen longer ago than the freshness window; returns how many
+    pub fn prune(&self) -> usize {
+        self.prune_at(Utc::now())
+    }
+
+    fn prune_at(&self, now: DateTime<Utc>) -> usize {
+        let mut seen = self.seen.lock().unwrap();
+        let mut pruned = 0;
+        seen.retain(|_, nonces| {
+            let before = nonces.len();
+            nonces.retain(|_, timestamp| now.signed_duration_since(*timestamp) <= FRESHNESS_WINDOW);
+            pruned += before - nonces.len();
+            !nonces.is_empty()
+        });
+        pruned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::MessageType;
+
+    fn message() -> DiscoveryMessage {
+        let mut message = DiscoveryMessage::new(MessageType::TextMessage, "sender".to_string(), 7878, None);
+        message.text = Some("hello".to_string());
+        message
+    }
+
+    #[test]
+    fn test_sealed_messages_need_the_same_key() {
+        let auth = MessageAuth::new();
+        auth.set_key(Some("correct horse"));
+        let sealed = auth.encode(&message()).unwrap();
+        assert_eq!(auth.decode(&sealed).unwrap().text.as_deref(), Some("hello"));
+
+        let other = MessageAuth::new();
+        other.set_key(Some("battery staple"));
+        assert!(other.decode(&sealed).is_err());
+        // Unsealed messages aren't accepted once a key is set, and vice versa
+        let plain = MessageAuth::new();
+        assert!(auth.decode(&plain.encode(&message()).unwrap()).is_err());
+        assert!(plain.decode(&sealed).is_err());
+
+        // Any change to the sealed message breaks the MAC
+        let tampered = String::from_utf8(sealed).unwrap().replace("hello", "jello");
+        assert!(auth.decode(tampered.as_bytes()).is_err());
+
+        auth.set_key(Some(""));
+        assert!(!auth.has_key());
+    }
+
+    #[test]
+    fn test_replayed_or_stale_messages_are_rejected() {
+        let guard = ReplayGuard::new();
+        let now = Utc::now();
+        guard.check_at("sender", Some("n1"), now, now).unwrap();
+        assert!(guard.check_at("sender", Some("n1"), now, now).is_err());
+        // Nonces are per sender
+        guard.check_at("other", Some("n1"), now, now).unwrap();
+        assert!(guard.check_at("sender", None, now, now).is_err());
+
+        let too_old = now - FRESHNESS_WINDOW - Duration::seconds(1);
+        let too_new = now + FRESHNESS_WINDOW + Duration::seconds(1);
+        check_fresh(now, now).unwrap();
+        assert!(check_fresh(too_old, now).is_err());
+        assert!(check_fresh(too_new, now).is_err());
+
+        // A nonce is kept while a message carrying it could still pass the
+        // freshness check, counting from its timestamp if that is ahead
+        let ahead = now + Duration::seconds(30);
+        guard.check_at("sender", Some("n2"), ahead, now).unwrap();
+        let later = now + FRESHNESS_WINDOW + Duration::seconds(1);
+        assert_eq!(guard.prune_at(later), 2);
+        assert!(guard.check_at("sender", Some("n2"), ahead, later).is_err());
+        assert_eq!(guard.prune_at(ahead + FRESHNESS_WINDOW + Duration::seconds(1)), 1);
+        assert!(guard.seen.lock().unwrap().is_empty());
+    }
+}
@@ -1,5 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -7,22 +8,52 @@ use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
-use get_if_addrs::{get_if_addrs, IfAddr};
-use tokio::net::UdpSocket;
-use tokio::sync::RwLock;
+use sha2::{Digest, Sha256};
+use tokio::sync::{broadcast, RwLock, Semaphore};
+use tokio::task::JoinSet;
 use tokio::time::{interval, sleep};
 use uuid::Uuid;
 use tauri::AppHandle;
 use tauri::Emitter;
 
+use crate::access::{AccessStore, Permissions};
+use crate::auth::{check_fresh, MessageAuth, ReplayGuard};
+use crate::history::{Direction, HistoryEntry, HistoryStore};
+use crate::inbox::Inbox;
+use crate::mirror::{DeltaHeader, MirrorHeader, MirrorInbox, MirrorOutbox, TextDelta};
+use crate::progress::{self, ProgressReporter, ProgressThrottle, TransferCancelled, TransferFailed, TransferProgress};
+use crate::ratelimit::{RateLimiter, TrafficClass};
+use crate::rooms::{normalize_room_name, Rooms};
+use crate::shared_text::{encode_batches, SharedDoc, SharedTextView, StampedOp, VersionVector};
+use crate::transfer::{ActiveTransfers, CancelToken, OutboundQueue, PeerSendResult, RateController, SendMode, SendStatus};
+use crate::transport::{Transport, TransportFactory, UdpTransportFactory};
+
 // Configuration constants for chunking
 pub const MAX_MESSAGE_BYTES: usize = 256 * 1024; // 256 KB max message size
 const CHUNK_PAYLOAD_BYTES: usize = 800;      // 800 bytes per chunk (optimized for reliability)
+const MAX_TOTAL_CHUNKS: u32 = MAX_MESSAGE_BYTES.div_ceil(CHUNK_PAYLOAD_BYTES) as u32; // Chunks of the largest allowed message
+const MAX_REASSEMBLIES_PER_PEER: usize = 8;  // Incomplete messages one peer may have open
+const MAX_REASSEMBLIES: usize = 64;          // Incomplete messages open across all peers
+const MAX_BUFFERED_BYTES_PER_PEER: usize = 4 * MAX_MESSAGE_BYTES; // Buffer space one peer's open messages may claim
+const MAX_BUFFERED_BYTES: usize = 32 * MAX_MESSAGE_BYTES;         // Buffer space all open messages may claim
 const REASSEMBLY_TIMEOUT_SECS: u64 = 10;     // 10 seconds timeout for incomplete messages (faster failure detection)
+const DISCOVERY_PORT: u16 = 7878;            // Well-known port for discovery and text traffic
+const TEXT_CHANNEL_CAPACITY: usize = 64;     // Buffered received texts for in-process subscribers
+const STALL_TIMEOUT: Duration = Duration::from_secs(2); // Give up on a peer that stops acknowledging chunks
+const MAX_PARALLEL_TRANSFERS: usize = 4;     // Recipients sent to at the same time
+const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 78, 78); // Group for one-to-many text sends
+const MULTICAST_BURST_INTERVAL: Duration = Duration::from_millis(2); // Pause between multicast bursts
+const RECENTLY_DELIVERED_CAPACITY: usize = 256; // Delivered message ids remembered per sender to drop duplicates
+const MIRROR_ACK_TIMEOUT: Duration = Duration::from_millis(250); // Wait for a live-mirror delta to be confirmed
+
+/// CRC32C of one chunk's payload
+fn chunk_checksum(data: &[u8]) -> u32 {
+    crc32c::crc32c(data)
+}
 
-/// Simple checksum function for chunk integrity
-fn simple_checksum(data: &[u8]) -> u32 {
-    data.iter().fold(0u32, |acc, &byte| acc.wrapping_add(byte as u32))
+/// Hex SHA-256 of a whole chunked message, checked once it is reassembled
+fn message_digest(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
 }
 
 /// Represents a discovered peer on the network
@@ -33,6 +64,9 @@ pub struct Peer {
     pub port: u16,
     pub last_seen: DateTime<Utc>,
     pub hostname: Option<String>,
+    /// Rooms the peer advertised in its last discovery message
+    #[serde(default)]
+    pub rooms: BTreeSet<String>,
 }
 
 impl Peer {
@@ -43,10 +77,19 @@ impl Peer {
             port,
             last_seen: Utc::now(),
             hostname,
+            rooms: BTreeSet::new(),
         }
     }
 
-    #[allow(dead_code)]
+    pub fn with_rooms(mut self, rooms: impl IntoIterator<Item = String>) -> Self {
+        self.rooms = rooms.into_iter().collect();
+        self
+    }
+
+    pub fn in_room(&self, room: &str) -> bool {
+        self.rooms.contains(room)
+    }
+
     pub fn socket_addr(&self) -> SocketAddr {
         SocketAddr::new(self.ip, self.port)
     }
@@ -65,6 +108,10 @@ pub enum MessageType {
     PeerDiscovery,
     TextMessage,   // Single-packet text message
     TextChunk,     // Chunked text message
+    TransferCancel, // Sender stopped a chunked message; drop its partial state
+    ChunkAck,      // Receiver got chunk `seq_no` of a message
+    ChunkNack,     // Receiver is missing the chunks listed in `missing`
+    MirrorAck,     // Receiver holds the mirrored text at version `mirror` (None: nothing)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -80,8 +127,128 @@ pub struct DiscoveryMessage {
     pub seq_no: Option<u32>,          // Sequence number of this chunk (0-based)
     pub total_chunks: Option<u32>,    // Total number of chunks in the message
     pub payload_len: Option<u32>,     // Length of payload in this chunk
-    pub checksum: Option<u32>,        // Simple checksum for integrity
+    pub checksum: Option<u32>,        // CRC32C of the payload
     pub payload: Option<Vec<u8>>,     // Chunk data (UTF-8 bytes)
+    #[serde(default)]
+    pub missing: Option<Vec<u32>>,    // Sequence numbers reported lost (ChunkNack)
+    #[serde(default)]
+    pub multicast: bool,              // Chunk was sent to the multicast group; acknowledge completion only
+    #[serde(default)]
+    pub rooms: Option<Vec<String>>,   // Rooms the sender joined (PeerDiscovery)
+    #[serde(default)]
+    pub room: Option<String>,         // Room a text is scoped to; None reaches everyone
+    #[serde(default)]
+    pub mirror: Option<MirrorHeader>, // Live-mirror version (text) or version held (MirrorAck)
+    #[serde(default)]
+    pub text_seq: Option<u64>,        // Per-sender text number; older texts than one delivered are dropped
+    #[serde(default)]
+    pub shared_ops: bool,             // Text is a JSON array of shared-text operations
+    #[serde(default)]
+    pub shared_versions: Option<VersionVector>, // Shared-text operations applied per replica (PeerDiscovery)
+    #[serde(default)]
+    pub digest: Option<String>,       // Hex SHA-256 of the whole message (first chunk)
+    #[serde(default)]
+    pub nonce: Option<String>,        // Unique per message; a nonce seen again from the sender is a replay
+    #[serde(default)]
+    pub recipients: Option<Vec<String>>, // Peers a multicast text is meant for; the rest of the group drops it
+}
+
+impl DiscoveryMessage {
+    /// A message of `message_type` with no text or chunk fields set
+    pub fn new(message_type: MessageType, peer_id: String, port: u16, hostname: Option<String>) -> Self {
+        Self {
+            message_type,
+            peer_id,
+            port,
+            hostname,
+            timestamp: Utc::now(),
+            text: None,
+            message_id: None,
+            seq_no: None,
+            total_chunks: None,
+            payload_len: None,
+            checksum: None,
+            payload: None,
+            missing: None,
+            multicast: false,
+            rooms: None,
+            room: None,
+            mirror: None,
+            text_seq: None,
+            shared_ops: false,
+            shared_versions: None,
+            digest: None,
+            nonce: Some(Uuid::new_v4().to_string()),
+            recipients: None,
+        }
+    }
+}
+
+/// Payload of the `text-received` event: a received text and where it came from
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReceivedText {
+    pub sender_id: String,
+    pub hostname: Option<String>,
+    pub source_addr: SocketAddr,
+    /// None only for texts from peers that predate message ids on single packets
+    pub message_id: Option<String>,
+    /// When the sender sent the message (first chunk for chunked messages)
+    pub sent_at: DateTime<Utc>,
+    /// Size of the text in bytes
+    pub size: usize,
+    pub chunked: bool,
+    /// Room the text was sent to, if any
+    pub room: Option<String>,
+    /// Live-mirror version; the text is always the full mirrored text
+    pub mirror_version: Option<u64>,
+    /// Sender's text number; a text older than one already delivered from
+    /// the same sender is dropped
+    pub seq: Option<u64>,
+    pub text: String,
+}
+
+/// Bounded set of recently delivered message ids, oldest evicted first
+#[derive(Debug, Default)]
+struct RecentMessageIds {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl RecentMessageIds {
+    fn contains(&self, message_id: &str) -> bool {
+        self.ids.contains(message_id)
+    }
+
+    /// Remember `message_id`; false if it was already known
+    fn insert(&mut self, message_id: &str) -> bool {
+        if !self.ids.insert(message_id.to_string()) {
+            return false;
+        }
+        self.order.push_back(message_id.to_string());
+        if self.order.len() > RECENTLY_DELIVERED_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Recently delivered message ids of each sender
+#[derive(Debug, Default)]
+struct DeliveredMessages {
+    by_sender: HashMap<String, RecentMessageIds>,
+}
+
+impl DeliveredMessages {
+    fn contains(&self, sender_id: &str, message_id: &str) -> bool {
+        self.by_sender.get(sender_id).is_some_and(|ids| ids.contains(message_id))
+    }
+
+    /// Record a delivery; false if `message_id` from `sender_id` was already delivered
+    fn insert(&mut self, sender_id: &str, message_id: &str) -> bool {
+        self.by_sender.entry(sender_id.to_string()).or_default().insert(message_id)
+    }
 }
 
 /// State for reassembling chunked messages
@@ -90,8 +257,14 @@ struct ReassemblyState {
     total_chunks: u32,
     received_chunks: Vec<Option<Vec<u8>>>,
     received_count: u32,
+    received_bytes: u64,
+    highest_seq: Option<u32>,
     started_at: Instant,
     sender_peer_id: String,
+    sent_at: DateTime<Utc>,
+    /// Expected SHA-256 of the whole message, from its first chunk
+    digest: Option<String>,
+    throttle: ProgressThrottle,
 }
 
 impl ReassemblyState {
@@ -100,8 +273,13 @@ impl ReassemblyState {
             total_chunks,
             received_chunks: vec![None; total_chunks as usize],
             received_count: 0,
+            received_bytes: 0,
+            highest_seq: None,
             started_at: Instant::now(),
             sender_peer_id,
+            sent_at: Utc::now(),
+            digest: None,
+            throttle: ProgressThrottle::default(),
         }
     }
 
@@ -110,7 +288,9 @@ impl ReassemblyState {
             return false;
         }
         
+        self.highest_seq = self.highest_seq.max(Some(seq_no));
         if self.received_chunks[seq_no as usize].is_none() {
+            self.received_bytes += payload.len() as u64;
             self.received_chunks[seq_no as usize] = Some(payload);
             self.received_count += 1;
         }
@@ -118,15 +298,62 @@ impl ReassemblyState {
         self.received_count == self.total_chunks
     }
 
+    /// Every chunk not received yet
+    fn missing(&self) -> Vec<u32> {
+        (0..self.total_chunks)
+            .filter(|seq| self.received_chunks[*seq as usize].is_none())
+            .collect()
+    }
+
+    /// Chunks skipped over if `seq_no` arrives next, i.e. probably lost
+    fn gaps_before(&self, seq_no: u32) -> Vec<u32> {
+        let from = self.highest_seq.map_or(0, |highest| highest + 1);
+        (from..seq_no.min(self.total_chunks))
+            .filter(|seq| self.received_chunks[*seq as usize].is_none())
+            .collect()
+    }
+
     fn is_complete(&self) -> bool {
         self.received_count == self.total_chunks
     }
 
+    /// Most bytes this message can buffer, going by its chunk count
+    fn capacity_bytes(&self) -> usize {
+        self.total_chunks as usize * CHUNK_PAYLOAD_BYTES
+    }
+
     fn is_stale(&self) -> bool {
         self.started_at.elapsed().as_secs() > REASSEMBLY_TIMEOUT_SECS
     }
 
-    fn reassemble(&self) -> Result<String> {
+    fn progress(&self, message_id: &str) -> TransferProgress {
+        let (bytes_per_sec, eta_secs) = progress::estimate(
+            self.received_count, self.total_chunks, self.received_bytes, self.started_at.elapsed());
+        TransferProgress {
+            message_id: message_id.to_string(),
+            peer_id: self.sender_peer_id.clone(),
+            direction: Direction::Received,
+            chunks_done: self.received_count,
+            total_chunks: self.total_chunks,
+            bytes_done: self.received_bytes,
+            total_bytes: None,
+            bytes_per_sec,
+            eta_secs,
+        }
+    }
+
+    /// Throw away every chunk so the whole message is received again
+    fn discard_chunks(&mut self) {
+        self.received_chunks = vec![None; self.total_chunks as usize];
+        self.received_count = 0;
+        self.received_bytes = 0;
+        self.highest_seq = None;
+        // The first chunk may have been the bad one
+        self.digest = None;
+    }
+
+    /// The complete message, if it matches its digest
+    fn verified_bytes(&self) -> Result<Vec<u8>> {
         if !self.is_complete() {
             return Err(anyhow::anyhow!("Message not complete"));
         }
@@ -140,7 +367,17 @@ impl ReassemblyState {
             }
         }
 
-        let result = String::from_utf8(complete_data)
+        let expected = self.digest.as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Message has no digest"))?;
+        let actual = message_digest(&complete_data);
+        if actual != expected {
+            return Err(anyhow::anyhow!("Digest mismatch: expected {}, got {}", expected, actual));
+        }
+        Ok(complete_data)
+    }
+
+    fn reassemble(&self) -> Result<String> {
+        let result = String::from_utf8(self.verified_bytes()?)
             .context("Failed to decode UTF-8")?;
         
         info!("Successfully reassembled message from peer {}: {} chars", self.sender_peer_id, result.len());
@@ -198,8 +435,27 @@ impl PeerRegistry {
         peers.values().cloned().collect()
     }
 
+    /// Get the peers that joined `room`
+    pub async fn get_peers_in_room(&self, room: &str) -> Vec<Peer> {
+        let peers = self.peers.read().await;
+        peers.values().filter(|peer| peer.in_room(room)).cloned().collect()
+    }
+
+    /// Get the peers in `room`, or every peer when there is none
+    pub async fn get_peers_for(&self, room: Option<&str>) -> Vec<Peer> {
+        match room {
+            Some(room) => self.get_peers_in_room(room).await,
+            None => self.get_peers().await,
+        }
+    }
+
+    /// Every room advertised by at least one peer
+    pub async fn known_rooms(&self) -> BTreeSet<String> {
+        let peers = self.peers.read().await;
+        peers.values().flat_map(|peer| peer.rooms.iter().cloned()).collect()
+    }
+
     /// Get a specific peer by ID
-    #[allow(dead_code)]
     pub async fn get_peer(&self, peer_id: &str) -> Option<Peer> {
         let peers = self.peers.read().await;
         peers.get(peer_id).cloned()
@@ -226,16 +482,11 @@ impl PeerRegistry {
         
         removed_count
     }
-
-    /// Get the number of peers
-    pub async fn peer_count(&self) -> usize {
-        self.peers.read().await.len()
-    }
 }
 
 /// UDP broadcaster for announcing presence on the network
 pub struct UdpBroadcaster {
-    socket: UdpSocket,
+    socket: Arc<dyn Transport>,
     peer_id: String,
     port: u16,
     hostname: Option<String>,
@@ -243,13 +494,10 @@ pub struct UdpBroadcaster {
 }
 
 impl UdpBroadcaster {
-    pub async fn new(port: u16, broadcast_interval: Duration) -> Result<Self> {
-        let socket = UdpSocket::bind("0.0.0.0:0")
+    pub async fn new(transport: &dyn TransportFactory, port: u16, broadcast_interval: Duration) -> Result<Self> {
+        let socket = transport.bind(0)
             .await
             .context("Failed to bind UDP socket for broadcasting")?;
-        
-        socket.set_broadcast(true)
-            .context("Failed to enable broadcast on UDP socket")?;
 
         let peer_id = Uuid::new_v4().to_string();
         let hostname = hostname::get();
@@ -267,8 +515,9 @@ impl UdpBroadcaster {
         &self.peer_id
     }
 
-    pub fn set_peer_id(&mut self, peer_id: String) {
-        self.peer_id = peer_id;
+    /// Advertise `port` as the port our listener is reachable on
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
     }
 
     /// Start broadcasting presence messages
@@ -281,26 +530,18 @@ impl UdpBroadcaster {
         loop {
             interval.tick().await;
             
-            let message = DiscoveryMessage {
-                message_type: MessageType::PeerDiscovery,
-                peer_id: self.peer_id.clone(),
-                port: self.port,
-                hostname: self.hostname.clone(),
-                timestamp: Utc::now(),
-                text: None,
-                message_id: None,
-                seq_no: None,
-                total_chunks: None,
-                payload_len: None,
-                checksum: None,
-                payload: None,
-            };
+            let message = DiscoveryMessage::new(
+                MessageType::PeerDiscovery,
+                self.peer_id.clone(),
+                self.port,
+                self.hostname.clone(),
+            );
 
             let message_bytes = serde_json::to_vec(&message)
                 .context("Failed to serialize discovery message")?;
 
             // Send to all interface broadcast addresses (robust on Windows with multiple adapters)
-            for addr in ipv4_broadcast_targets() {
+            for addr in self.socket.broadcast_targets(DISCOVERY_PORT) {
                 match self.socket.send_to(&message_bytes, addr).await {
                     Ok(_) => {
                         debug!("Broadcasted presence message to {}", addr);
@@ -316,7 +557,7 @@ impl UdpBroadcaster {
 
 /// UDP listener for discovering other peers on the network
 pub struct UdpListener {
-    socket: UdpSocket,
+    socket: Arc<dyn Transport>,
     #[allow(dead_code)]
     registry: Arc<PeerRegistry>,
     #[allow(dead_code)]
@@ -324,12 +565,24 @@ pub struct UdpListener {
 }
 
 impl UdpListener {
-    pub async fn new(registry: Arc<PeerRegistry>, own_peer_id: String) -> Result<Self> {
-        let socket = UdpSocket::bind("0.0.0.0:7878")
-            .await
-            .context("Failed to bind UDP socket for listening")?;
+    /// Bind the listener on `port`, falling back to a free port if it is taken
+    /// (e.g. by another instance on the same host)
+    pub async fn new(transport: &dyn TransportFactory, registry: Arc<PeerRegistry>, own_peer_id: String, port: u16) -> Result<Self> {
+        let socket = match transport.bind(port).await {
+            Ok(socket) => socket,
+            Err(e) if port != 0 => {
+                warn!("Port {} unavailable ({}), falling back to a free port", port, e);
+                transport.bind(0)
+                    .await
+                    .context("Failed to bind UDP socket for listening")?
+            }
+            Err(e) => return Err(e).context("Failed to bind UDP socket for listening"),
+        };
 
-        info!("UDP listener started on port 7878");
+        match socket.local_addr() {
+            Ok(addr) => info!("UDP listener started on {}", addr),
+            Err(_) => info!("UDP listener started on port {}", port),
+        }
 
         Ok(Self { 
             socket, 
@@ -338,6 +591,11 @@ impl UdpListener {
         })
     }
 
+    /// Port the listener actually bound
+    pub fn port(&self) -> Result<u16> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
     /// Start listening for discovery messages
     #[allow(dead_code)]
     pub async fn start_listening(&self) -> Result<()> {
@@ -377,7 +635,7 @@ impl UdpListener {
                     src_addr.ip(),
                     message.port,
                     message.hostname,
-                );
+                ).with_rooms(message.rooms.unwrap_or_default());
                 self.registry.add_peer(peer).await;
             }
             MessageType::TextMessage => {
@@ -393,243 +651,1249 @@ impl UdpListener {
                     message.total_chunks.unwrap_or(0));
                 // Note: TextChunk handling is done in the new listener implementation
             }
+            MessageType::TransferCancel | MessageType::ChunkAck | MessageType::ChunkNack | MessageType::MirrorAck => {
+                // Transfer control is handled in the new listener implementation
+            }
         }
 
         Ok(())
     }
 }
 
-/// Main discovery service that coordinates broadcasting and listening
-pub struct DiscoveryService {
-    registry: Arc<PeerRegistry>,
-    peer_id: Option<String>,
-    pub app_handle: Option<AppHandle>,
-    reassembly_states: Arc<RwLock<HashMap<String, ReassemblyState>>>,
+/// A text chunked once, shared by the concurrent transfers to each recipient
+struct OutgoingText {
+    own_peer_id: String,
+    port: u16,
+    /// Set for chunked messages, which are acknowledged and can be cancelled
+    message_id: Option<String>,
+    messages: Vec<DiscoveryMessage>,
+    encoded: Vec<Vec<u8>>,
+    total_bytes: usize,
+    cancel: Option<CancelToken>,
+    /// Per-peer tokens from an `OutboundQueue`, cancelled by a newer update
+    superseded: HashMap<String, CancelToken>,
+    reporter: ProgressReporter,
+    auth: Arc<MessageAuth>,
+    replay: Arc<ReplayGuard>,
 }
 
-impl DiscoveryService {
-    pub fn new(timeout_duration: Duration) -> Self {
-        Self {
-            registry: Arc::new(PeerRegistry::new(timeout_duration)),
-            peer_id: None,
-            app_handle: None,
-            reassembly_states: Arc::new(RwLock::new(HashMap::new())),
+impl OutgoingText {
+    /// Serialize `messages` up front; `total_bytes` is the size of the text they carry
+    fn new(
+        own_peer_id: String,
+        port: u16,
+        messages: Vec<DiscoveryMessage>,
+        total_bytes: usize,
+        reporter: ProgressReporter,
+        auth: Arc<MessageAuth>,
+        replay: Arc<ReplayGuard>,
+    ) -> Result<Self> {
+        let encoded = messages.iter()
+            .map(|message| auth.encode(message))
+            .collect::<serde_json::Result<Vec<_>>>()
+            .context("Failed to serialize text message")?;
+        Ok(Self {
+            own_peer_id,
+            port,
+            message_id: messages.iter()
+                .find(|message| message.message_type == MessageType::TextChunk)
+                .and_then(|message| message.message_id.clone()),
+            messages,
+            encoded,
+            total_bytes,
+            cancel: None,
+            superseded: HashMap::new(),
+            reporter,
+            auth,
+            replay,
+        })
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancelToken::is_cancelled)
+    }
+
+    /// Feedback gets the listener's checks: a captured NACK replayed later
+    /// must not force a resend, nor an old ACK stand in for a lost chunk
+    fn accept_feedback(&self, feedback: &DiscoveryMessage) -> bool {
+        if self.auth.has_key() {
+            if let Err(e) = check_fresh(feedback.timestamp, Utc::now()) {
+                debug!("Ignoring {:?} from {}: {}", feedback.message_type, feedback.peer_id, e);
+                return false;
+            }
+        }
+        if let Err(e) = self.replay.check(&feedback.peer_id, feedback.nonce.as_deref(), feedback.timestamp) {
+            debug!("Ignoring {:?} from {}: {}", feedback.message_type, feedback.peer_id, e);
+            return false;
         }
+        true
     }
 
-    /// Start the discovery service
-    pub async fn start(&mut self, port: u16) -> Result<()> {
-        // Start the broadcaster
-        let _broadcaster = UdpBroadcaster::new(port, Duration::from_secs(2)).await?;
-        let peer_id = _broadcaster.get_peer_id().to_string();
-        
-        // Store the peer ID
-        self.peer_id = Some(peer_id.clone());
-        
-        // Start the listener
-        let _listener = UdpListener::new(self.registry.clone(), peer_id.clone()).await?;
+    fn is_cancelled_for(&self, peer_id: &str) -> bool {
+        self.is_cancelled() || self.superseded.get(peer_id).is_some_and(CancelToken::is_cancelled)
+    }
 
-        // Return the tasks to be spawned by the caller
-        // The caller should spawn these tasks in the appropriate runtime context
-        
-        info!("Discovery service initialized with peer ID: {}", peer_id);
-        Ok(())
+    /// Run the whole transfer to one peer on its own socket, so feedback
+    /// from different peers never competes for the same receive buffer
+    async fn send_to(&self, transport: &dyn TransportFactory, peer: &Peer) -> PeerSendResult {
+        let total_chunks = self.messages.len() as u32;
+        let result = |status, chunks_confirmed, error| PeerSendResult {
+            peer_id: peer.id.clone(),
+            status,
+            chunks_confirmed,
+            total_chunks,
+            error,
+        };
+
+        // Cancelled while waiting for a transfer slot; nothing was sent
+        if self.is_cancelled_for(&peer.id) {
+            return result(SendStatus::Cancelled, 0, None);
+        }
+        let socket = match transport.bind(0).await {
+            Ok(socket) => socket,
+            Err(e) => return result(SendStatus::Failed, 0, Some(format!("Failed to bind UDP socket for sending: {}", e))),
+        };
+
+        let confirmed = self.send_messages(socket.as_ref(), peer).await;
+        if confirmed == total_chunks {
+            info!("Sent text to peer {}: {} bytes in {} messages", peer.id, self.total_bytes, total_chunks);
+            let status = if self.message_id.is_some() { SendStatus::Delivered } else { SendStatus::Sent };
+            return result(status, confirmed, None);
+        }
+        match &self.message_id {
+            Some(message_id) if self.is_cancelled_for(&peer.id) => {
+                self.send_cancel_notice(socket.as_ref(), peer, message_id).await;
+                self.reporter.cancelled(TransferCancelled {
+                    message_id: message_id.clone(),
+                    peer_id: peer.id.clone(),
+                    direction: Direction::Sent,
+                    chunks_done: confirmed,
+                    total_chunks,
+                });
+                result(SendStatus::Cancelled, confirmed, None)
+            }
+            Some(_) => result(SendStatus::Failed, confirmed, Some(format!(
+                "Peer stopped acknowledging after {}/{} chunks", confirmed, total_chunks))),
+            None => result(SendStatus::Failed, 0, Some("Failed to send message".to_string())),
+        }
     }
 
-    /// Get the broadcaster task for spawning
-    pub fn get_broadcaster_task(&self, port: u16) -> Result<tokio::task::JoinHandle<()>> {
-        // Use the peer ID that was already generated in start()
-        let peer_id = self.peer_id.clone().ok_or_else(|| {
-            anyhow::anyhow!("Peer ID not available - call start() first")
-        })?;
-        
-        let broadcaster = UdpBroadcaster::new(port, Duration::from_secs(2));
-        
-        Ok(tokio::spawn(async move {
-            let broadcaster = match broadcaster.await {
-                Ok(mut b) => {
-                    // Override the peer ID to use the one from start()
-                    b.set_peer_id(peer_id);
-                    b
-                },
-                Err(e) => {
-                    error!("Failed to create broadcaster: {}", e);
-                    return;
+    /// Send every message to one peer, reporting progress for chunked messages
+    ///
+    /// Chunks are sent within a window that the rate controller sizes from
+    /// the peer's ACKs and NACKs; lost chunks are sent again. Stops early if
+    /// the transfer is cancelled or the peer stops responding, and returns
+    /// how many messages went out (single packet) or were confirmed (chunked).
+    async fn send_messages(&self, socket: &dyn Transport, peer: &Peer) -> u32 {
+        let addr = peer.socket_addr();
+        let messages = &self.messages;
+        let encoded = &self.encoded;
+
+        // Single-packet messages are not acknowledged
+        let Some(message_id) = self.message_id.clone() else {
+            if let Err(e) = socket.send_to(&encoded[0], addr).await {
+                error!("Failed to send message to peer {}: {}", peer.id, e);
+                return 0;
+            }
+            return 1;
+        };
+
+        let total = messages.len();
+        let mut controller = RateController::new();
+        let mut acked = vec![false; total];
+        let mut sent_at: Vec<Option<Instant>> = vec![None; total];
+        let mut retransmitted = vec![false; total];
+        let mut in_flight = BTreeSet::new();
+        let mut lost = VecDeque::new();
+        let mut next = 0;
+        let mut acked_count = 0;
+        let mut bytes_done = 0u64;
+        let started_at = Instant::now();
+        let mut last_feedback = started_at;
+        let mut throttle = ProgressThrottle::default();
+        let mut buf = [0u8; 8192];
+
+        while acked_count < total {
+            if self.is_cancelled_for(&peer.id) {
+                break;
+            }
+
+            // Fill the window, resending lost chunks first
+            while in_flight.len() < controller.window() {
+                let seq = if let Some(seq) = lost.pop_front() {
+                    retransmitted[seq] = true;
+                    seq
+                } else if next < total {
+                    next += 1;
+                    next - 1
+                } else {
+                    break;
+                };
+                if let Err(e) = socket.send_to(&encoded[seq], addr).await {
+                    error!("Failed to send message chunk to peer {}: {}", peer.id, e);
+                } else {
+                    debug!("Sent message chunk {}/{} to peer {}: {} bytes",
+                           seq + 1, total, peer.id, encoded[seq].len());
                 }
-            };
-            let mut interval = interval(broadcaster.broadcast_interval);
-            info!("Starting UDP broadcast on port 7878 with peer ID: {}", broadcaster.get_peer_id());
-            loop {
-                interval.tick().await;
-                let message = DiscoveryMessage {
-                message_type: MessageType::PeerDiscovery,
-                peer_id: broadcaster.get_peer_id().to_string(),
-                port: broadcaster.port,
-                hostname: broadcaster.hostname.clone(),
-                timestamp: Utc::now(),
-                text: None,
-                message_id: None,
-                seq_no: None,
-                total_chunks: None,
-                payload_len: None,
-                checksum: None,
-                payload: None,
-            };
-                let message_bytes = match serde_json::to_vec(&message) {
-                    Ok(bytes) => bytes,
-                    Err(e) => {
-                        error!("Failed to serialize discovery message: {}", e);
+                controller.on_send();
+                sent_at[seq] = Some(Instant::now());
+                in_flight.insert(seq);
+            }
+
+            // Wait for feedback until the oldest chunk in flight is overdue
+            let rto = controller.retransmit_timeout();
+            let deadline = in_flight.iter()
+                .filter_map(|seq| sent_at[*seq])
+                .min()
+                .unwrap_or_else(Instant::now) + rto;
+            match tokio::time::timeout_at(deadline.into(), socket.recv_from(&mut buf)).await {
+                Ok(Ok((len, _))) => {
+                    let Ok(feedback) = self.auth.decode(&buf[..len]) else {
+                        continue;
+                    };
+                    if feedback.peer_id != peer.id || feedback.message_id.as_deref() != Some(message_id.as_str()) {
                         continue;
                     }
-                };
-                for addr in ipv4_broadcast_targets() {
-                    match broadcaster.socket.send_to(&message_bytes, addr).await {
-                        Ok(_) => {
-                            debug!("Broadcasted presence message to {}", addr);
+                    if !self.accept_feedback(&feedback) {
+                        continue;
+                    }
+                    match feedback.message_type {
+                        MessageType::ChunkAck => {
+                            let Some(seq) = feedback.seq_no.map(|seq| seq as usize).filter(|seq| *seq < total) else {
+                                continue;
+                            };
+                            last_feedback = Instant::now();
+                            if acked[seq] {
+                                continue;
+                            }
+                            acked[seq] = true;
+                            acked_count += 1;
+                            in_flight.remove(&seq);
+                            lost.retain(|lost_seq| *lost_seq != seq);
+                            // Only chunks sent once give an unambiguous round trip time
+                            let rtt = if retransmitted[seq] { None } else { sent_at[seq].map(|at| at.elapsed()) };
+                            controller.on_ack(rtt);
+
+                            bytes_done += messages[seq].payload_len.unwrap_or(0) as u64;
+                            let chunks_done = acked_count as u32;
+                            if throttle.ready(acked_count == total) {
+                                let (bytes_per_sec, eta_secs) = progress::estimate(
+                                    chunks_done, total as u32, bytes_done, started_at.elapsed());
+                                self.reporter.report(TransferProgress {
+                                    message_id: message_id.clone(),
+                                    peer_id: peer.id.clone(),
+                                    direction: Direction::Sent,
+                                    chunks_done,
+                                    total_chunks: total as u32,
+                                    bytes_done,
+                                    total_bytes: Some(self.total_bytes as u64),
+                                    bytes_per_sec,
+                                    eta_secs,
+                                });
+                            }
                         }
-                        Err(e) => {
-                            error!("Failed to broadcast presence message to {}: {}", addr, e);
+                        MessageType::ChunkNack => {
+                            last_feedback = Instant::now();
+                            for seq in feedback.missing.unwrap_or_default() {
+                                let seq = seq as usize;
+                                if seq >= total {
+                                    continue;
+                                }
+                                if acked[seq] {
+                                    // The message failed its digest and the receiver threw it away
+                                    acked[seq] = false;
+                                    acked_count -= 1;
+                                    bytes_done -= messages[seq].payload_len.unwrap_or(0) as u64;
+                                    lost.push_back(seq);
+                                } else if in_flight.remove(&seq) {
+                                    lost.push_back(seq);
+                                    controller.on_loss();
+                                }
+                            }
                         }
+                        _ => {}
                     }
                 }
-            }
-        }))
-    }
-
-    /// Get the listener task for spawning
-    pub fn get_listener_task(&self, own_peer_id: String) -> Result<tokio::task::JoinHandle<()>> {
-        let registry = self.registry.clone();
-        let app_handle = self.app_handle.clone();
-        let reassembly_states = self.reassembly_states.clone();
-        let listener = UdpListener::new(registry.clone(), own_peer_id.clone());
-        
-        Ok(tokio::spawn(async move {
-            let listener = match listener.await {
-                Ok(l) => l,
-                Err(e) => {
-                    error!("Failed to create listener: {}", e);
-                    return;
+                Ok(Err(e)) => {
+                    error!("Failed to receive transfer feedback from peer {}: {}", peer.id, e);
+                    sleep(Duration::from_millis(10)).await;
                 }
-            };
-            let mut buf = [0; 8192];
-            loop {
-                match listener.socket.recv_from(&mut buf).await {
-                    Ok((len, src_addr)) => {
-                        let message_bytes = &buf[..len];
-                        if let Err(e) = Self::handle_listener_message(
-                            message_bytes,
-                            src_addr,
-                            &registry,
-                            &own_peer_id,
-                            app_handle.clone(),
-                            &reassembly_states,
-                        ).await {
-                            error!("Failed to handle discovery message: {}", e);
-                        }
-                    }
-                    Err(e) => {
-                        error!("Failed to receive UDP message: {}", e);
-                        sleep(Duration::from_millis(100)).await;
+                Err(_) => {
+                    let now = Instant::now();
+                    let overdue: Vec<usize> = in_flight.iter()
+                        .copied()
+                        .filter(|seq| sent_at[*seq].is_some_and(|at| now.duration_since(at) >= rto))
+                        .collect();
+                    for seq in overdue {
+                        in_flight.remove(&seq);
+                        lost.push_back(seq);
+                        controller.on_loss();
                     }
                 }
             }
-        }))
-    }
 
-    /// Get the cleanup task for spawning
-    pub fn get_cleanup_task(&self) -> tokio::task::JoinHandle<()> {
-        let registry = self.registry.clone();
-        let reassembly_states = self.reassembly_states.clone();
-        tokio::spawn(async move {
-            let mut interval = interval(Duration::from_secs(3));
-            loop {
-                interval.tick().await;
-                registry.cleanup_stale_peers().await;
-                
-                // Clean up stale reassembly states
-                let mut states = reassembly_states.write().await;
-                let initial_count = states.len();
-                states.retain(|message_id, state| {
-                    if state.is_stale() {
-                        warn!("Cleaning up stale reassembly state for message {}: {}/{} chunks received (timeout after {}s)", 
-                              message_id, state.received_count, state.total_chunks, REASSEMBLY_TIMEOUT_SECS);
-                        false
-                    } else {
-                        true
-                    }
-                });
-                let removed_count = initial_count - states.len();
-                if removed_count > 0 {
-                    info!("Cleaned up {} stale reassembly states", removed_count);
-                }
+            if last_feedback.elapsed() > STALL_TIMEOUT {
+                warn!("Peer {} stopped acknowledging message {} after {}/{} chunks", peer.id, message_id, acked_count, total);
+                break;
             }
-        })
+        }
+
+        debug!("Transfer {} to peer {}: {}/{} chunks confirmed, window {}, loss rate {:.1}%",
+               message_id, peer.id, acked_count, total, controller.window(), controller.loss_rate() * 100.0);
+        acked_count as u32
     }
 
-    async fn handle_listener_message(
-        message_bytes: &[u8],
-        src_addr: SocketAddr,
-        registry: &Arc<PeerRegistry>,
-        own_peer_id: &str,
-        app_handle: Option<AppHandle>,
-        reassembly_states: &Arc<RwLock<HashMap<String, ReassemblyState>>>,
-    ) -> Result<()> {
-        // Log raw UDP packet
-        debug!("Received UDP packet from {}: {:?}", src_addr, message_bytes);
+    /// Send the text once to the multicast group for all of `peers`
+    ///
+    /// Receivers report the chunks they missed with NACKs and confirm the
+    /// complete message once; missing chunks are repaired over unicast. When
+    /// the group has been sent everything, silent receivers are probed with
+    /// the last chunk so they report what they are missing.
+    async fn multicast_to(&self, transport: &dyn TransportFactory, peers: &[Peer]) -> Vec<PeerSendResult> {
+        let total_chunks = self.messages.len() as u32;
+        let result = |peer: &Peer, status, chunks_confirmed, error| PeerSendResult {
+            peer_id: peer.id.clone(),
+            status,
+            chunks_confirmed,
+            total_chunks,
+            error,
+        };
+        let pending = |status: &[Option<SendStatus>]| -> Vec<usize> {
+            (0..status.len()).filter(|i| status[*i].is_none()).collect()
+        };
+        let fail_all = |error: String| peers.iter()
+            .map(|peer| result(peer, SendStatus::Failed, 0, Some(error.clone())))
+            .collect();
 
-        let message: DiscoveryMessage = match serde_json::from_slice(message_bytes) {
-            Ok(msg) => msg,
-            Err(e) => {
-                error!("Failed to deserialize discovery message from {}: {}", src_addr, e);
-                return Ok(());
-            }
+        if self.is_cancelled() {
+            return peers.iter().map(|peer| result(peer, SendStatus::Cancelled, 0, None)).collect();
+        }
+        let socket = match transport.bind(0).await {
+            Ok(socket) => socket,
+            Err(e) => return fail_all(format!("Failed to bind UDP socket for sending: {}", e)),
         };
+        // The whole group hears it, so name who it is for
+        let recipients: Vec<String> = peers.iter().map(|peer| peer.id.clone()).collect();
+        let encoded: Vec<Vec<u8>> = match self.messages.iter()
+            .map(|message| self.auth.encode(&DiscoveryMessage {
+                multicast: true,
+                recipients: Some(recipients.clone()),
+                ..message.clone()
+            }))
+            .collect()
+        {
+            Ok(encoded) => encoded,
+            Err(e) => return fail_all(format!("Failed to serialize text message: {}", e)),
+        };
+        let group = SocketAddr::new(IpAddr::V4(MULTICAST_GROUP), DISCOVERY_PORT);
 
-        // Log after deserialization
-        debug!("Deserialized message from {}: {:?}", src_addr, message);
+        // Single-packet messages are not acknowledged
+        let Some(message_id) = self.message_id.clone() else {
+            return match socket.send_to(&encoded[0], group).await {
+                Ok(_) => peers.iter().map(|peer| result(peer, SendStatus::Sent, 1, None)).collect(),
+                Err(e) => fail_all(format!("Failed to send message: {}", e)),
+            };
+        };
 
-        if message.peer_id == own_peer_id {
-            return Ok(());
-        }
-        match message.message_type {
-            MessageType::PeerDiscovery => {
-                let peer = Peer::new(
-                    message.peer_id,
-                    src_addr.ip(),
-                    message.port,
-                    message.hostname,
-                );
-                registry.add_peer(peer).await;
+        let total = encoded.len();
+        let started_at = Instant::now();
+        let mut status: Vec<Option<SendStatus>> = vec![None; peers.len()];
+        let mut last_heard = vec![started_at; peers.len()];
+        let mut last_probe: Vec<Option<Instant>> = vec![None; peers.len()];
+        let mut repairs: VecDeque<(usize, usize)> = VecDeque::new();
+        let mut controller = RateController::new();
+        let mut throttle = ProgressThrottle::default();
+        let mut next = 0;
+        let mut bytes_done = 0u64;
+        let mut buf = [0u8; 8192];
+
+        while status.iter().any(Option::is_none) {
+            if self.is_cancelled() {
+                for i in pending(&status) {
+                    self.send_cancel_notice(socket.as_ref(), &peers[i], &message_id).await;
+                    status[i] = Some(SendStatus::Cancelled);
+                }
+                self.reporter.cancelled(TransferCancelled {
+                    message_id: message_id.clone(),
+                    peer_id: group.to_string(),
+                    direction: Direction::Sent,
+                    chunks_done: next as u32,
+                    total_chunks,
+                });
+                break;
             }
-            MessageType::TextMessage => {
-                if let Some(text) = message.text {
-                    info!("Received text message from {}: {}", message.peer_id, text);
-                    if let Some(app) = app_handle {
-                        info!("Emitting text-received event to frontend: {}", text);
-                        let _ = app.emit("text-received", text);
+
+            // One burst: repairs first, then fresh chunks to the whole group
+            let mut burst = 0;
+            while burst < controller.window() {
+                let (seq, target) = if let Some((i, seq)) = repairs.pop_front() {
+                    if status[i].is_some() {
+                        continue;
                     }
+                    (seq, peers[i].socket_addr())
+                } else if next < total {
+                    next += 1;
+                    bytes_done += self.messages[next - 1].payload_len.unwrap_or(0) as u64;
+                    if throttle.ready(next == total) {
+                        let (bytes_per_sec, eta_secs) = progress::estimate(
+                            next as u32, total_chunks, bytes_done, started_at.elapsed());
+                        self.reporter.report(TransferProgress {
+                            message_id: message_id.clone(),
+                            peer_id: group.to_string(),
+                            direction: Direction::Sent,
+                            chunks_done: next as u32,
+                            total_chunks,
+                            bytes_done,
+                            total_bytes: Some(self.total_bytes as u64),
+                            bytes_per_sec,
+                            eta_secs,
+                        });
+                    }
+                    (next - 1, group)
+                } else {
+                    break;
+                };
+                if let Err(e) = socket.send_to(&encoded[seq], target).await {
+                    error!("Failed to send message chunk to {}: {}", target, e);
                 }
+                controller.on_send();
+                burst += 1;
             }
-            MessageType::TextChunk => {
-                info!("Received text chunk from {}: seq={}/{}", 
-                    message.peer_id, 
-                    message.seq_no.unwrap_or(0), 
-                    message.total_chunks.unwrap_or(0));
-                
-                // Handle chunk reassembly
-                if let Err(e) = Self::handle_text_chunk_internal(message, app_handle.clone(), reassembly_states).await {
-                    error!("Failed to handle text chunk: {}", e);
+
+            // Everything sent: probe receivers that have gone quiet
+            let rto = controller.retransmit_timeout();
+            if next == total && repairs.is_empty() {
+                let now = Instant::now();
+                for i in pending(&status) {
+                    let peer = &peers[i];
+                    let quiet_since = last_probe[i].unwrap_or(last_heard[i]).max(last_heard[i]);
+                    if now.duration_since(quiet_since) >= rto {
+                        if let Err(e) = socket.send_to(&encoded[total - 1], peer.socket_addr()).await {
+                            error!("Failed to probe peer {}: {}", peer.id, e);
+                        }
+                        last_probe[i] = Some(now);
+                    }
                 }
             }
-        }
+
+            // Collect feedback until the next burst is due
+            let wait = if next < total || !repairs.is_empty() { MULTICAST_BURST_INTERVAL } else { rto };
+            let deadline = Instant::now() + wait;
+            let mut saw_loss = false;
+            while let Ok(received) = tokio::time::timeout_at(deadline.into(), socket.recv_from(&mut buf)).await {
+                let Ok((len, _)) = received else {
+                    break;
+                };
+                let Ok(feedback) = self.auth.decode(&buf[..len]) else {
+                    continue;
+                };
+                if feedback.message_id.as_deref() != Some(message_id.as_str()) {
+                    continue;
+                }
+                let Some(i) = peers.iter().position(|peer| peer.id == feedback.peer_id) else {
+                    continue;
+                };
+                if !self.accept_feedback(&feedback) {
+                    continue;
+                }
+                last_heard[i] = Instant::now();
+                match feedback.message_type {
+                    MessageType::ChunkAck if feedback.seq_no.is_none() => {
+                        status[i].get_or_insert(SendStatus::Delivered);
+                    }
+                    MessageType::ChunkNack => {
+                        for seq in feedback.missing.unwrap_or_default() {
+                            let seq = seq as usize;
+                            if seq < next && !repairs.contains(&(i, seq)) {
+                                repairs.push_back((i, seq));
+                                saw_loss = true;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if saw_loss {
+                controller.on_loss();
+            } else {
+                for _ in 0..burst {
+                    controller.on_ack(None);
+                }
+            }
+
+            for i in pending(&status) {
+                if last_heard[i].elapsed() > STALL_TIMEOUT {
+                    warn!("Peer {} stopped responding to multicast message {}", peers[i].id, message_id);
+                    status[i] = Some(SendStatus::Failed);
+                }
+            }
+        }
+
+        debug!("Multicast transfer {}: {} chunks, window {}, loss rate {:.1}%",
+               message_id, total, controller.window(), controller.loss_rate() * 100.0);
+        peers.iter().zip(status).map(|(peer, status)| match status {
+            Some(SendStatus::Delivered) => result(peer, SendStatus::Delivered, total_chunks, None),
+            Some(SendStatus::Failed) => result(peer, SendStatus::Failed, 0, Some(
+                "Peer stopped responding to multicast transfer".to_string())),
+            status => result(peer, status.unwrap_or(SendStatus::Cancelled), 0, None),
+        }).collect()
+    }
+
+    /// Tell `peer` to drop what it has received of `message_id`
+    async fn send_cancel_notice(&self, socket: &dyn Transport, peer: &Peer, message_id: &str) {
+        let mut notice = DiscoveryMessage::new(MessageType::TransferCancel, self.own_peer_id.clone(), self.port, None);
+        notice.message_id = Some(message_id.to_string());
+        match self.auth.encode(&notice) {
+            Ok(bytes) => {
+                if let Err(e) = socket.send_to(&bytes, peer.socket_addr()).await {
+                    error!("Failed to send cancel notice for {} to peer {}: {}", message_id, peer.id, e);
+                }
+            }
+            Err(e) => error!("Failed to serialize cancel notice: {}", e),
+        }
+    }
+}
+
+/// Main discovery service that coordinates broadcasting and listening
+pub struct DiscoveryService {
+    registry: Arc<PeerRegistry>,
+    peer_id: Option<String>,
+    pub app_handle: Option<AppHandle>,
+    pub history: Option<Arc<HistoryStore>>,
+    /// Block and allow lists applied to incoming traffic
+    pub access: Option<Arc<AccessStore>>,
+    /// Texts from peers that aren't auto-accepted, waiting for the user
+    inbox: Arc<Inbox>,
+    reassembly_states: Arc<RwLock<HashMap<String, ReassemblyState>>>,
+    transport: Arc<dyn TransportFactory>,
+    text_tx: broadcast::Sender<ReceivedText>,
+    progress: ProgressReporter,
+    transfers: Arc<ActiveTransfers>,
+    rooms: Arc<Rooms>,
+    mirror_outbox: Arc<MirrorOutbox>,
+    mirror_inbox: Arc<MirrorInbox>,
+    mirror_queue: Arc<OutboundQueue>,
+    shared: Arc<SharedDoc>,
+    limiter: Arc<RateLimiter>,
+    auth: Arc<MessageAuth>,
+    replay: Arc<ReplayGuard>,
+    /// Last text number handed out; every text sent gets the next one
+    text_seq: AtomicU64,
+    broadcaster: Option<UdpBroadcaster>,
+    listener: Option<UdpListener>,
+    listen_port: Option<u16>,
+}
+
+/// Check a chunk's header against its payload before anything is buffered
+fn validate_chunk(seq_no: u32, total_chunks: u32, payload_len: Option<u32>, payload: &[u8]) -> Result<()> {
+    if total_chunks == 0 || total_chunks > MAX_TOTAL_CHUNKS {
+        return Err(anyhow::anyhow!("Chunk count {} outside 1..={}", total_chunks, MAX_TOTAL_CHUNKS));
+    }
+    if seq_no >= total_chunks {
+        return Err(anyhow::anyhow!("Chunk {} of a {} chunk message", seq_no, total_chunks));
+    }
+    if payload.is_empty() || payload.len() > CHUNK_PAYLOAD_BYTES {
+        return Err(anyhow::anyhow!("Chunk payload of {} bytes (max {})", payload.len(), CHUNK_PAYLOAD_BYTES));
+    }
+    match payload_len {
+        Some(len) if len as usize == payload.len() => Ok(()),
+        Some(len) => Err(anyhow::anyhow!("Chunk claims {} payload bytes but carries {}", len, payload.len())),
+        None => Err(anyhow::anyhow!("Missing payload_len")),
+    }
+}
+
+/// Check a text or chunk against what its sender may do
+///
+/// Chunks are checked against the smallest text their chunk count allows;
+/// the whole text is checked again before it is delivered.
+fn check_permissions(permissions: &Permissions, message: &DiscoveryMessage) -> Result<()> {
+    if message.mirror.is_some() || message.shared_ops {
+        // Deltas and operations aren't the size of the text they produce
+        return match permissions.live_mirror {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!("Peer may not live-mirror")),
+        };
+    }
+    if !permissions.text {
+        return Err(anyhow::anyhow!("Peer may not send text"));
+    }
+    let min_bytes = match message.message_type {
+        MessageType::TextChunk => message.total_chunks.unwrap_or(1).saturating_sub(1) as usize * CHUNK_PAYLOAD_BYTES + 1,
+        _ => message.text.as_ref().map_or(0, String::len),
+    };
+    if min_bytes > permissions.max_text_bytes {
+        return Err(anyhow::anyhow!("Text of at least {} bytes exceeds the peer's limit of {}", min_bytes, permissions.max_text_bytes));
+    }
+    Ok(())
+}
+
+/// Whether the shared text is exchanged with `peer`; without an access
+/// store every peer takes part
+fn shares_text_with(access: Option<&AccessStore>, peer: &Peer) -> bool {
+    access.is_none_or(|access| access.permissions(&peer.id, Some(peer.ip), peer.hostname.as_deref()).shares_text())
+}
+
+/// Check that `sender_id` may open another reassembly of `total_chunks` chunks
+///
+/// Open messages count with the buffer space their chunk count allows, so
+/// the caps hold however the chunks of each message arrive.
+fn check_reassembly_limits(states: &HashMap<String, ReassemblyState>, sender_id: &str, total_chunks: u32) -> Result<()> {
+    let wanted = total_chunks as usize * CHUNK_PAYLOAD_BYTES;
+    let (peer_open, peer_bytes) = states.values()
+        .filter(|state| state.sender_peer_id == sender_id)
+        .fold((0, 0), |(count, bytes), state| (count + 1, bytes + state.capacity_bytes()));
+    let total_bytes: usize = states.values().map(ReassemblyState::capacity_bytes).sum();
+
+    if peer_open >= MAX_REASSEMBLIES_PER_PEER || peer_bytes + wanted > MAX_BUFFERED_BYTES_PER_PEER {
+        return Err(anyhow::anyhow!("Peer {} already has {} messages ({} bytes) being reassembled", sender_id, peer_open, peer_bytes));
+    }
+    if states.len() >= MAX_REASSEMBLIES || total_bytes + wanted > MAX_BUFFERED_BYTES {
+        return Err(anyhow::anyhow!("Too many messages ({}, {} bytes) being reassembled", states.len(), total_bytes));
+    }
+    Ok(())
+}
+
+/// Shared state handed to the listener task for processing incoming datagrams
+#[derive(Clone)]
+struct ListenerContext {
+    registry: Arc<PeerRegistry>,
+    own_peer_id: String,
+    app_handle: Option<AppHandle>,
+    access: Option<Arc<AccessStore>>,
+    inbox: Arc<Inbox>,
+    reassembly_states: Arc<RwLock<HashMap<String, ReassemblyState>>>,
+    sink: TextSink,
+    progress: ProgressReporter,
+    socket: Arc<dyn Transport>,
+    delivered: Arc<std::sync::Mutex<DeliveredMessages>>,
+    /// Highest text number delivered per sender
+    delivered_seq: Arc<std::sync::Mutex<HashMap<String, u64>>>,
+    rooms: Arc<Rooms>,
+    mirror_outbox: Arc<MirrorOutbox>,
+    mirror_inbox: Arc<MirrorInbox>,
+    shared: Arc<SharedDoc>,
+    transport: Arc<dyn TransportFactory>,
+    limiter: Arc<RateLimiter>,
+    auth: Arc<MessageAuth>,
+    replay: Arc<ReplayGuard>,
+}
+
+impl ListenerContext {
+    /// Send an ACK or NACK for a chunked message back to the socket it came from
+    async fn send_feedback(
+        &self,
+        to: SocketAddr,
+        message_type: MessageType,
+        message_id: &str,
+        seq_no: Option<u32>,
+        missing: Option<Vec<u32>>,
+    ) {
+        let port = self.socket.local_addr().map(|addr| addr.port()).unwrap_or(DISCOVERY_PORT);
+        let mut feedback = DiscoveryMessage::new(message_type, self.own_peer_id.clone(), port, None);
+        feedback.message_id = Some(message_id.to_string());
+        feedback.seq_no = seq_no;
+        feedback.missing = missing;
+        match self.auth.encode(&feedback) {
+            Ok(bytes) => {
+                if let Err(e) = self.socket.send_to(&bytes, to).await {
+                    debug!("Failed to send transfer feedback to {}: {}", to, e);
+                }
+            }
+            Err(e) => error!("Failed to serialize transfer feedback: {}", e),
+        }
+    }
+
+    /// Whether `message` is a chunk of a transfer from its sender that was
+    /// fresh when it began; its timestamp was fixed when the text was
+    /// chunked, so retransmits of a long transfer can be older than the window
+    async fn is_under_way(&self, message: &DiscoveryMessage) -> bool {
+        let (MessageType::TextChunk, Some(message_id)) = (&message.message_type, &message.message_id) else {
+            return false;
+        };
+        if self.delivered.lock().unwrap().contains(&message.peer_id, message_id) {
+            return true;
+        }
+        self.reassembly_states.read().await.get(message_id)
+            .is_some_and(|state| state.sender_peer_id == message.peer_id)
+    }
+
+    /// Whether `message` is newer than every text delivered from its sender,
+    /// recording it as the newest if so
+    ///
+    /// A slow chunked text can complete after a newer one from the same
+    /// sender. Checked before a mirror update is resolved, so a stale one
+    /// can't move the mirrored text backwards.
+    fn is_newest_text(&self, message: &DiscoveryMessage) -> bool {
+        let Some(seq) = message.text_seq else {
+            return true;
+        };
+        let mut delivered = self.delivered_seq.lock().unwrap();
+        let latest = delivered.entry(message.peer_id.clone()).or_insert(0);
+        if seq <= *latest {
+            info!("Dropping text {} from {}: text {} was already delivered", seq, message.peer_id, latest);
+            return false;
+        }
+        *latest = seq;
+        true
+    }
+
+    /// Whether texts from the sender of `message` skip the inbox; only these
+    /// may change the shared text or the mirrored text we hold
+    async fn auto_accepts(&self, message: &DiscoveryMessage, src_addr: SocketAddr) -> bool {
+        let Some(access) = &self.access else {
+            return true;
+        };
+        let hostname = match &message.hostname {
+            Some(hostname) => Some(hostname.clone()),
+            None => self.registry.get_peer(&message.peer_id).await.and_then(|peer| peer.hostname),
+        };
+        access.permissions(&message.peer_id, Some(src_addr.ip()), hostname.as_deref()).auto_accept
+    }
+
+    /// Turn a live-mirror update into the full text it represents
+    ///
+    /// Tells the sender which version we hold afterwards, so it can send the
+    /// next edit against it or fall back to a snapshot. Returns None if the
+    /// update didn't apply.
+    async fn resolve_mirror(&self, message: &DiscoveryMessage, src_addr: SocketAddr, text: String, trusted: bool) -> Option<String> {
+        let Some(header) = &message.mirror else {
+            return Some(text);
+        };
+        // An untrusted peer's update only goes to the inbox and never becomes
+        // our copy, so only its snapshots make sense on their own
+        let resolved = if trusted {
+            self.mirror_inbox.receive(&message.peer_id, header, &text)
+        } else if header.delta.is_none() {
+            Some(text)
+        } else {
+            debug!("Ignoring mirror delta from {}: not auto-accepted, so there is no base to apply it to", message.peer_id);
+            None
+        };
+        let port = self.socket.local_addr().map(|addr| addr.port()).unwrap_or(DISCOVERY_PORT);
+        let mut ack = DiscoveryMessage::new(MessageType::MirrorAck, self.own_peer_id.clone(), port, None);
+        ack.mirror = self.mirror_inbox.version(&message.peer_id)
+            .map(|version| MirrorHeader { version, delta: None });
+        let to = SocketAddr::new(src_addr.ip(), message.port);
+        match self.auth.encode(&ack) {
+            Ok(bytes) => {
+                if let Err(e) = self.socket.send_to(&bytes, to).await {
+                    debug!("Failed to send mirror ack to {}: {}", to, e);
+                }
+            }
+            Err(e) => error!("Failed to serialize mirror ack: {}", e),
+        }
+        resolved
+    }
+
+    /// Apply a batch of shared-text operations received as `text`
+    fn apply_shared_ops(&self, sender_id: &str, text: &str) {
+        let ops: Vec<StampedOp> = match serde_json::from_str(text) {
+            Ok(ops) => ops,
+            Err(e) => {
+                warn!("Dropping malformed shared-text operations from {}: {}", sender_id, e);
+                return;
+            }
+        };
+        if let Some(view) = self.shared.apply_remote(sender_id, ops) {
+            if let Some(app) = &self.app_handle {
+                let _ = app.emit("shared-text-changed", &view);
+            }
+        }
+    }
+
+    /// Send `peer` the shared-text operations its advertised version vector
+    /// shows it hasn't applied, unless a catch-up to it is already running
+    /// or the last one for the same vector got nowhere
+    fn sync_shared_text(&self, peer: &Peer, seen: &VersionVector) {
+        // Teams in different rooms each keep their own shared text
+        if !self.rooms.shares_room(&peer.rooms) || !shares_text_with(self.access.as_deref(), peer) {
+            return;
+        }
+        self.shared.add_collaborator(&peer.id);
+        let missing = self.shared.ops_since(seen);
+        if missing.is_empty() || !self.shared.begin_sync(&peer.id, seen) {
+            return;
+        }
+        let ctx = self.clone();
+        let peer = peer.clone();
+        tokio::spawn(async move {
+            debug!("Sending {} missed shared-text operation(s) to {}", missing.len(), peer.id);
+            let result = ctx.send_shared_ops(&peer, &missing).await;
+            if let Err(e) = &result {
+                warn!("Failed to bring {} up to date with the shared text: {}", peer.id, e);
+            }
+            ctx.shared.end_sync(&peer.id, result.is_ok());
+        });
+    }
+
+    async fn send_shared_ops(&self, peer: &Peer, ops: &[StampedOp]) -> Result<()> {
+        let port = self.socket.local_addr().map(|addr| addr.port()).unwrap_or(DISCOVERY_PORT);
+        for batch in encode_batches(ops, MAX_MESSAGE_BYTES)? {
+            let mut messages = chunk_text(&batch, &self.own_peer_id, port, None);
+            let room = self.rooms.current();
+            messages.iter_mut().for_each(|message| {
+                message.shared_ops = true;
+                message.room = room.clone();
+            });
+            let outgoing = OutgoingText::new(
+                self.own_peer_id.clone(), port, messages, batch.len(), ProgressReporter::new(None), self.auth.clone(), self.replay.clone())?;
+            let result = outgoing.send_to(self.transport.as_ref(), peer).await;
+            if !result.status.reached_peer() {
+                return Err(anyhow::anyhow!(result.error.unwrap_or_else(|| format!("{:?}", result.status))));
+            }
+        }
+        Ok(())
+    }
+
+    /// Deliver a received text to history, the frontend and any in-process subscribers
+    async fn emit_text(&self, mut received: ReceivedText) {
+        if received.hostname.is_none() {
+            received.hostname = self.registry.get_peer(&received.sender_id).await.and_then(|peer| peer.hostname);
+        }
+        let permissions = self.access.as_ref().map(|access| {
+            access.permissions(&received.sender_id, Some(received.source_addr.ip()), received.hostname.as_deref())
+        });
+        if let Some(limit) = permissions.map(|permissions| permissions.max_text_bytes).filter(|limit| received.size > *limit) {
+            info!("Dropping text of {} bytes from {}: over its limit of {}", received.size, received.sender_id, limit);
+            return;
+        }
+        if !permissions.is_none_or(|permissions| permissions.auto_accept) {
+            let pending = self.inbox.push(received);
+            info!("Holding text {} from {} until it is accepted", pending.id, pending.message.sender_id);
+            if let Some(app) = &self.app_handle {
+                let _ = app.emit("message-pending", &pending);
+            }
+            return;
+        }
+        self.sink.deliver(received);
+    }
+}
+
+/// Where delivered texts go: the history, the frontend and in-process subscribers
+#[derive(Clone)]
+pub struct TextSink {
+    own_peer_id: String,
+    history: Option<Arc<HistoryStore>>,
+    app_handle: Option<AppHandle>,
+    text_tx: broadcast::Sender<ReceivedText>,
+}
+
+impl TextSink {
+    /// Hand a received text to the history, the frontend and subscribers
+    pub fn deliver(&self, received: ReceivedText) {
+        if let Some(history) = &self.history {
+            let entry = HistoryEntry::new(
+                received.message_id.clone().unwrap_or_else(|| Uuid::new_v4().to_string()),
+                Direction::Received,
+                received.sender_id.clone(),
+                received.hostname.clone(),
+                vec![self.own_peer_id.clone()],
+                received.text.clone(),
+            );
+            if let Err(e) = history.record(&entry) {
+                error!("Failed to record received message in history: {}", e);
+            }
+        }
+        if let Some(app) = &self.app_handle {
+            info!("Emitting text-received event to frontend: {} bytes from {}", received.size, received.sender_id);
+            let _ = app.emit("text-received", &received);
+        }
+        let _ = self.text_tx.send(received);
+    }
+}
+
+impl DiscoveryService {
+    pub fn new(timeout_duration: Duration) -> Self {
+        Self::with_transport(timeout_duration, Arc::new(UdpTransportFactory))
+    }
+
+    /// Create a service that sends and receives through the given transport
+    pub fn with_transport(timeout_duration: Duration, transport: Arc<dyn TransportFactory>) -> Self {
+        let (text_tx, _) = broadcast::channel(TEXT_CHANNEL_CAPACITY);
+        Self {
+            registry: Arc::new(PeerRegistry::new(timeout_duration)),
+            peer_id: None,
+            app_handle: None,
+            history: None,
+            access: None,
+            inbox: Arc::new(Inbox::new()),
+            reassembly_states: Arc::new(RwLock::new(HashMap::new())),
+            transport,
+            text_tx,
+            progress: ProgressReporter::new(None),
+            transfers: Arc::new(ActiveTransfers::new()),
+            rooms: Arc::new(Rooms::new()),
+            mirror_outbox: Arc::new(MirrorOutbox::new()),
+            mirror_inbox: Arc::new(MirrorInbox::new()),
+            mirror_queue: Arc::new(OutboundQueue::new()),
+            shared: Arc::new(SharedDoc::new()),
+            limiter: Arc::new(RateLimiter::new()),
+            auth: Arc::new(MessageAuth::new()),
+            replay: Arc::new(ReplayGuard::new()),
+            text_seq: AtomicU64::new(0),
+            broadcaster: None,
+            listener: None,
+            listen_port: None,
+        }
+    }
+
+    /// Start the discovery service
+    ///
+    /// Binds the listener on `port` (or a free port if it is taken) and the
+    /// broadcaster; the tasks that drive them are spawned separately.
+    pub async fn start(&mut self, port: u16) -> Result<()> {
+        // Create the broadcaster, which also generates our peer ID
+        let mut broadcaster = UdpBroadcaster::new(self.transport.as_ref(), port, Duration::from_secs(2)).await?;
+        let peer_id = broadcaster.get_peer_id().to_string();
+        
+        // Store the peer ID
+        self.peer_id = Some(peer_id.clone());
+        self.shared.set_replica(&peer_id);
+        
+        // Bind the listener and advertise the port we actually got
+        let listener = UdpListener::new(self.transport.as_ref(), self.registry.clone(), peer_id.clone(), port).await?;
+        let listen_port = listener.port()?;
+        broadcaster.set_port(listen_port);
+        // Multicast sends target the well-known port, so only that listener joins
+        if listen_port == DISCOVERY_PORT {
+            if let Err(e) = listener.socket.join_multicast(MULTICAST_GROUP) {
+                warn!("Multicast unavailable, chunks will be repaired over unicast: {}", e);
+            }
+        }
+
+        self.listen_port = Some(listen_port);
+        self.broadcaster = Some(broadcaster);
+        self.listener = Some(listener);
+        
+        info!("Discovery service initialized with peer ID: {} on port {}", peer_id, listen_port);
+        Ok(())
+    }
+
+    /// Get the broadcaster task for spawning
+    pub fn get_broadcaster_task(&mut self) -> Result<tokio::task::JoinHandle<()>> {
+        // Use the broadcaster created in start()
+        let broadcaster = self.broadcaster.take().ok_or_else(|| {
+            anyhow::anyhow!("Broadcaster not available - call start() first")
+        })?;
+        let registry = self.registry.clone();
+        let rooms = self.rooms.clone();
+        let shared = self.shared.clone();
+        let auth = self.auth.clone();
+        
+        Ok(tokio::spawn(async move {
+            let mut interval = interval(broadcaster.broadcast_interval);
+            info!("Starting UDP broadcast on port {} with peer ID: {}", DISCOVERY_PORT, broadcaster.get_peer_id());
+            loop {
+                interval.tick().await;
+                let mut message = DiscoveryMessage::new(
+                    MessageType::PeerDiscovery,
+                    broadcaster.get_peer_id().to_string(),
+                    broadcaster.port,
+                    broadcaster.hostname.clone(),
+                );
+                message.rooms = Some(rooms.joined().into_iter().collect());
+                message.shared_versions = Some(shared.version_vector());
+                let message_bytes = match auth.encode(&message) {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        error!("Failed to serialize discovery message: {}", e);
+                        continue;
+                    }
+                };
+                // Peers on a fallback port never hear broadcasts, so announce to them directly
+                let mut targets = broadcaster.socket.broadcast_targets(DISCOVERY_PORT);
+                for peer in registry.get_peers().await {
+                    if peer.port != DISCOVERY_PORT {
+                        targets.push(peer.socket_addr());
+                    }
+                }
+                for addr in targets {
+                    match broadcaster.socket.send_to(&message_bytes, addr).await {
+                        Ok(_) => {
+                            debug!("Broadcasted presence message to {}", addr);
+                        }
+                        Err(e) => {
+                            error!("Failed to broadcast presence message to {}: {}", addr, e);
+                        }
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Get the listener task for spawning
+    pub fn get_listener_task(&mut self, own_peer_id: String) -> Result<tokio::task::JoinHandle<()>> {
+        // Use the listener bound in start()
+        let listener = self.listener.take().ok_or_else(|| {
+            anyhow::anyhow!("Listener not available - call start() first")
+        })?;
+        // Texts accepted from the inbox are delivered the same way, without the service
+        let sink = TextSink {
+            own_peer_id: own_peer_id.clone(),
+            history: self.history.clone(),
+            app_handle: self.app_handle.clone(),
+            text_tx: self.text_tx.clone(),
+        };
+        self.inbox.deliver_to(sink.clone());
+        let ctx = ListenerContext {
+            registry: self.registry.clone(),
+            own_peer_id,
+            app_handle: self.app_handle.clone(),
+            access: self.access.clone(),
+            inbox: self.inbox.clone(),
+            reassembly_states: self.reassembly_states.clone(),
+            sink,
+            progress: self.progress_reporter(),
+            socket: listener.socket.clone(),
+            delivered: Arc::new(std::sync::Mutex::new(DeliveredMessages::default())),
+            delivered_seq: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            rooms: self.rooms.clone(),
+            mirror_outbox: self.mirror_outbox.clone(),
+            mirror_inbox: self.mirror_inbox.clone(),
+            shared: self.shared.clone(),
+            transport: self.transport.clone(),
+            limiter: self.limiter.clone(),
+            auth: self.auth.clone(),
+            replay: self.replay.clone(),
+        };
+        
+        Ok(tokio::spawn(async move {
+            let mut buf = [0; 8192];
+            loop {
+                match listener.socket.recv_from(&mut buf).await {
+                    Ok((len, src_addr)) => {
+                        let message_bytes = &buf[..len];
+                        if let Err(e) = Self::handle_listener_message(message_bytes, src_addr, &ctx).await {
+                            error!("Failed to handle discovery message: {}", e);
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to receive UDP message: {}", e);
+                        sleep(Duration::from_millis(100)).await;
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Get the cleanup task for spawning
+    pub fn get_cleanup_task(&self) -> tokio::task::JoinHandle<()> {
+        let registry = self.registry.clone();
+        let reassembly_states = self.reassembly_states.clone();
+        let limiter = self.limiter.clone();
+        let replay = self.replay.clone();
+        tokio::spawn(async move {
+            let mut interval = interval(Duration::from_secs(3));
+            loop {
+                interval.tick().await;
+                registry.cleanup_stale_peers().await;
+                limiter.prune();
+                replay.prune();
+                
+                // Clean up stale reassembly states
+                let mut states = reassembly_states.write().await;
+                let initial_count = states.len();
+                states.retain(|message_id, state| {
+                    if state.is_stale() {
+                        warn!("Cleaning up stale reassembly state for message {}: {}/{} chunks received (timeout after {}s)", 
+                              message_id, state.received_count, state.total_chunks, REASSEMBLY_TIMEOUT_SECS);
+                        false
+                    } else {
+                        true
+                    }
+                });
+                let removed_count = initial_count - states.len();
+                if removed_count > 0 {
+                    info!("Cleaned up {} stale reassembly states", removed_count);
+                }
+            }
+        })
+    }
+
+    async fn handle_listener_message(
+        message_bytes: &[u8],
+        src_addr: SocketAddr,
+        ctx: &ListenerContext,
+    ) -> Result<()> {
+        // Log raw UDP packet
+        debug!("Received UDP packet from {}: {:?}", src_addr, message_bytes);
+
+        // Decoding and the lookups after it cost more than counting does
+        if !ctx.limiter.check_addr(src_addr.ip()) {
+            return Ok(());
+        }
+        let message = match ctx.auth.decode(message_bytes) {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("Dropping message from {}: {:#}", src_addr, e);
+                return Ok(());
+            }
+        };
+
+        // Log after deserialization
+        debug!("Deserialized message from {}: {:?}", src_addr, message);
+
+        if message.peer_id == ctx.own_peer_id {
+            return Ok(());
+        }
+        let class = match message.message_type {
+            MessageType::PeerDiscovery => TrafficClass::Discovery,
+            MessageType::TextChunk => TrafficClass::Chunk,
+            _ => TrafficClass::Text,
+        };
+        // Decoding succeeded, so with a key the peer id is vouched for
+        if !ctx.limiter.check(src_addr.ip(), &message.peer_id, class, ctx.auth.has_key()) {
+            return Ok(());
+        }
+        let is_text = matches!(message.message_type, MessageType::TextMessage | MessageType::TextChunk);
+        if let Some(access) = &ctx.access {
+            let (ip, hostname) = (Some(src_addr.ip()), message.hostname.as_deref());
+            // Blocked peers don't even make it into the registry
+            if access.is_blocked(&message.peer_id, ip, hostname) {
+                debug!("Ignoring {:?} from blocked peer {} ({})", message.message_type, message.peer_id, src_addr);
+                return Ok(());
+            }
+            if is_text && !access.accepts_text(&message.peer_id, ip, hostname) {
+                debug!("Ignoring text from {} ({}): not on the allow list", message.peer_id, src_addr);
+                return Ok(());
+            }
+            if is_text {
+                if let Err(e) = check_permissions(&access.permissions(&message.peer_id, ip, hostname), &message) {
+                    info!("Ignoring {:?} from {} ({}): {}", message.message_type, message.peer_id, src_addr, e);
+                    return Ok(());
+                }
+            }
+        }
+        // Only a key keeps the timestamp honest, so only then must clocks agree
+        if ctx.auth.has_key() && message.message_type != MessageType::PeerDiscovery && !ctx.is_under_way(&message).await {
+            if let Err(e) = check_fresh(message.timestamp, Utc::now()) {
+                warn!("Ignoring {:?} from {} ({}): {}", message.message_type, message.peer_id, src_addr, e);
+                return Ok(());
+            }
+        }
+        // The network (or a second interface) can deliver a packet twice;
+        // that's a duplicate to drop quietly before it looks like a replay
+        if message.message_type == MessageType::TextMessage {
+            if let Some(message_id) = &message.message_id {
+                if ctx.delivered.lock().unwrap().contains(&message.peer_id, message_id) {
+                    debug!("Dropping duplicate text message {} from {}", message_id, message.peer_id);
+                    return Ok(());
+                }
+            }
+        }
+        // Retransmitted chunks repeat their nonce, and reassembly already
+        // copes with duplicates; everything else is only handled once
+        if !matches!(message.message_type, MessageType::PeerDiscovery | MessageType::TextChunk) {
+            if let Err(e) = ctx.replay.check(&message.peer_id, message.nonce.as_deref(), message.timestamp) {
+                debug!("Ignoring {:?} from {} ({}): {}", message.message_type, message.peer_id, src_addr, e);
+                return Ok(());
+            }
+        }
+        if is_text && message.recipients.as_ref().is_some_and(|ids| !ids.contains(&ctx.own_peer_id)) {
+            debug!("Ignoring multicast text from {}: not one of its recipients", message.peer_id);
+            return Ok(());
+        }
+        if is_text && !ctx.rooms.accepts(message.room.as_deref()) {
+            debug!("Ignoring text for room {:?} from {}: not joined", message.room, message.peer_id);
+            return Ok(());
+        }
+        // Only a copy that got this far counts as delivered; one turned away
+        // above mustn't shut out a later copy that isn't
+        if message.message_type == MessageType::TextMessage {
+            if let Some(message_id) = &message.message_id {
+                ctx.delivered.lock().unwrap().insert(&message.peer_id, message_id);
+            }
+        }
+        match message.message_type {
+            MessageType::PeerDiscovery => {
+                let peer = Peer::new(
+                    message.peer_id,
+                    src_addr.ip(),
+                    message.port,
+                    message.hostname,
+                ).with_rooms(message.rooms.unwrap_or_default());
+                if let Some(seen) = &message.shared_versions {
+                    ctx.sync_shared_text(&peer, seen);
+                }
+                ctx.registry.add_peer(peer).await;
+            }
+            MessageType::TextMessage => {
+                if let Some(text) = message.text.clone() {
+                    let trusted = ctx.auto_accepts(&message, src_addr).await;
+                    if message.shared_ops {
+                        if trusted {
+                            ctx.apply_shared_ops(&message.peer_id, &text);
+                        } else {
+                            info!("Ignoring shared-text operations from {}: not auto-accepted", message.peer_id);
+                        }
+                        return Ok(());
+                    }
+                    info!("Received text message from {}: {}", message.peer_id, text);
+                    if !ctx.is_newest_text(&message) {
+                        return Ok(());
+                    }
+                    let Some(text) = ctx.resolve_mirror(&message, src_addr, text, trusted).await else {
+                        return Ok(());
+                    };
+                    ctx.emit_text(ReceivedText {
+                        sender_id: message.peer_id,
+                        hostname: message.hostname,
+                        source_addr: src_addr,
+                        message_id: message.message_id,
+                        sent_at: message.timestamp,
+                        size: text.len(),
+                        chunked: false,
+                        room: message.room,
+                        mirror_version: message.mirror.map(|header| header.version),
+                        seq: message.text_seq,
+                        text,
+                    }).await;
+                }
+            }
+            MessageType::TextChunk => {
+                info!("Received text chunk from {}: seq={}/{}", 
+                    message.peer_id, 
+                    message.seq_no.unwrap_or(0), 
+                    message.total_chunks.unwrap_or(0));
+                
+                // Handle chunk reassembly
+                if let Err(e) = Self::handle_text_chunk_internal(message, src_addr, ctx).await {
+                    error!("Failed to handle text chunk: {}", e);
+                }
+            }
+            MessageType::ChunkAck | MessageType::ChunkNack => {
+                // Feedback goes to the sending socket, not the listener
+                debug!("Ignoring stray transfer feedback from {}", src_addr);
+            }
+            MessageType::MirrorAck => {
+                ctx.mirror_outbox.on_ack(&message.peer_id, message.mirror.map(|header| header.version));
+            }
+            MessageType::TransferCancel => {
+                if let Some(message_id) = message.message_id {
+                    let mut states = ctx.reassembly_states.write().await;
+                    // Only the sender of a message may cancel it
+                    if states.get(&message_id).is_some_and(|state| state.sender_peer_id == message.peer_id) {
+                        if let Some(state) = states.remove(&message_id) {
+                            ctx.progress.cancelled(TransferCancelled {
+                                message_id,
+                                peer_id: state.sender_peer_id,
+                                direction: Direction::Received,
+                                chunks_done: state.received_count,
+                                total_chunks: state.total_chunks,
+                            });
+                        }
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
     /// Internal function to handle text chunk reassembly
     async fn handle_text_chunk_internal(
         message: DiscoveryMessage,
-        app_handle: Option<AppHandle>,
-        reassembly_states: &Arc<RwLock<HashMap<String, ReassemblyState>>>,
+        src_addr: SocketAddr,
+        ctx: &ListenerContext,
     ) -> Result<()> {
         let message_id = message.message_id.as_ref()
             .ok_or_else(|| anyhow::anyhow!("Missing message_id"))?;
@@ -639,116 +1903,561 @@ impl DiscoveryService {
             .ok_or_else(|| anyhow::anyhow!("Missing total_chunks"))?;
         let payload = message.payload.as_ref()
             .ok_or_else(|| anyhow::anyhow!("Missing payload"))?;
+        let multicast = message.multicast;
+
+        // A chunk of a message we already delivered was resent because our
+        // acknowledgement got lost; acknowledge it again and nothing more
+        if ctx.delivered.lock().unwrap().contains(&message.peer_id, message_id) {
+            let seq = if multicast { None } else { Some(seq_no) };
+            ctx.send_feedback(src_addr, MessageType::ChunkAck, message_id, seq, None).await;
+            return Ok(());
+        }
+
+        validate_chunk(seq_no, total_chunks, message.payload_len, payload)
+            .with_context(|| format!("Rejected chunk of message {} from {}", message_id, message.peer_id))?;
 
         // Verify checksum
-        if let Some(expected_checksum) = message.checksum {
-            let actual_checksum = simple_checksum(payload);
-            if actual_checksum != expected_checksum {
-                warn!("Checksum mismatch for chunk {} of message {}", seq_no, message_id);
-                return Ok(()); // Continue processing other chunks
+        let expected_checksum = message.checksum
+            .ok_or_else(|| anyhow::anyhow!("Chunk {} of message {} has no checksum", seq_no, message_id))?;
+        if chunk_checksum(payload) != expected_checksum {
+            warn!("Checksum mismatch for chunk {} of message {}", seq_no, message_id);
+            ctx.send_feedback(src_addr, MessageType::ChunkNack, message_id, None, Some(vec![seq_no])).await;
+            return Ok(()); // Continue processing other chunks
+        }
+
+        let mut states = ctx.reassembly_states.write().await;
+        
+        // Get or create reassembly state
+        let (is_complete, gaps) = if let Some(state) = states.get_mut(message_id) {
+            // Every chunk must describe the same message as the first one
+            if state.sender_peer_id != message.peer_id || state.total_chunks != total_chunks {
+                return Err(anyhow::anyhow!(
+                    "Chunk of message {} from {} claims {} chunks; the message from {} has {}",
+                    message_id, message.peer_id, total_chunks, state.sender_peer_id, state.total_chunks));
+            }
+            if let Some(digest) = &message.digest {
+                if state.digest.get_or_insert_with(|| digest.clone()) != digest {
+                    return Err(anyhow::anyhow!("Chunk of message {} from {} carries a different digest", message_id, message.peer_id));
+                }
+            }
+            let was_complete = state.is_complete();
+            let gaps = state.gaps_before(seq_no);
+            let is_complete = state.add_chunk(seq_no, payload.clone());
+            state.sent_at = state.sent_at.min(message.timestamp);
+            if !was_complete {
+                info!("Reassembly progress for {}: {}/{} chunks received", 
+                      message_id, state.received_count, total_chunks);
+                if state.throttle.ready(is_complete) {
+                    ctx.progress.report(state.progress(message_id));
+                }
+            }
+            (is_complete, gaps)
+        } else {
+            check_reassembly_limits(&states, &message.peer_id, total_chunks)
+                .with_context(|| format!("Not reassembling message {}", message_id))?;
+            let mut new_state = ReassemblyState::new(total_chunks, message.peer_id.clone());
+            new_state.sent_at = message.timestamp;
+            new_state.digest = message.digest.clone();
+            let gaps = new_state.gaps_before(seq_no);
+            let is_complete = new_state.add_chunk(seq_no, payload.clone());
+            if new_state.throttle.ready(is_complete) {
+                ctx.progress.report(new_state.progress(message_id));
+            }
+            states.insert(message_id.clone(), new_state);
+            info!("Started reassembly for message {}: {}/{} chunks received", 
+                  message_id, 1, total_chunks);
+            (is_complete, gaps)
+        };
+
+        // Multicast senders probe with the last chunk; answer with everything still missing
+        let gaps = if multicast && seq_no + 1 == total_chunks {
+            states.get(message_id).map(ReassemblyState::missing).unwrap_or_default()
+        } else {
+            gaps
+        };
+
+        // Acknowledge the chunk and report any it overtook so the sender can
+        // adapt; multicast receivers only acknowledge the complete message. The
+        // chunk completing a message is only acknowledged once it checks out.
+        if !multicast && !is_complete {
+            ctx.send_feedback(src_addr, MessageType::ChunkAck, message_id, Some(seq_no), None).await;
+        }
+        if !gaps.is_empty() {
+            ctx.send_feedback(src_addr, MessageType::ChunkNack, message_id, None, Some(gaps)).await;
+        }
+
+        // If complete, verify, reassemble and emit
+        if is_complete {
+            let Some(state) = states.get_mut(message_id) else {
+                return Ok(());
+            };
+            if let Err(e) = state.verified_bytes() {
+                // Some chunk was bad despite its checksum; ask for all of them again
+                warn!("Message {} from {} failed verification, requesting it again: {}", message_id, state.sender_peer_id, e);
+                ctx.progress.failed(TransferFailed {
+                    message_id: message_id.clone(),
+                    peer_id: state.sender_peer_id.clone(),
+                    direction: Direction::Received,
+                    reason: e.to_string(),
+                });
+                state.discard_chunks();
+                let all = (0..total_chunks).collect();
+                ctx.send_feedback(src_addr, MessageType::ChunkNack, message_id, None, Some(all)).await;
+                return Ok(());
+            }
+            let seq = if multicast { None } else { Some(seq_no) };
+            ctx.send_feedback(src_addr, MessageType::ChunkAck, message_id, seq, None).await;
+            ctx.delivered.lock().unwrap().insert(&message.peer_id, message_id);
+            if let Some(state) = states.remove(message_id) {
+                match state.reassemble() {
+                    Ok(complete_text) => {
+                        info!("Reassembled complete message from {} chunks: {} chars", total_chunks, complete_text.len());
+                        let trusted = ctx.auto_accepts(&message, src_addr).await;
+                        if message.shared_ops {
+                            if trusted {
+                                ctx.apply_shared_ops(&state.sender_peer_id, &complete_text);
+                            } else {
+                                info!("Ignoring shared-text operations from {}: not auto-accepted", state.sender_peer_id);
+                            }
+                            return Ok(());
+                        }
+                        if !ctx.is_newest_text(&message) {
+                            return Ok(());
+                        }
+                        let Some(complete_text) = ctx.resolve_mirror(&message, src_addr, complete_text, trusted).await else {
+                            return Ok(());
+                        };
+                        ctx.emit_text(ReceivedText {
+                            sender_id: state.sender_peer_id.clone(),
+                            hostname: message.hostname.clone(),
+                            source_addr: src_addr,
+                            message_id: Some(message_id.clone()),
+                            sent_at: state.sent_at,
+                            size: complete_text.len(),
+                            chunked: true,
+                            room: message.room.clone(),
+                            mirror_version: message.mirror.as_ref().map(|header| header.version),
+                            seq: message.text_seq,
+                            text: complete_text,
+                        }).await;
+                    }
+                    Err(e) => {
+                        error!("Rejecting message {} from {}: {}", message_id, state.sender_peer_id, e);
+                        ctx.progress.failed(TransferFailed {
+                            message_id: message_id.clone(),
+                            peer_id: state.sender_peer_id.clone(),
+                            direction: Direction::Received,
+                            reason: e.to_string(),
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the peer registry
+    pub fn registry(&self) -> Arc<PeerRegistry> {
+        self.registry.clone()
+    }
+
+    /// Get the current peer ID
+    pub fn peer_id(&self) -> Option<String> {
+        self.peer_id.clone()
+    }
+
+    /// Port the listener is bound to, once started
+    pub fn listen_port(&self) -> Option<u16> {
+        self.listen_port
+    }
+
+    /// Subscribe to texts received by the listener task
+    #[allow(dead_code)]
+    pub fn subscribe_text(&self) -> broadcast::Receiver<ReceivedText> {
+        self.text_tx.subscribe()
+    }
+
+    /// Subscribe to progress of chunked transfers in either direction
+    #[allow(dead_code)]
+    pub fn subscribe_progress(&self) -> broadcast::Receiver<TransferProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Subscribe to transfers cancelled by either end
+    #[allow(dead_code)]
+    pub fn subscribe_cancelled(&self) -> broadcast::Receiver<TransferCancelled> {
+        self.progress.subscribe_cancelled()
+    }
+
+    /// Subscribe to received messages rejected by their integrity checks
+    #[allow(dead_code)]
+    pub fn subscribe_failed(&self) -> broadcast::Receiver<TransferFailed> {
+        self.progress.subscribe_failed()
+    }
+
+    fn progress_reporter(&self) -> ProgressReporter {
+        self.progress.with_app_handle(self.app_handle.clone())
+    }
+
+    /// Outgoing transfers in flight, for cancelling them
+    pub fn transfers(&self) -> Arc<ActiveTransfers> {
+        self.transfers.clone()
+    }
+
+    /// Rooms this instance joined, advertised with every discovery message
+    pub fn rooms(&self) -> Arc<Rooms> {
+        self.rooms.clone()
+    }
+
+    /// Get the authentication applied to every datagram sent and received
+    pub fn auth(&self) -> Arc<MessageAuth> {
+        self.auth.clone()
+    }
+
+    /// Get the limiter applied to incoming traffic
+    pub fn rate_limiter(&self) -> Arc<RateLimiter> {
+        self.limiter.clone()
+    }
+
+    /// Get the latest-wins queue live-mirror updates claim their slots from
+    pub fn mirror_queue(&self) -> Arc<OutboundQueue> {
+        self.mirror_queue.clone()
+    }
+
+    /// Get the shared text edited together with peers
+    pub fn shared(&self) -> Arc<SharedDoc> {
+        self.shared.clone()
+    }
+
+    /// Texts from peers that aren't auto-accepted, held until accepted or rejected
+    pub fn inbox(&self) -> Arc<Inbox> {
+        self.inbox.clone()
+    }
+
+    /// Chunk `text` once and send it to all of `peers` concurrently
+    ///
+    /// At most `MAX_PARALLEL_TRANSFERS` transfers run at a time. Returns one
+    /// result per peer, in the order of `peers`; an error means nothing could
+    /// be sent at all.
+    pub async fn send_text(&self, peers: &[Peer], text: &str) -> Result<Vec<PeerSendResult>> {
+        self.send_text_with_mode(peers, text, SendMode::Unicast).await
+    }
+
+    /// Like `send_text`, but with `SendMode::Multicast` the data goes out once
+    /// to the multicast group for every peer listening on the default port
+    pub async fn send_text_with_mode(&self, peers: &[Peer], text: &str, mode: SendMode) -> Result<Vec<PeerSendResult>> {
+        self.send_scoped_text(peers, text, mode, None).await
+    }
+
+    /// Send `text` to the peers with the given ids in one fan-out
+    ///
+    /// Results follow the order of `peer_ids` (duplicates removed); ids not in
+    /// the registry fail without holding up the others.
+    pub async fn send_text_to_peer_ids(&self, peer_ids: &[String], text: &str, mode: SendMode) -> Result<Vec<PeerSendResult>> {
+        let mut wanted: Vec<&String> = Vec::with_capacity(peer_ids.len());
+        for id in peer_ids {
+            if !wanted.contains(&id) {
+                wanted.push(id);
+            }
+        }
+        let mut peers = Vec::new();
+        for id in &wanted {
+            if let Some(peer) = self.registry.get_peer(id).await {
+                peers.push(peer);
+            }
+        }
+
+        let mut sent: HashMap<String, PeerSendResult> = self.send_text_with_mode(&peers, text, mode).await?
+            .into_iter()
+            .map(|result| (result.peer_id.clone(), result))
+            .collect();
+        Ok(wanted.into_iter()
+            .map(|id| sent.remove(id).unwrap_or_else(|| PeerSendResult {
+                peer_id: id.clone(),
+                status: SendStatus::Failed,
+                chunks_confirmed: 0,
+                total_chunks: 0,
+                error: Some(format!("Peer {} not found", id)),
+            }))
+            .collect())
+    }
+
+    /// Send `text` to every peer that joined `room`
+    ///
+    /// The text is tagged with the room, so peers that haven't joined it drop
+    /// it even if it reaches them (e.g. over multicast).
+    pub async fn send_text_to_room(&self, room: &str, text: &str, mode: SendMode) -> Result<Vec<PeerSendResult>> {
+        let room = normalize_room_name(room)?;
+        let peers = self.registry.get_peers_in_room(&room).await;
+        self.send_scoped_text(&peers, text, mode, Some(room)).await
+    }
+
+    async fn send_scoped_text(&self, peers: &[Peer], text: &str, mode: SendMode, room: Option<String>) -> Result<Vec<PeerSendResult>> {
+        let (message_id, results) = self.send_stamped(peers, text, mode, &HashMap::new(), |message| message.room = room.clone()).await?;
+        self.record_sent(message_id, text, &results);
+        Ok(results)
+    }
+
+    /// Mirror `text` to `peers` as a new version
+    ///
+    /// Peers that confirmed an earlier version get only the edit against it;
+    /// everyone else gets a full snapshot. A peer that doesn't confirm a
+    /// delta within `MIRROR_ACK_TIMEOUT` is sent the snapshot straight away.
+    ///
+    /// `slots` come from `mirror_queue().supersede`; claim them before waiting
+    /// to send, so this update cancels older ones still queued or in flight.
+    /// Peers whose slot a newer update took over are skipped.
+    pub async fn mirror_text(&self, peers: &[Peer], text: &str, slots: HashMap<String, CancelToken>) -> Result<Vec<PeerSendResult>> {
+        let results = self.mirror_in_slots(peers, text, &slots).await;
+        self.mirror_queue.finish(&slots);
+        results
+    }
+
+    async fn mirror_in_slots(&self, peers: &[Peer], text: &str, slots: &HashMap<String, CancelToken>) -> Result<Vec<PeerSendResult>> {
+        let superseded = |peer_id: &str| slots.get(peer_id).is_some_and(CancelToken::is_cancelled);
+        let mut finished: HashMap<String, PeerSendResult> = peers.iter()
+            .filter(|peer| superseded(&peer.id))
+            .map(|peer| (peer.id.clone(), PeerSendResult {
+                peer_id: peer.id.clone(),
+                status: SendStatus::Cancelled,
+                chunks_confirmed: 0,
+                total_chunks: 0,
+                error: Some("Superseded by a newer update".to_string()),
+            }))
+            .collect();
+        let live: Vec<Peer> = peers.iter().filter(|peer| !superseded(&peer.id)).cloned().collect();
+        if live.is_empty() {
+            debug!("Mirror update superseded for every peer before it was sent");
+            return Ok(peers.iter().filter_map(|peer| finished.remove(&peer.id)).collect());
+        }
+
+        let version = self.mirror_outbox.publish(text);
+        let snapshot = MirrorHeader { version, delta: None };
+        // Peers outside the current room drop the update
+        let room = self.rooms.current();
+
+        // Peers holding the same version share one delta
+        let mut snapshot_peers = Vec::new();
+        let mut by_base: HashMap<u64, (Arc<str>, Vec<Peer>)> = HashMap::new();
+        for peer in &live {
+            match self.mirror_outbox.base_for(&peer.id) {
+                Some((base_version, base)) => by_base.entry(base_version).or_insert_with(|| (base, Vec::new())).1.push(peer.clone()),
+                None => snapshot_peers.push(peer.clone()),
+            }
+        }
+
+        let mut message_id = None;
+        if !snapshot_peers.is_empty() {
+            let (id, results) = self.send_stamped(&snapshot_peers, text, SendMode::Unicast, slots, |message| {
+                message.mirror = Some(snapshot.clone());
+                message.room = room.clone();
+            }).await?;
+            message_id = message_id.or(id);
+            finished.extend(results.into_iter().map(|result| (result.peer_id.clone(), result)));
+        }
+        let mut delta_peers = Vec::new();
+        for (base_version, (base, group)) in by_base {
+            let delta = TextDelta::between(&base, text);
+            let header = MirrorHeader {
+                version,
+                delta: Some(DeltaHeader { base_version, start: delta.start, delete_len: delta.delete_len }),
+            };
+            debug!("Mirroring version {} to {} peer(s) as a {} byte edit of version {}",
+                   version, group.len(), delta.insert.len(), base_version);
+            let (_, results) = self.send_stamped(&group, &delta.insert, SendMode::Unicast, slots, |message| {
+                message.mirror = Some(header.clone());
+                message.room = room.clone();
+            }).await?;
+            for result in results {
+                if result.status.reached_peer() {
+                    delta_peers.push(result.peer_id.clone());
+                }
+                finished.insert(result.peer_id.clone(), result);
+            }
+        }
+
+        // Deltas that weren't applied (or whose acknowledgement was lost) are
+        // followed by a snapshot so the peer doesn't stay out of date, unless
+        // a newer update is on its way anyway
+        let diverged: Vec<String> = self.mirror_outbox.wait_for_acks(&delta_peers, version, MIRROR_ACK_TIMEOUT).await
+            .into_iter()
+            .filter(|peer_id| !superseded(peer_id))
+            .collect();
+        if !diverged.is_empty() {
+            info!("Falling back to a mirror snapshot for {} peer(s)", diverged.len());
+            let fallback: Vec<Peer> = live.iter().filter(|peer| diverged.contains(&peer.id)).cloned().collect();
+            let (id, results) = self.send_stamped(&fallback, text, SendMode::Unicast, slots, |message| {
+                message.mirror = Some(snapshot.clone());
+                message.room = room.clone();
+            }).await?;
+            message_id = message_id.or(id);
+            finished.extend(results.into_iter().map(|result| (result.peer_id.clone(), result)));
+        }
+
+        let results: Vec<PeerSendResult> = peers.iter()
+            .filter_map(|peer| finished.remove(&peer.id))
+            .collect();
+        self.record_sent(message_id, text, &results);
+        Ok(results)
+    }
+
+    /// Online peers the shared text is edited with: collaborators in our room
+    /// whose permissions share it (see [`Permissions::shares_text`])
+    pub async fn shared_text_peers(&self) -> Vec<Peer> {
+        let collaborators = self.shared.collaborators();
+        self.registry.get_peers().await.into_iter()
+            .filter(|peer| collaborators.contains(&peer.id) && self.rooms.shares_room(&peer.rooms))
+            .filter(|peer| shares_text_with(self.access.as_deref(), peer))
+            .collect()
+    }
+
+    /// Apply the user's edit of the shared text, made on the view `base_version`
+    ///
+    /// The resulting operations go to every collaborating peer that is online
+    /// and still shares the text; one that misses them catches up from the
+    /// version vector it advertises.
+    pub async fn edit_shared_text(&self, base_version: u64, text: &str) -> Result<(SharedTextView, Vec<PeerSendResult>)> {
+        let (view, ops) = self.shared.edit(base_version, text)?;
+        let peers = self.shared_text_peers().await;
+        let room = self.rooms.current();
+        if ops.is_empty() || peers.is_empty() {
+            return Ok((view, Vec::new()));
+        }
+
+        // Each peer's result is that of the first batch it didn't get
+        let mut results: Vec<PeerSendResult> = Vec::new();
+        for batch in encode_batches(&ops, MAX_MESSAGE_BYTES)? {
+            let (_, sent) = self.send_stamped(&peers, &batch, SendMode::Unicast, &HashMap::new(), |message| {
+                message.shared_ops = true;
+                message.room = room.clone();
+            }).await?;
+            if results.is_empty() {
+                results = sent;
+            } else {
+                for (result, latest) in results.iter_mut().zip(sent) {
+                    if result.status.reached_peer() {
+                        *result = latest;
+                    }
+                }
             }
         }
+        Ok((view, results))
+    }
 
-        let mut states = reassembly_states.write().await;
-        
-        // Get or create reassembly state
-        let is_complete = if let Some(state) = states.get_mut(message_id) {
-            let was_complete = state.is_complete();
-            let is_complete = state.add_chunk(seq_no, payload.clone());
-            if !was_complete {
-                info!("Reassembly progress for {}: {}/{} chunks received", 
-                      message_id, state.received_count, total_chunks);
-            }
-            is_complete
-        } else {
-            let mut new_state = ReassemblyState::new(total_chunks, message.peer_id.clone());
-            let is_complete = new_state.add_chunk(seq_no, payload.clone());
-            states.insert(message_id.clone(), new_state);
-            info!("Started reassembly for message {}: {}/{} chunks received", 
-                  message_id, 1, total_chunks);
-            is_complete
+    /// Chunk `payload` once, let `stamp` tag every message, and send it to all
+    /// of `peers` concurrently; returns the message id if it was chunked
+    ///
+    /// A peer whose token in `superseded` is cancelled is no longer sent to.
+    async fn send_stamped(
+        &self,
+        peers: &[Peer],
+        payload: &str,
+        mode: SendMode,
+        superseded: &HashMap<String, CancelToken>,
+        stamp: impl Fn(&mut DiscoveryMessage),
+    ) -> Result<(Option<String>, Vec<PeerSendResult>)> {
+        // Whatever picked the recipients, blocked peers get nothing
+        let all_peers = peers;
+        let blocked: HashSet<&str> = all_peers.iter()
+            .filter(|peer| self.access.as_ref()
+                .is_some_and(|access| access.is_blocked(&peer.id, Some(peer.ip), peer.hostname.as_deref())))
+            .map(|peer| peer.id.as_str())
+            .collect();
+        let peers: Vec<Peer> = all_peers.iter().filter(|peer| !blocked.contains(peer.id.as_str())).cloned().collect();
+        let own_peer_id = self.peer_id().unwrap_or_default();
+        let port = self.listen_port.unwrap_or(DISCOVERY_PORT);
+        let mut messages = self.chunk_text_to_messages(payload, &own_peer_id, port, hostname::get())?;
+        let text_seq = self.text_seq.fetch_add(1, Ordering::SeqCst) + 1;
+        for message in &mut messages {
+            message.text_seq = Some(text_seq);
+            stamp(message);
+        }
+        let mut outgoing = OutgoingText::new(own_peer_id, port, messages, payload.len(), self.progress_reporter(), self.auth.clone(), self.replay.clone())?;
+
+        // Chunked transfers can be cancelled by message id while they are sent
+        let message_id = outgoing.messages[0].message_id.clone();
+        let chunked_id = outgoing.message_id.clone();
+        outgoing.cancel = chunked_id.as_deref().map(|id| self.transfers.register(id));
+        outgoing.superseded = superseded.clone();
+        let outgoing = Arc::new(outgoing);
+
+        // Peers on a fallback port can't receive the group traffic
+        let (group_peers, unicast_peers): (Vec<Peer>, Vec<Peer>) = match mode {
+            SendMode::Multicast => peers.iter().cloned().partition(|peer| peer.port == DISCOVERY_PORT),
+            SendMode::Unicast => (Vec::new(), peers.to_vec()),
         };
 
-        // If complete, reassemble and emit
-        if is_complete {
-            if let Some(state) = states.remove(message_id) {
-                match state.reassemble() {
-                    Ok(complete_text) => {
-                        info!("Reassembled complete message from {} chunks: {} chars", total_chunks, complete_text.len());
-                        if let Some(app) = app_handle {
-                            let _ = app.emit("text-received", &complete_text);
-                        }
-                    }
-                    Err(e) => {
-                        error!("Failed to reassemble message {}: {}", message_id, e);
+        let slots = Arc::new(Semaphore::new(MAX_PARALLEL_TRANSFERS));
+        let mut tasks = JoinSet::new();
+        if !group_peers.is_empty() {
+            let outgoing = outgoing.clone();
+            let transport = self.transport.clone();
+            tasks.spawn(async move { outgoing.multicast_to(transport.as_ref(), &group_peers).await });
+        }
+        for peer in unicast_peers {
+            let outgoing = outgoing.clone();
+            let transport = self.transport.clone();
+            let slots = slots.clone();
+            tasks.spawn(async move {
+                let _slot = slots.acquire_owned().await.expect("transfer slots are never closed");
+                vec![outgoing.send_to(transport.as_ref(), &peer).await]
+            });
+        }
+        let mut finished = HashMap::new();
+        while let Some(joined) = tasks.join_next().await {
+            match joined {
+                Ok(results) => {
+                    for result in results {
+                        finished.insert(result.peer_id.clone(), result);
                     }
                 }
+                Err(e) => error!("Transfer task failed: {}", e),
             }
         }
+        if let Some(id) = &chunked_id {
+            self.transfers.finish(id);
+        }
 
-        Ok(())
-    }
-
-    /// Get the peer registry
-    pub fn registry(&self) -> Arc<PeerRegistry> {
-        self.registry.clone()
+        let total_chunks = outgoing.messages.len() as u32;
+        let results: Vec<PeerSendResult> = all_peers.iter()
+            .map(|peer| finished.remove(&peer.id).unwrap_or_else(|| {
+                let (status, error) = match blocked.contains(peer.id.as_str()) {
+                    true => (SendStatus::Blocked, format!("Peer {} is blocked", peer.id)),
+                    false => (SendStatus::Failed, "Transfer task failed".to_string()),
+                };
+                PeerSendResult { peer_id: peer.id.clone(), status, chunks_confirmed: 0, total_chunks, error: Some(error) }
+            }))
+            .collect();
+        Ok((message_id, results))
     }
 
-    /// Get the current peer ID
-    pub fn peer_id(&self) -> Option<String> {
-        self.peer_id.clone()
+    /// Record a sent text in history with the recipients it reached
+    fn record_sent(&self, message_id: Option<String>, text: &str, results: &[PeerSendResult]) {
+        let recipients: Vec<String> = results.iter()
+            .filter(|result| result.status.reached_peer())
+            .map(|result| result.peer_id.clone())
+            .collect();
+        if let (Some(history), false) = (&self.history, recipients.is_empty()) {
+            let entry = HistoryEntry::new(
+                message_id.unwrap_or_else(|| Uuid::new_v4().to_string()),
+                Direction::Sent,
+                self.peer_id().unwrap_or_default(),
+                hostname::get(),
+                recipients,
+                text.to_string(),
+            );
+            if let Err(e) = history.record(&entry) {
+                error!("Failed to record sent message in history: {}", e);
+            }
+        }
     }
 
     /// Chunk text into multiple messages for large payloads
     pub fn chunk_text_to_messages(&self, text: &str, peer_id: &str, port: u16, hostname: Option<String>) -> Result<Vec<DiscoveryMessage>> {
-        let text_bytes = text.as_bytes();
-        
-        if text_bytes.len() <= CHUNK_PAYLOAD_BYTES {
-            // Single message
-            return Ok(vec![DiscoveryMessage {
-                message_type: MessageType::TextMessage,
-                peer_id: peer_id.to_string(),
-                port,
-                hostname,
-                timestamp: Utc::now(),
-                text: Some(text.to_string()),
-                message_id: None,
-                seq_no: None,
-                total_chunks: None,
-                payload_len: None,
-                checksum: None,
-                payload: None,
-            }]);
-        }
-
-        // Chunked message
-        let message_id = Uuid::new_v4().to_string();
-        let chunks: Vec<&[u8]> = text_bytes.chunks(CHUNK_PAYLOAD_BYTES).collect();
-        let total_chunks = chunks.len() as u32;
-        
-        let mut messages = Vec::new();
-        for (seq_no, chunk) in chunks.iter().enumerate() {
-            let checksum = simple_checksum(chunk);
-            messages.push(DiscoveryMessage {
-                message_type: MessageType::TextChunk,
-                peer_id: peer_id.to_string(),
-                port,
-                hostname: hostname.clone(),
-                timestamp: Utc::now(),
-                text: None,
-                message_id: Some(message_id.clone()),
-                seq_no: Some(seq_no as u32),
-                total_chunks: Some(total_chunks),
-                payload_len: Some(chunk.len() as u32),
-                checksum: Some(checksum),
-                payload: Some(chunk.to_vec()),
-            });
-        }
-        
-        Ok(messages)
+        Ok(chunk_text(text, peer_id, port, hostname))
     }
 
-
     /// Stop the discovery service
     #[allow(dead_code)]
     pub async fn stop(&mut self) -> Result<()> {
@@ -758,34 +2467,38 @@ impl DiscoveryService {
     }
 }
 
-/// Compute broadcast targets for all non-loopback IPv4 interfaces.
-fn ipv4_broadcast_targets() -> Vec<SocketAddr> {
-    let mut targets: Vec<SocketAddr> = Vec::new();
-    if let Ok(ifaces) = get_if_addrs() {
-        for iface in ifaces {
-            // Only consider IPv4 addresses
-            let (ip, mask, broadcast_opt) = match iface.addr {
-                IfAddr::V4(v4) => (v4.ip, v4.netmask, v4.broadcast),
-                _ => continue,
-            };
-            // Skip loopback and link-local 169.254.x.x
-            let oct0 = ip.octets()[0];
-            if ip.is_loopback() || oct0 == 169 { continue; }
+/// Split `text` into one `TextMessage`, or `TextChunk`s, under a fresh message id
+fn chunk_text(text: &str, peer_id: &str, port: u16, hostname: Option<String>) -> Vec<DiscoveryMessage> {
+    let text_bytes = text.as_bytes();
+    
+    if text_bytes.len() <= CHUNK_PAYLOAD_BYTES {
+        // Single message
+        let mut message = DiscoveryMessage::new(MessageType::TextMessage, peer_id.to_string(), port, hostname);
+        message.text = Some(text.to_string());
+        message.message_id = Some(Uuid::new_v4().to_string());
+        return vec![message];
+    }
 
-            let bcast = if let Some(b) = broadcast_opt { b } else {
-                let bcast_u32 = u32::from(ip) | !u32::from(mask);
-                Ipv4Addr::from(bcast_u32)
-            };
-            targets.push(SocketAddr::new(IpAddr::V4(bcast), 7878));
-        }
+    // Chunked message
+    let message_id = Uuid::new_v4().to_string();
+    let chunks: Vec<&[u8]> = text_bytes.chunks(CHUNK_PAYLOAD_BYTES).collect();
+    let total_chunks = chunks.len() as u32;
+    let digest = message_digest(text_bytes);
+    
+    let mut messages = Vec::new();
+    for (seq_no, chunk) in chunks.iter().enumerate() {
+        let mut message = DiscoveryMessage::new(MessageType::TextChunk, peer_id.to_string(), port, hostname.clone());
+        message.message_id = Some(message_id.clone());
+        message.seq_no = Some(seq_no as u32);
+        message.total_chunks = Some(total_chunks);
+        message.payload_len = Some(chunk.len() as u32);
+        message.checksum = Some(chunk_checksum(chunk));
+        message.payload = Some(chunk.to_vec());
+        message.digest = (seq_no == 0).then(|| digest.clone());
+        messages.push(message);
     }
-    targets.sort();
-    targets.dedup();
-    // Always include 255.255.255.255 as a last resort
-    targets.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), 7878));
-    targets.sort();
-    targets.dedup();
-    targets
+    
+    messages
 }
 
 // Helper function to get hostname
@@ -803,7 +2516,105 @@ mod hostname {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::access::AccessList;
+    use crate::netsim::LinkConditions;
+    use crate::ratelimit::RateLimitStats;
+    use crate::transport::memory::MemoryNetwork;
     use std::net::Ipv4Addr;
+    use std::sync::OnceLock;
+
+    /// Start a service on `network` with broadcaster and listener tasks running
+    async fn start_memory_service(network: &MemoryNetwork, ip: Ipv4Addr) -> DiscoveryService {
+        start_configured(network, ip, |_| {}).await
+    }
+
+    /// Start a service on `ip` once `configure` has set it up
+    async fn start_configured(network: &MemoryNetwork, ip: Ipv4Addr, configure: impl FnOnce(&mut DiscoveryService)) -> DiscoveryService {
+        let mut service = DiscoveryService::with_transport(Duration::from_secs(30), network.host(ip));
+        configure(&mut service);
+        service.start(DISCOVERY_PORT).await.unwrap();
+        let peer_id = service.peer_id().unwrap();
+        service.get_broadcaster_task().unwrap();
+        service.get_listener_task(peer_id).unwrap();
+        service
+    }
+
+    /// A raw socket on 10.0.0.1 that sends hand-built messages to a service on 10.0.0.2
+    struct Forger {
+        socket: Arc<dyn Transport>,
+        to: SocketAddr,
+        auth: MessageAuth,
+    }
+
+    impl Forger {
+        /// A socket on 10.0.0.1 aimed at the listener on 10.0.0.2
+        async fn new(network: &MemoryNetwork) -> Self {
+            Self {
+                socket: network.host(Ipv4Addr::new(10, 0, 0, 1)).bind(0).await.unwrap(),
+                to: SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), DISCOVERY_PORT),
+                auth: MessageAuth::new(),
+            }
+        }
+
+        /// Announce the forger as peer "sender", answering on its own socket,
+        /// with `seen` as its shared-text version vector
+        async fn announce(&self, seen: &VersionVector) {
+            let port = self.socket.local_addr().unwrap().port();
+            let mut announcement = DiscoveryMessage::new(MessageType::PeerDiscovery, "sender".to_string(), port, None);
+            announcement.shared_versions = Some(seen.clone());
+            self.send(&announcement).await;
+        }
+
+        async fn send(&self, message: &DiscoveryMessage) {
+            self.send_bytes(&self.auth.encode(message).unwrap()).await;
+        }
+
+        async fn send_bytes(&self, bytes: &[u8]) {
+            self.socket.send_to(bytes, self.to).await.unwrap();
+        }
+
+        /// Feedback sent back to the forger until it goes quiet
+        async fn feedback(&self) -> Vec<DiscoveryMessage> {
+            let mut feedback = Vec::new();
+            let mut buf = [0u8; 8192];
+            while let Ok(Ok((len, _))) = tokio::time::timeout(Duration::from_millis(200), self.socket.recv_from(&mut buf)).await {
+                feedback.push(serde_json::from_slice(&buf[..len]).unwrap());
+            }
+            feedback
+        }
+    }
+
+    /// Start a receiving service on 10.0.0.2 and a forger aimed at it
+    async fn start_forged(network: &MemoryNetwork) -> (DiscoveryService, Forger) {
+        let receiver = start_memory_service(network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        (receiver, Forger::new(network).await)
+    }
+
+    /// Wait until `service` has discovered `count` peers
+    async fn wait_for_peers(service: &DiscoveryService, count: usize) -> Vec<Peer> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let peers = service.registry().get_peers().await;
+                if peers.len() >= count {
+                    return peers;
+                }
+                sleep(Duration::from_millis(20)).await;
+            }
+        }).await.expect("peers were not discovered in time")
+    }
+
+    async fn next_text(rx: &mut broadcast::Receiver<ReceivedText>) -> ReceivedText {
+        tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap().unwrap()
+    }
+
+    /// Texts delivered until the receiver has been quiet for a moment
+    async fn collect_text(rx: &mut broadcast::Receiver<ReceivedText>) -> Vec<String> {
+        let mut texts = Vec::new();
+        while let Ok(Ok(received)) = tokio::time::timeout(Duration::from_millis(200), rx.recv()).await {
+            texts.push(received.text);
+        }
+        texts
+    }
 
     #[tokio::test]
     async fn test_peer_registry() {
@@ -817,14 +2628,14 @@ mod tests {
         );
 
         registry.add_peer(peer.clone()).await;
-        assert_eq!(registry.peer_count().await, 1);
+        assert_eq!(registry.get_peers().await.len(), 1);
 
         let peers = registry.get_peers().await;
         assert_eq!(peers.len(), 1);
         assert_eq!(peers[0].id, "test-id");
 
         registry.remove_peer("test-id").await;
-        assert_eq!(registry.peer_count().await, 0);
+        assert_eq!(registry.get_peers().await.len(), 0);
     }
 
     #[tokio::test]
@@ -842,13 +2653,13 @@ mod tests {
         peer.last_seen = Utc::now() - chrono::Duration::seconds(2);
         
         registry.add_peer(peer).await;
-        assert_eq!(registry.peer_count().await, 1);
+        assert_eq!(registry.get_peers().await.len(), 1);
 
         // Wait for cleanup
         sleep(Duration::from_millis(1100)).await;
         let removed = registry.cleanup_stale_peers().await;
         assert_eq!(removed, 1);
-        assert_eq!(registry.peer_count().await, 0);
+        assert_eq!(registry.get_peers().await.len(), 0);
     }
 
     #[tokio::test]
@@ -860,7 +2671,7 @@ mod tests {
         
         // Test that the registry is accessible
         let registry = discovery_service.registry();
-        assert_eq!(registry.peer_count().await, 0);
+        assert_eq!(registry.get_peers().await.len(), 0);
     }
 
     #[test]
@@ -874,7 +2685,7 @@ mod tests {
         assert_eq!(messages.len(), 1);
         assert_eq!(messages[0].message_type, MessageType::TextMessage);
         assert_eq!(messages[0].text, Some(small_text.to_string()));
-        assert!(messages[0].message_id.is_none());
+        assert!(messages[0].message_id.is_some());
         assert!(messages[0].seq_no.is_none());
         assert!(messages[0].total_chunks.is_none());
     }
@@ -895,6 +2706,8 @@ mod tests {
         assert!(messages[0].total_chunks.is_some());
         assert!(messages[0].payload.is_some());
         assert!(messages[0].checksum.is_some());
+        assert_eq!(messages[0].digest, Some(message_digest(large_text.as_bytes())));
+        assert!(messages[1..].iter().all(|message| message.digest.is_none()));
         
         // All chunks should have the same message_id
         let message_id = messages[0].message_id.as_ref().unwrap();
@@ -941,14 +2754,222 @@ mod tests {
         let data2 = b"Hello, World!";
         let data3 = b"Hello, World?";
         
-        let checksum1 = simple_checksum(data1);
-        let checksum2 = simple_checksum(data2);
-        let checksum3 = simple_checksum(data3);
+        let checksum1 = chunk_checksum(data1);
+        let checksum2 = chunk_checksum(data2);
+        let checksum3 = chunk_checksum(data3);
         
         // Same data should have same checksum
         assert_eq!(checksum1, checksum2);
         // Different data should have different checksum
         assert_ne!(checksum1, checksum3);
+        // Reordered bytes and offsetting changes, which a byte sum misses
+        assert_ne!(chunk_checksum(b"ab"), chunk_checksum(b"ba"));
+        assert_ne!(chunk_checksum(b"ac"), chunk_checksum(b"bb"));
+        assert_eq!(chunk_checksum(b"123456789"), 0xe3069283);
+        assert_eq!(message_digest(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn test_validate_chunk_rejects_inconsistent_headers() {
+        let payload = vec![b'x'; CHUNK_PAYLOAD_BYTES];
+        assert!(validate_chunk(0, 2, Some(payload.len() as u32), &payload).is_ok());
+        assert!(validate_chunk(0, MAX_TOTAL_CHUNKS, Some(1), b"x").is_ok());
+
+        assert!(validate_chunk(0, 0, Some(1), b"x").is_err());
+        assert!(validate_chunk(0, u32::MAX, Some(1), b"x").is_err());
+        assert!(validate_chunk(0, MAX_TOTAL_CHUNKS + 1, Some(1), b"x").is_err());
+        assert!(validate_chunk(2, 2, Some(1), b"x").is_err());
+        assert!(validate_chunk(0, 2, Some(2), b"x").is_err());
+        assert!(validate_chunk(0, 2, None, b"x").is_err());
+        assert!(validate_chunk(0, 2, Some(0), b"").is_err());
+        let oversized = vec![b'x'; CHUNK_PAYLOAD_BYTES + 1];
+        assert!(validate_chunk(0, 2, Some(oversized.len() as u32), &oversized).is_err());
+    }
+
+    #[test]
+    fn test_check_permissions_by_kind_and_size() {
+        let text = chunk_text("hello", "peer", 9000, None).remove(0);
+        let chunk = chunk_text(&"x".repeat(3 * CHUNK_PAYLOAD_BYTES), "peer", 9000, None).remove(0);
+        let mut mirrored = text.clone();
+        mirrored.mirror = Some(MirrorHeader { version: 1, delta: None });
+        let mut shared = chunk.clone();
+        shared.shared_ops = true;
+
+        let defaults = Permissions::default();
+        for message in [&text, &chunk, &mirrored, &shared] {
+            assert!(check_permissions(&defaults, message).is_ok());
+        }
+
+        let no_mirror = Permissions { live_mirror: false, ..defaults };
+        assert!(check_permissions(&no_mirror, &text).is_ok());
+        assert!(check_permissions(&no_mirror, &mirrored).is_err());
+        assert!(check_permissions(&no_mirror, &shared).is_err());
+
+        let no_text = Permissions { text: false, ..defaults };
+        assert!(check_permissions(&no_text, &text).is_err());
+        assert!(check_permissions(&no_text, &mirrored).is_ok());
+
+        // Three chunks hold at least two full payloads
+        let small = Permissions { max_text_bytes: 2 * CHUNK_PAYLOAD_BYTES, ..defaults };
+        assert!(check_permissions(&small, &text).is_ok());
+        assert!(check_permissions(&small, &chunk).is_err());
+        assert!(check_permissions(&Permissions { max_text_bytes: 2 * CHUNK_PAYLOAD_BYTES + 1, ..defaults }, &chunk).is_ok());
+    }
+
+    /// A receiver on 10.0.0.2 applying `permissions` to the peer on 10.0.0.1
+    async fn start_with_policy(network: &MemoryNetwork, permissions: Permissions) -> DiscoveryService {
+        let access = AccessStore::open_in_memory().unwrap();
+        let permissions = Permissions { auto_accept: true, ..permissions };
+        access.set_policy("sender", Some(Ipv4Addr::new(10, 0, 0, 1).into()), None, permissions).unwrap();
+        start_with_access(network, Arc::new(access)).await
+    }
+
+    /// A receiver on 10.0.0.2 that checks its peers against `access`
+    async fn start_with_access(network: &MemoryNetwork, access: Arc<AccessStore>) -> DiscoveryService {
+        start_configured(network, Ipv4Addr::new(10, 0, 0, 2), |service| service.access = Some(access)).await
+    }
+
+    #[tokio::test]
+    async fn test_policy_without_text_refuses_texts() {
+        let network = MemoryNetwork::new();
+        let receiver = start_with_policy(&network, Permissions { text: false, ..Permissions::default() }).await;
+        let mut received = receiver.subscribe_text();
+
+        let sender = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let peers = wait_for_peers(&sender, 1).await;
+        sender.send_text(&peers, "small").await.unwrap();
+        sender.send_text(&peers, &"x".repeat(2000)).await.unwrap();
+        // Live mirroring has a flag of its own
+        sender.mirror_text(&peers, "mirrored", sender.mirror_queue().supersede(peers.iter().map(|peer| peer.id.as_str()))).await.unwrap();
+        assert_eq!(collect_text(&mut received).await, vec!["mirrored".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_policy_without_live_mirror_refuses_mirror_and_shared_text() {
+        let network = MemoryNetwork::new();
+        let receiver = start_with_policy(&network, Permissions { live_mirror: false, ..Permissions::default() }).await;
+        let mut received = receiver.subscribe_text();
+
+        let sender = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let peers = wait_for_peers(&sender, 1).await;
+        wait_for_peers(&receiver, 1).await;
+        sender.edit_shared_text(0, "shared").await.unwrap();
+        sender.mirror_text(&peers, "mirrored", sender.mirror_queue().supersede(peers.iter().map(|peer| peer.id.as_str()))).await.unwrap();
+        sender.send_text(&peers, "small").await.unwrap();
+        assert_eq!(collect_text(&mut received).await, vec!["small".to_string()]);
+        assert_eq!(receiver.shared().view().text, "");
+    }
+
+    #[tokio::test]
+    async fn test_policy_caps_text_size() {
+        let network = MemoryNetwork::new();
+        let receiver = start_with_policy(&network, Permissions { max_text_bytes: 1000, ..Permissions::default() }).await;
+        let mut received = receiver.subscribe_text();
+
+        let sender = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let peers = wait_for_peers(&sender, 1).await;
+        // Two chunks could still fit the limit, so this is only caught once reassembled
+        sender.send_text(&peers, &"x".repeat(1200)).await.unwrap();
+        sender.send_text(&peers, &"x".repeat(3000)).await.unwrap();
+        sender.send_text(&peers, "small").await.unwrap();
+        assert_eq!(collect_text(&mut received).await, vec!["small".to_string()]);
+    }
+
+    #[test]
+    fn test_reassembly_limits_per_peer_and_overall() {
+        let mut states = HashMap::new();
+        for i in 0..MAX_REASSEMBLIES_PER_PEER {
+            check_reassembly_limits(&states, "greedy", 1).unwrap();
+            states.insert(format!("greedy-{}", i), ReassemblyState::new(1, "greedy".to_string()));
+        }
+        assert!(check_reassembly_limits(&states, "greedy", 1).is_err());
+        assert!(check_reassembly_limits(&states, "other", 1).is_ok());
+
+        // Buffer space is claimed by chunk count, not by what has arrived
+        let mut states = HashMap::new();
+        let per_peer = MAX_BUFFERED_BYTES_PER_PEER / MAX_MESSAGE_BYTES;
+        for i in 0..per_peer {
+            states.insert(format!("big-{}", i), ReassemblyState::new(MAX_TOTAL_CHUNKS, "big".to_string()));
+        }
+        assert!(check_reassembly_limits(&states, "big", MAX_TOTAL_CHUNKS).is_err());
+        assert!(check_reassembly_limits(&states, "big", 1).is_err());
+
+        let mut states = HashMap::new();
+        for i in 0..MAX_REASSEMBLIES {
+            states.insert(format!("m-{}", i), ReassemblyState::new(1, format!("peer-{}", i)));
+        }
+        assert!(check_reassembly_limits(&states, "newcomer", 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_forged_chunks_open_no_reassembly() {
+        let network = MemoryNetwork::new();
+        let (receiver, forger) = start_forged(&network).await;
+
+        let genuine = chunk_text(&"x".repeat(2000), "sender", 9000, None);
+        let mut huge = genuine[0].clone();
+        huge.message_id = Some("huge".to_string());
+        huge.total_chunks = Some(u32::MAX);
+        let mut lying = genuine[1].clone();
+        lying.message_id = Some("lying".to_string());
+        lying.payload_len = Some(1);
+        forger.send(&huge).await;
+        forger.send(&lying).await;
+
+        // A chunk that contradicts the message it claims to belong to
+        forger.send(&genuine[0]).await;
+        let mut hijack = genuine[1].clone();
+        hijack.peer_id = "intruder".to_string();
+        forger.send(&hijack).await;
+        sleep(Duration::from_millis(100)).await;
+
+        let states = receiver.reassembly_states.read().await;
+        assert_eq!(states.len(), 1);
+        let state = &states[genuine[0].message_id.as_ref().unwrap()];
+        assert_eq!(state.sender_peer_id, "sender");
+        assert_eq!(state.received_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_message_failing_its_digest_is_requested_again() {
+        let network = MemoryNetwork::new();
+        let (receiver, forger) = start_forged(&network).await;
+        let mut received = receiver.subscribe_text();
+        let mut failed = receiver.subscribe_failed();
+
+        // Swap the two payloads and fix up their checksums: every chunk checks
+        // out on its own, but the message doesn't
+        let text = format!("{}{}", "a".repeat(CHUNK_PAYLOAD_BYTES), "b".repeat(CHUNK_PAYLOAD_BYTES));
+        let chunks = chunk_text(&text, "sender", 9000, None);
+        let mut swapped = chunks.clone();
+        for (chunk, payload) in swapped.iter_mut().zip([chunks[1].payload.clone(), chunks[0].payload.clone()]) {
+            chunk.checksum = Some(chunk_checksum(payload.as_ref().unwrap()));
+            chunk.payload = payload;
+        }
+        // A chunk corrupted in transit is refused outright
+        let mut corrupted = swapped[1].clone();
+        corrupted.payload.as_mut().unwrap()[0] ^= 1;
+        for chunk in [&corrupted, &swapped[0], &swapped[1]] {
+            forger.send(chunk).await;
+        }
+
+        let report = tokio::time::timeout(Duration::from_secs(1), failed.recv()).await.unwrap().unwrap();
+        assert_eq!(report.message_id, chunks[0].message_id.clone().unwrap());
+        assert_eq!(report.peer_id, "sender");
+        assert!(report.reason.contains("Digest mismatch"));
+        // The completing chunk isn't acknowledged; every chunk is asked for again
+        let feedback = forger.feedback().await;
+        assert!(feedback.iter().all(|message| message.message_type != MessageType::ChunkAck || message.seq_no == Some(0)));
+        assert!(feedback.iter().any(|message| message.missing == Some(vec![0, 1])));
+        assert!(collect_text(&mut received).await.is_empty());
+
+        // The repaired message is delivered and acknowledged
+        for chunk in &chunks {
+            forger.send(chunk).await;
+        }
+        assert_eq!(collect_text(&mut received).await, vec![text]);
+        assert!(forger.feedback().await.iter().any(|message| message.message_type == MessageType::ChunkAck && message.seq_no == Some(1)));
+        assert!(receiver.reassembly_states.read().await.is_empty());
     }
 
     #[test]
@@ -1015,10 +3036,23 @@ mod tests {
         state.add_chunk(1, b", ".to_vec());
         state.add_chunk(2, b"World!".to_vec());
         
+        assert!(state.reassemble().is_err());
+        state.digest = Some(message_digest(b"Hello, World!"));
         let result = state.reassemble().unwrap();
         assert_eq!(result, "Hello, World!");
     }
 
+    #[test]
+    fn test_reassembly_rejects_digest_mismatch() {
+        let mut state = ReassemblyState::new(2, "sender-peer".to_string());
+        state.digest = Some(message_digest(b"Hello, World!"));
+        // Each chunk intact on its own, but in the wrong order
+        state.add_chunk(0, b"World!".to_vec());
+        state.add_chunk(1, b"Hello, ".to_vec());
+        let error = state.reassemble().unwrap_err();
+        assert!(error.to_string().contains("Digest mismatch"));
+    }
+
     #[test]
     fn test_reassembly_incomplete_message() {
         let mut state = ReassemblyState::new(3, "sender-peer".to_string());
@@ -1041,6 +3075,7 @@ mod tests {
         
         state.add_chunk(0, bytes[..mid].to_vec());
         state.add_chunk(1, bytes[mid..].to_vec());
+        state.digest = Some(message_digest(bytes));
         
         let result = state.reassemble().unwrap();
         assert_eq!(result, emoji);
@@ -1061,6 +3096,17 @@ mod tests {
             payload_len: Some(5),
             checksum: Some(12345),
             payload: Some(b"Hello".to_vec()),
+            missing: None,
+            multicast: false,
+            rooms: None,
+            room: Some("design".to_string()),
+            mirror: Some(MirrorHeader { version: 2, delta: Some(DeltaHeader { base_version: 1, start: 3, delete_len: 0 }) }),
+            text_seq: Some(7),
+            shared_ops: true,
+            shared_versions: None,
+            digest: Some(message_digest(b"Hello, World")),
+            nonce: Some("test-nonce".to_string()),
+            recipients: None,
         };
         
         // Serialize
@@ -1080,6 +3126,8 @@ mod tests {
         assert_eq!(deserialized.total_chunks, original_message.total_chunks);
         assert_eq!(deserialized.payload_len, original_message.payload_len);
         assert_eq!(deserialized.checksum, original_message.checksum);
+        assert_eq!(deserialized.digest, original_message.digest);
+        assert_eq!(deserialized.nonce, original_message.nonce);
         assert_eq!(deserialized.payload, original_message.payload);
     }
 
@@ -1104,6 +3152,7 @@ mod tests {
         } else {
             // If chunked, verify we can reassemble
             let mut state = ReassemblyState::new(messages.len() as u32, "test-peer".to_string());
+            state.digest = messages[0].digest.clone();
             for (i, message) in messages.iter().enumerate() {
                 if let Some(payload) = &message.payload {
                     state.add_chunk(i as u32, payload.clone());
@@ -1113,4 +3162,833 @@ mod tests {
             assert_eq!(reassembled, unicode_text);
         }
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_memory_services_discover_each_other() {
+        let network = MemoryNetwork::new();
+        let a = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let b = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+
+        let peers_of_a = wait_for_peers(&a, 1).await;
+        let peers_of_b = wait_for_peers(&b, 1).await;
+
+        assert_eq!(peers_of_a[0].id, b.peer_id().unwrap());
+        assert_eq!(peers_of_a[0].ip, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(peers_of_b[0].id, a.peer_id().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_memory_services_exchange_small_and_chunked_text() {
+        let network = MemoryNetwork::new();
+        let sender = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let receiver = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let mut received = receiver.subscribe_text();
+
+        let peers = wait_for_peers(&sender, 1).await;
+
+        let before = Utc::now();
+        sender.send_text(&peers, "Hello, World!").await.unwrap();
+        let small = tokio::time::timeout(Duration::from_secs(1), received.recv()).await.unwrap().unwrap();
+        assert_eq!(small.text, "Hello, World!");
+        assert_eq!(small.sender_id, sender.peer_id().unwrap());
+        assert_eq!(small.source_addr.ip(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(small.size, 13);
+        assert!(!small.chunked);
+        assert!(small.message_id.is_some());
+        assert!(small.sent_at >= before && small.sent_at <= Utc::now());
+
+        let large_text = "Café 🌍 ".repeat(500);
+        sender.send_text(&peers, &large_text).await.unwrap();
+        let large = tokio::time::timeout(Duration::from_secs(1), received.recv()).await.unwrap().unwrap();
+        assert_eq!(large.text, large_text);
+        assert_eq!(large.size, large_text.len());
+        assert!(large.chunked);
+        assert!(large.message_id.is_some());
+        assert!(large.sent_at >= small.sent_at);
+    }
+
+    #[tokio::test]
+    async fn test_mirror_sends_deltas_and_falls_back_to_snapshots() {
+        let network = MemoryNetwork::new();
+        let sender = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let receiver = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let mut received = receiver.subscribe_text();
+        let peers = wait_for_peers(&sender, 1).await;
+        let receiver_id = receiver.peer_id().unwrap();
+
+        // Nothing confirmed yet: the whole document goes out
+        let document = "lorem ipsum ".repeat(500);
+        sender.mirror_text(&peers, &document, HashMap::new()).await.unwrap();
+        let first = next_text(&mut received).await;
+        assert_eq!(first.text, document);
+        assert!(first.chunked);
+        assert_eq!(first.mirror_version, Some(1));
+
+        // One keystroke later only the edit is sent, but the full text arrives
+        let edited = format!("{}!", document);
+        sender.mirror_text(&peers, &edited, HashMap::new()).await.unwrap();
+        let second = next_text(&mut received).await;
+        assert_eq!(second.text, edited);
+        assert!(!second.chunked);
+        assert_eq!(second.mirror_version, Some(2));
+
+        // The sender believes the receiver is behind; the delta can't apply
+        // there, so a snapshot follows
+        sender.mirror_outbox.on_ack(&receiver_id, Some(1));
+        let rewritten = format!("intro {}", edited);
+        let results = sender.mirror_text(&peers, &rewritten, HashMap::new()).await.unwrap();
+        assert_eq!(results[0].status, SendStatus::Delivered);
+        let third = next_text(&mut received).await;
+        assert_eq!(third.text, rewritten);
+        assert!(third.chunked);
+        assert_eq!(third.mirror_version, Some(3));
+        assert_eq!(sender.mirror_outbox.base_for(&receiver_id).map(|(version, _)| version), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_older_text_from_same_sender_is_dropped() {
+        let network = MemoryNetwork::new();
+        let (receiver, forger) = start_forged(&network).await;
+        let mut received = receiver.subscribe_text();
+        let send = |text: &str, seq: u64| {
+            let mut messages = chunk_text(text, "sender", 9000, None);
+            messages.iter_mut().for_each(|message| message.text_seq = Some(seq));
+            messages.iter().map(|message| serde_json::to_vec(message).unwrap()).collect::<Vec<_>>()
+        };
+
+        // Text 2 arrives before the chunks of text 1 finish; text 1 is stale by then
+        let large = send(&"x".repeat(5000), 1);
+        for bytes in send("second", 2).iter().chain(&large) {
+            forger.send_bytes(bytes).await;
+        }
+        for bytes in send("third", 3).iter().chain(&send("second again", 2)) {
+            forger.send_bytes(bytes).await;
+        }
+        assert_eq!(collect_text(&mut received).await, vec!["second".to_string(), "third".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_stale_mirror_update_leaves_mirrored_text_alone() {
+        let network = MemoryNetwork::new();
+        let (receiver, forger) = start_forged(&network).await;
+        let mut received = receiver.subscribe_text();
+
+        let mut newer = chunk_text("newer", "sender", 9000, None).remove(0);
+        newer.text_seq = Some(2);
+        let mut mirrored = chunk_text("older mirror", "sender", 9000, None).remove(0);
+        mirrored.text_seq = Some(1);
+        mirrored.mirror = Some(MirrorHeader { version: 1, delta: None });
+        forger.send(&newer).await;
+        forger.send(&mirrored).await;
+
+        assert_eq!(collect_text(&mut received).await, vec!["newer".to_string()]);
+        assert_eq!(receiver.mirror_inbox.version("sender"), None);
+    }
+
+    /// A clock that stands still, so no bucket refills during a test
+    fn frozen_clock() -> Instant {
+        static START: OnceLock<Instant> = OnceLock::new();
+        *START.get_or_init(Instant::now)
+    }
+
+    /// Flood a receiver whose clock stands still with 200 texts from
+    /// "flooder", under `key`; returns how many were delivered and the stats
+    async fn flood(key: Option<&str>) -> (usize, RateLimitStats) {
+        let network = MemoryNetwork::new();
+        let receiver = start_configured(&network, Ipv4Addr::new(10, 0, 0, 2), |service| {
+            service.limiter = Arc::new(RateLimiter::with_clock(frozen_clock));
+            service.auth().set_key(key);
+        }).await;
+        let mut received = receiver.subscribe_text();
+        let forger = Forger::new(&network).await;
+        forger.auth.set_key(key);
+
+        for i in 0..200 {
+            let message = chunk_text(&format!("spam {}", i), "flooder", 9000, None).remove(0);
+            forger.send(&message).await;
+        }
+        (collect_text(&mut received).await.len(), receiver.rate_limiter().stats())
+    }
+
+    #[tokio::test]
+    async fn test_text_flood_is_rate_limited() {
+        // Exactly the burst gets through, and without a key to vouch for the
+        // peer id the rest gets the address blocked, from then on before decoding
+        let (delivered, stats) = flood(None).await;
+        assert_eq!(delivered, 50);
+        assert_eq!(stats.dropped_text, 100);
+        assert_eq!(stats.dropped_datagrams, 50);
+        assert_eq!(stats.blocked_addrs, vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        assert!(stats.blocked_peers.is_empty());
+
+        // With one, only the peer id is blocked
+        let (delivered, stats) = flood(Some("shared secret")).await;
+        assert_eq!(delivered, 50);
+        assert_eq!(stats.dropped_text, 150);
+        assert!(stats.blocked_addrs.is_empty());
+        assert_eq!(stats.blocked_peers, vec!["flooder".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_blocked_peer_is_hidden_and_its_text_dropped() {
+        let network = MemoryNetwork::new();
+        let access = Arc::new(AccessStore::open_in_memory().unwrap());
+        access.add(AccessList::Block, "unknown-yet", Some(Ipv4Addr::new(10, 0, 0, 1).into()), None).unwrap();
+        let receiver = start_with_access(&network, access.clone()).await;
+        let mut received = receiver.subscribe_text();
+
+        let blocked = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let friend = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 3)).await;
+        let peers = wait_for_peers(&blocked, 2).await;
+        wait_for_peers(&friend, 2).await;
+        let to_receiver: Vec<Peer> = peers.into_iter().filter(|peer| peer.ip == IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))).collect();
+        blocked.send_text(&to_receiver, "let me in").await.unwrap();
+
+        let visible = wait_for_peers(&receiver, 1).await;
+        assert_eq!(visible.iter().map(|peer| peer.id.clone()).collect::<Vec<_>>(), vec![friend.peer_id().unwrap()]);
+        assert!(collect_text(&mut received).await.is_empty());
+
+        // With an allow list, only allowed peers' text gets through
+        access.remove(AccessList::Block, "unknown-yet").unwrap();
+        access.add(AccessList::Allow, &friend.peer_id().unwrap(), None, None).unwrap();
+        access.set_policy(&friend.peer_id().unwrap(), None, None, Permissions { auto_accept: true, ..Permissions::default() }).unwrap();
+        blocked.send_text(&to_receiver, "still not").await.unwrap();
+        let receiver_peer: Vec<Peer> = friend.registry().get_peer(&receiver.peer_id().unwrap()).await.into_iter().collect();
+        friend.send_text(&receiver_peer, "hello").await.unwrap();
+        assert_eq!(collect_text(&mut received).await, vec!["hello".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_text_from_untrusted_peer_waits_in_inbox() {
+        let network = MemoryNetwork::new();
+        let access = AccessStore::open_in_memory().unwrap();
+        access.set_policy("trusted", Some(Ipv4Addr::new(10, 0, 0, 3).into()), None, Permissions { auto_accept: true, ..Permissions::default() }).unwrap();
+        let receiver = start_with_access(&network, Arc::new(access)).await;
+        let mut received = receiver.subscribe_text();
+
+        let stranger = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let trusted = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 3)).await;
+        let receiver_peer = |service: &DiscoveryService| {
+            let receiver_id = receiver.peer_id().unwrap();
+            let registry = service.registry();
+            async move { registry.get_peer(&receiver_id).await.into_iter().collect::<Vec<Peer>>() }
+        };
+        wait_for_peers(&stranger, 2).await;
+        wait_for_peers(&trusted, 2).await;
+        stranger.send_text(&receiver_peer(&stranger).await, "first").await.unwrap();
+        stranger.send_text(&receiver_peer(&stranger).await, "second").await.unwrap();
+        trusted.send_text(&receiver_peer(&trusted).await, "trusted").await.unwrap();
+
+        // Only the auto-accepted peer's text is delivered right away
+        assert_eq!(collect_text(&mut received).await, vec!["trusted".to_string()]);
+        let inbox = receiver.inbox();
+        let pending = inbox.pending();
+        assert_eq!(pending.iter().map(|held| held.message.text.as_str()).collect::<Vec<_>>(), ["first", "second"]);
+
+        assert_eq!(inbox.accept(&pending[1].id).unwrap().text, "second");
+        assert_eq!(next_text(&mut received).await.text, "second");
+        inbox.reject(&pending[0].id).unwrap();
+        assert!(inbox.pending().is_empty());
+        assert!(collect_text(&mut received).await.is_empty());
+        assert!(inbox.accept(&pending[0].id).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_peer_cannot_change_shared_or_mirrored_text() {
+        let network = MemoryNetwork::new();
+        let receiver = start_with_access(&network, Arc::new(AccessStore::open_in_memory().unwrap())).await;
+        let receiver_id = receiver.peer_id().unwrap();
+        let stranger = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let peers = wait_for_peers(&stranger, 1).await;
+        wait_for_peers(&receiver, 1).await;
+
+        let (_, results) = stranger.edit_shared_text(0, "graffiti").await.unwrap();
+        assert!(results[0].status.reached_peer());
+        let slots = stranger.mirror_queue().supersede([receiver_id.as_str()]);
+        stranger.mirror_text(&peers, "mirrored", slots).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+
+        // The mirrored text waits in the inbox without becoming our copy
+        assert_eq!(receiver.shared().view().text, "");
+        let pending = receiver.inbox().pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].message.text, "mirrored");
+        assert_eq!(receiver.mirror_inbox.version(&stranger.peer_id().unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn test_stale_and_replayed_messages_are_rejected() {
+        let network = MemoryNetwork::new();
+        let (receiver, forger) = start_forged(&network).await;
+        let mut received = receiver.subscribe_text();
+        let long_ago = Utc::now() - chrono::Duration::minutes(5);
+
+        let original = chunk_text("paste me", "sender", 9000, None).remove(0);
+        forger.send(&original).await;
+        // Replayed with a new message id so it isn't just a duplicate
+        let mut replayed = original.clone();
+        replayed.message_id = Some("replayed".to_string());
+        forger.send(&replayed).await;
+        let mut unstamped = chunk_text("no nonce", "sender", 9000, None).remove(0);
+        unstamped.nonce = None;
+        forger.send(&unstamped).await;
+        // Without a key the timestamp proves nothing, so a skewed clock is fine
+        let mut skewed = chunk_text("skewed clock", "sender", 9000, None).remove(0);
+        skewed.timestamp = long_ago;
+        forger.send(&skewed).await;
+        assert_eq!(collect_text(&mut received).await, vec!["paste me".to_string(), "skewed clock".to_string()]);
+
+        receiver.auth().set_key(Some("shared secret"));
+        forger.auth.set_key(Some("shared secret"));
+        let mut stale = chunk_text("old news", "sender", 9000, None).remove(0);
+        stale.timestamp = long_ago;
+        forger.send(&stale).await;
+        // Chunks of a transfer that started fresh stay welcome however long it takes
+        let mut chunks = chunk_text(&"x".repeat(2000), "sender", 9000, None);
+        for (i, chunk) in chunks.iter_mut().enumerate() {
+            if i > 0 {
+                chunk.timestamp = long_ago;
+            }
+            forger.send(chunk).await;
+        }
+        assert_eq!(collect_text(&mut received).await, vec!["x".repeat(2000)]);
+    }
+
+    #[test]
+    fn test_replayed_or_stale_feedback_is_ignored() {
+        let auth = Arc::new(MessageAuth::new());
+        let outgoing = OutgoingText::new(
+            "sender".to_string(), 9000, chunk_text(&"x".repeat(2000), "sender", 9000, None), 2000,
+            ProgressReporter::new(None), auth.clone(), Arc::new(ReplayGuard::new())).unwrap();
+        let mut nack = DiscoveryMessage::new(MessageType::ChunkNack, "receiver".to_string(), 9001, None);
+        nack.message_id = outgoing.message_id.clone();
+        nack.missing = Some(vec![0]);
+
+        assert!(outgoing.accept_feedback(&nack));
+        // A captured NACK sent again must not trigger another resend
+        assert!(!outgoing.accept_feedback(&nack));
+        let mut skewed = nack.clone();
+        skewed.nonce = Some(Uuid::new_v4().to_string());
+        skewed.timestamp = Utc::now() - chrono::Duration::minutes(5);
+        assert!(outgoing.accept_feedback(&skewed));
+
+        // With a key, stale feedback is turned away like any other message
+        auth.set_key(Some("shared secret"));
+        let mut stale = skewed.clone();
+        stale.nonce = Some(Uuid::new_v4().to_string());
+        assert!(!outgoing.accept_feedback(&stale));
+        let mut fresh = nack.clone();
+        fresh.nonce = Some(Uuid::new_v4().to_string());
+        assert!(outgoing.accept_feedback(&fresh));
+    }
+
+    #[tokio::test]
+    async fn test_network_key_shuts_out_peers_without_it() {
+        let network = MemoryNetwork::new();
+        let a = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let b = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let outsider = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 3)).await;
+        let mut received = b.subscribe_text();
+        let peers = wait_for_peers(&outsider, 2).await;
+        wait_for_peers(&a, 2).await;
+        a.auth().set_key(Some("shared secret"));
+        b.auth().set_key(Some("shared secret"));
+        let to_b: Vec<Peer> = peers.into_iter().filter(|peer| peer.ip == IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))).collect();
+
+        outsider.send_text(&to_b, "let me in").await.unwrap();
+        let b_peer: Vec<Peer> = a.registry().get_peer(&b.peer_id().unwrap()).await.into_iter().collect();
+        a.send_text(&b_peer, "hello").await.unwrap();
+        a.send_text(&b_peer, &"x".repeat(2000)).await.unwrap();
+
+        assert_eq!(collect_text(&mut received).await, vec!["hello".to_string(), "x".repeat(2000)]);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_text_messages_are_dropped_per_sender() {
+        let network = MemoryNetwork::new();
+        let (receiver, forger) = start_forged(&network).await;
+        let mut received = receiver.subscribe_text();
+
+        let mut message = chunk_text("hello", "first", 9000, None).remove(0);
+        let first = serde_json::to_vec(&message).unwrap();
+        // Resent under a new nonce, only the message id gives it away
+        message.nonce = Some(Uuid::new_v4().to_string());
+        let resent = serde_json::to_vec(&message).unwrap();
+        // The same id from another sender is a different message
+        message.peer_id = "second".to_string();
+        let second = serde_json::to_vec(&message).unwrap();
+        for bytes in [&first, &first, &resent, &second, &second] {
+            forger.send_bytes(bytes).await;
+        }
+
+        let texts = collect_text(&mut received).await;
+        assert_eq!(texts, vec!["hello".to_string(), "hello".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_text_turned_away_for_its_room_is_delivered_once_joined() {
+        let network = MemoryNetwork::new();
+        let (receiver, forger) = start_forged(&network).await;
+        let mut received = receiver.subscribe_text();
+
+        let mut message = chunk_text("for the team", "sender", 9000, None).remove(0);
+        message.room = Some("design".to_string());
+        forger.send(&message).await;
+        assert!(collect_text(&mut received).await.is_empty());
+
+        receiver.rooms().join("design").unwrap();
+        message.nonce = Some(Uuid::new_v4().to_string());
+        forger.send(&message).await;
+        assert_eq!(collect_text(&mut received).await, vec!["for the team".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_newer_mirror_update_supersedes_older_one() {
+        // Some latency so the first update is still being sent when superseded
+        let network = MemoryNetwork::with_link(LinkConditions {
+            min_delay: Duration::from_millis(5),
+            max_delay: Duration::from_millis(5),
+            ..LinkConditions::default()
+        }, 1);
+        let sender = Arc::new(start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await);
+        let receiver = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let mut progress = sender.subscribe_progress();
+        let mut received = receiver.subscribe_text();
+        let peers = wait_for_peers(&sender, 1).await;
+        let peer_ids = || peers.iter().map(|peer| peer.id.as_str());
+
+        let oldest = sender.mirror_queue().supersede(peer_ids());
+        let sending = {
+            let (sender, peers) = (sender.clone(), peers.clone());
+            tokio::spawn(async move { sender.mirror_text(&peers, &"x".repeat(200 * 1024), oldest).await })
+        };
+        tokio::time::timeout(Duration::from_secs(1), progress.recv()).await.unwrap().unwrap();
+
+        // Two more updates queue up; only the newest is sent
+        let queued = sender.mirror_queue().supersede(peer_ids());
+        let newest = sender.mirror_queue().supersede(peer_ids());
+        assert_eq!(sending.await.unwrap().unwrap()[0].status, SendStatus::Cancelled);
+        let skipped = sender.mirror_text(&peers, "stale", queued).await.unwrap();
+        assert_eq!(skipped[0].status, SendStatus::Cancelled);
+        let results = sender.mirror_text(&peers, "newest", newest).await.unwrap();
+        assert_eq!(results[0].status, SendStatus::Sent);
+
+        assert_eq!(collect_text(&mut received).await, vec!["newest".to_string()]);
+    }
+
+    /// Wait until the shared text of `service` reads `expected`
+    async fn wait_for_shared_text(service: &DiscoveryService, expected: &str) {
+        let mut changes = service.shared().subscribe();
+        tokio::time::timeout(Duration::from_secs(5), changes.wait_for(|view| view.text == expected))
+            .await
+            .unwrap_or_else(|_| panic!("shared text is {:?}, expected {:?}", service.shared().view().text, expected))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shared_text_converges_and_late_peers_catch_up() {
+        let network = MemoryNetwork::new();
+        let a = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let b = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        wait_for_peers(&a, 1).await;
+        wait_for_peers(&b, 1).await;
+
+        let (_, results) = a.edit_shared_text(a.shared().view().version, "hello world").await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].status.reached_peer());
+        wait_for_shared_text(&b, "hello world").await;
+
+        // Both edit the same text at once
+        let (from_a, from_b) = (a.shared().view(), b.shared().view());
+        let (edit_a, edit_b) = tokio::join!(
+            a.edit_shared_text(from_a.version, "hello brave world"),
+            b.edit_shared_text(from_b.version, "hello world!"),
+        );
+        edit_a.unwrap();
+        edit_b.unwrap();
+        wait_for_shared_text(&a, "hello brave world!").await;
+        wait_for_shared_text(&b, "hello brave world!").await;
+
+        // A peer that joins later gets the operations it missed from discovery
+        let c = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 3)).await;
+        wait_for_shared_text(&c, "hello brave world!").await;
+        assert_eq!(c.shared().version_vector(), a.shared().version_vector());
+    }
+
+    #[tokio::test]
+    async fn test_shared_text_needs_trust_on_both_sides() {
+        let network = MemoryNetwork::new();
+        let (a_access, b_access) = (Arc::new(AccessStore::open_in_memory().unwrap()), Arc::new(AccessStore::open_in_memory().unwrap()));
+        let a = start_configured(&network, Ipv4Addr::new(10, 0, 0, 1), |service| service.access = Some(a_access.clone())).await;
+        let b = start_configured(&network, Ipv4Addr::new(10, 0, 0, 2), |service| service.access = Some(b_access.clone())).await;
+        wait_for_peers(&a, 1).await;
+        wait_for_peers(&b, 1).await;
+
+        // On default settings the edit stays local
+        let (_, results) = a.edit_shared_text(a.shared().view().version, "from a").await.unwrap();
+        assert!(results.is_empty());
+        sleep(Duration::from_millis(2500)).await;
+        assert!(a.shared_text_peers().await.is_empty());
+        assert_eq!(b.shared().view().text, "");
+
+        // Once each auto-accepts the other, the earlier edit catches up
+        let trusted = Permissions { auto_accept: true, ..Permissions::default() };
+        a_access.set_policy(&b.peer_id().unwrap(), None, None, trusted).unwrap();
+        b_access.set_policy(&a.peer_id().unwrap(), None, None, trusted).unwrap();
+        wait_for_shared_text(&b, "from a").await;
+        assert_eq!(a.shared_text_peers().await.len(), 1);
+    }
+
+    /// Shared-text operations among what `forger` was sent
+    async fn shared_ops_sent(forger: &Forger) -> usize {
+        forger.feedback().await.iter().filter(|message| message.shared_ops).count()
+    }
+
+    #[tokio::test]
+    async fn test_shared_text_is_not_pushed_to_untrusted_peers() {
+        let network = MemoryNetwork::new();
+        let receiver = start_with_access(&network, Arc::new(AccessStore::open_in_memory().unwrap())).await;
+        receiver.edit_shared_text(receiver.shared().view().version, "private").await.unwrap();
+        let forger = Forger::new(&network).await;
+
+        // A peer on default settings that stays behind round after round
+        for _ in 0..3 {
+            forger.announce(&VersionVector::new()).await;
+            assert_eq!(shared_ops_sent(&forger).await, 0);
+        }
+        assert!(receiver.shared().collaborators().is_empty());
+        let (_, results) = receiver.edit_shared_text(receiver.shared().view().version, "private notes").await.unwrap();
+        assert!(results.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_catch_up_is_not_repeated_while_the_peer_stays_behind() {
+        let network = MemoryNetwork::new();
+        let receiver = start_with_policy(&network, Permissions::default()).await;
+        let receiver_id = receiver.peer_id().unwrap();
+        receiver.edit_shared_text(receiver.shared().view().version, "hello").await.unwrap();
+        receiver.edit_shared_text(receiver.shared().view().version, "hello world").await.unwrap();
+        let forger = Forger::new(&network).await;
+
+        forger.announce(&VersionVector::new()).await;
+        assert_eq!(shared_ops_sent(&forger).await, 1);
+        // It didn't apply them, so announcing the same vector gets nothing more
+        for _ in 0..3 {
+            forger.announce(&VersionVector::new()).await;
+        }
+        assert_eq!(shared_ops_sent(&forger).await, 0);
+
+        // Once it moves on, it is sent what it still lacks
+        forger.announce(&VersionVector::from([(receiver_id, 1)])).await;
+        assert_eq!(shared_ops_sent(&forger).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_room_text_reaches_members_only() {
+        let network = MemoryNetwork::new();
+        let sender = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let member = start_configured(&network, Ipv4Addr::new(10, 0, 0, 2), |service| {
+            service.rooms().join("design").unwrap();
+        }).await;
+        let outsider = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 3)).await;
+        let mut member_rx = member.subscribe_text();
+        let mut outsider_rx = outsider.subscribe_text();
+
+        let peers = wait_for_peers(&sender, 2).await;
+        assert_eq!(sender.registry().known_rooms().await.into_iter().collect::<Vec<_>>(), vec!["design"]);
+        let in_room = sender.registry().get_peers_in_room("design").await;
+        assert_eq!(in_room.iter().map(|p| p.id.clone()).collect::<Vec<_>>(), vec![member.peer_id().unwrap()]);
+
+        let text = "room only ".repeat(300);
+        let results = sender.send_text_to_room(" design ", &text, SendMode::Unicast).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].status, SendStatus::Delivered);
+        let received = tokio::time::timeout(Duration::from_secs(1), member_rx.recv()).await.unwrap().unwrap();
+        assert_eq!(received.text, text);
+        assert_eq!(received.room.as_deref(), Some("design"));
+
+        // Text scoped to a room the outsider never joined is dropped on arrival
+        let outsider_peer = peers.into_iter().find(|p| Some(&p.id) == outsider.peer_id().as_ref()).unwrap();
+        sender.send_scoped_text(&[outsider_peer], "not for you", SendMode::Unicast, Some("design".to_string())).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(200), outsider_rx.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shared_and_mirrored_text_stay_in_the_current_room() {
+        let network = MemoryNetwork::new();
+        let mut services = Vec::new();
+        for (host, room) in [(1, "design"), (2, "design"), (3, "ops")] {
+            services.push(start_configured(&network, Ipv4Addr::new(10, 0, 0, host), |service| {
+                service.rooms().join(room).unwrap();
+            }).await);
+        }
+        let (a, b, other) = (&services[0], &services[1], &services[2]);
+        let mut other_rx = other.subscribe_text();
+        let peers = wait_for_peers(a, 2).await;
+        wait_for_peers(other, 2).await;
+
+        // Only the peer in the same room collaborates on the shared text
+        let (_, results) = a.edit_shared_text(0, "design notes").await.unwrap();
+        assert_eq!(results.iter().map(|r| r.peer_id.clone()).collect::<Vec<_>>(), vec![b.peer_id().unwrap()]);
+        wait_for_shared_text(b, "design notes").await;
+        assert!(!other.shared().collaborators().contains(&a.peer_id().unwrap()));
+
+        // Mirror updates carry the room, so they are dropped outside it
+        let ids: Vec<String> = peers.iter().map(|peer| peer.id.clone()).collect();
+        let slots = a.mirror_queue().supersede(ids.iter().map(String::as_str));
+        a.mirror_text(&peers, "mirrored", slots).await.unwrap();
+        assert!(collect_text(&mut other_rx).await.is_empty());
+        assert_eq!(other.shared().view().text, "");
+        assert_eq!(a.registry().get_peers_for(a.rooms().current().as_deref()).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_transfer_reports_progress_on_both_ends() {
+        let network = MemoryNetwork::new();
+        let sender = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let receiver = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let mut sent_progress = sender.subscribe_progress();
+        let mut received_progress = receiver.subscribe_progress();
+        let mut received = receiver.subscribe_text();
+
+        let peers = wait_for_peers(&sender, 1).await;
+        let text = "progress ".repeat(1000);
+        sender.send_text(&peers, &text).await.unwrap();
+        let delivered = tokio::time::timeout(Duration::from_secs(1), received.recv()).await.unwrap().unwrap();
+        let message_id = delivered.message_id.unwrap();
+        let total_chunks = text.len().div_ceil(CHUNK_PAYLOAD_BYTES) as u32;
+
+        for (rx, direction, peer_id) in [
+            (&mut sent_progress, Direction::Sent, receiver.peer_id().unwrap()),
+            (&mut received_progress, Direction::Received, sender.peer_id().unwrap()),
+        ] {
+            let mut updates = Vec::new();
+            while let Ok(update) = rx.try_recv() {
+                updates.push(update);
+            }
+            assert!(!updates.is_empty());
+            assert!(updates.windows(2).all(|w| w[0].chunks_done < w[1].chunks_done));
+            let last = updates.last().unwrap();
+            assert_eq!(last.message_id, message_id);
+            assert_eq!(last.direction, direction);
+            assert_eq!(last.peer_id, peer_id);
+            assert_eq!(last.total_chunks, total_chunks);
+            assert!(last.is_complete());
+            assert_eq!(last.bytes_done, text.len() as u64);
+            assert_eq!(last.eta_secs, Some(0.0));
+        }
+
+        // Single-packet messages have no progress to report
+        sender.send_text(&peers, "tiny").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), received.recv()).await.unwrap().unwrap();
+        assert!(sent_progress.try_recv().is_err());
+        assert!(received_progress.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_fans_out_concurrently_with_per_peer_results() {
+        let network = MemoryNetwork::new();
+        let sender = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let receiver = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let mut received = receiver.subscribe_text();
+        let mut peers = wait_for_peers(&sender, 1).await;
+        // Two recipients that never answer; each one stalls for STALL_TIMEOUT
+        peers.push(Peer::new("gone-1".to_string(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 8)), DISCOVERY_PORT, None));
+        peers.push(Peer::new("gone-2".to_string(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 9)), DISCOVERY_PORT, None));
+
+        let text = "fan out ".repeat(500);
+        let started = Instant::now();
+        let results = sender.send_text(&peers, &text).await.unwrap();
+
+        // The stalls overlap instead of adding up
+        assert!(started.elapsed() < STALL_TIMEOUT * 2);
+        assert_eq!(results.iter().map(|r| r.peer_id.as_str()).collect::<Vec<_>>(),
+                   peers.iter().map(|p| p.id.as_str()).collect::<Vec<_>>());
+        assert_eq!(results[0].status, SendStatus::Delivered);
+        assert_eq!(results[0].chunks_confirmed, results[0].total_chunks);
+        for result in &results[1..] {
+            assert_eq!(result.status, SendStatus::Failed);
+            assert_eq!(result.chunks_confirmed, 0);
+            assert!(result.error.is_some());
+        }
+        assert_eq!(tokio::time::timeout(Duration::from_secs(1), received.recv()).await.unwrap().unwrap().text, text);
+
+        let results = sender.send_text(&peers[..1], "small").await.unwrap();
+        assert_eq!(results[0].status, SendStatus::Sent);
+    }
+
+    #[tokio::test]
+    async fn test_send_to_peer_ids_reports_unknown_and_skips_duplicates() {
+        let network = MemoryNetwork::new();
+        let sender = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let a = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let b = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 3)).await;
+        let mut rx_a = a.subscribe_text();
+        let mut rx_b = b.subscribe_text();
+        wait_for_peers(&sender, 2).await;
+
+        let a_id = a.peer_id().unwrap();
+        let ids = vec!["missing".to_string(), a_id.clone(), a_id.clone()];
+        let text = "selected ".repeat(200);
+        let results = sender.send_text_to_peer_ids(&ids, &text, SendMode::Unicast).await.unwrap();
+
+        assert_eq!(results.iter().map(|r| r.peer_id.as_str()).collect::<Vec<_>>(), vec!["missing", a_id.as_str()]);
+        assert_eq!(results[0].status, SendStatus::Failed);
+        assert_eq!(results[1].status, SendStatus::Delivered);
+        assert_eq!(collect_text(&mut rx_a).await, vec![text]);
+        assert!(collect_text(&mut rx_b).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_blocked_peer_is_sent_nothing_however_it_is_picked() {
+        let network = MemoryNetwork::new();
+        let access = Arc::new(AccessStore::open_in_memory().unwrap());
+        let sender = start_configured(&network, Ipv4Addr::new(10, 0, 0, 1), |service| service.access = Some(access.clone())).await;
+        let blocked = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let friend = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 3)).await;
+        let mut blocked_rx = blocked.subscribe_text();
+        let mut friend_rx = friend.subscribe_text();
+        let peers = wait_for_peers(&sender, 2).await;
+        // Still in the registry, as it was known before it was blocked
+        let (blocked_id, friend_id) = (blocked.peer_id().unwrap(), friend.peer_id().unwrap());
+        access.add(AccessList::Block, &blocked_id, None, None).unwrap();
+
+        let results = sender.send_text_to_peer_ids(&[blocked_id.clone(), friend_id.clone()], "by id", SendMode::Unicast).await.unwrap();
+        assert_eq!(results.iter().map(|r| r.status).collect::<Vec<_>>(), vec![SendStatus::Blocked, SendStatus::Sent]);
+        let results = sender.send_text(&peers, &"to all ".repeat(300)).await.unwrap();
+        let blocked_result = results.iter().find(|r| r.peer_id == blocked_id).unwrap();
+        assert_eq!(blocked_result.status, SendStatus::Blocked);
+        assert_eq!(results.iter().find(|r| r.peer_id == friend_id).unwrap().status, SendStatus::Delivered);
+
+        assert_eq!(collect_text(&mut friend_rx).await.len(), 2);
+        assert!(collect_text(&mut blocked_rx).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_multicast_to_some_peers_skips_the_rest_of_the_group() {
+        let network = MemoryNetwork::new();
+        let sender = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let a = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let b = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 3)).await;
+        let mut rx_a = a.subscribe_text();
+        let mut rx_b = b.subscribe_text();
+        wait_for_peers(&sender, 2).await;
+
+        let ids = vec![a.peer_id().unwrap()];
+        for text in ["just for a".to_string(), "chunked for a ".repeat(200)] {
+            let results = sender.send_text_to_peer_ids(&ids, &text, SendMode::Multicast).await.unwrap();
+            assert_ne!(results[0].status, SendStatus::Failed);
+            assert_eq!(collect_text(&mut rx_a).await, vec![text]);
+        }
+        assert!(collect_text(&mut rx_b).await.is_empty());
+        assert!(b.reassembly_states.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_transfer_is_dropped_by_receiver() {
+        // Some latency so the transfer is still running when it is cancelled
+        let network = MemoryNetwork::with_link(LinkConditions {
+            min_delay: Duration::from_millis(5),
+            max_delay: Duration::from_millis(5),
+            ..LinkConditions::default()
+        }, 1);
+        let sender = Arc::new(start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await);
+        let receiver = start_memory_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let mut progress = sender.subscribe_progress();
+        let mut sender_cancelled = sender.subscribe_cancelled();
+        let mut receiver_cancelled = receiver.subscribe_cancelled();
+        let mut received = receiver.subscribe_text();
+
+        let peers = wait_for_peers(&sender, 1).await;
+        let sending = {
+            let sender = sender.clone();
+            tokio::spawn(async move { sender.send_text(&peers, &"x".repeat(200 * 1024)).await })
+        };
+
+        let message_id = tokio::time::timeout(Duration::from_secs(1), progress.recv()).await.unwrap().unwrap().message_id;
+        assert!(sender.transfers().cancel(&message_id));
+        let results = sending.await.unwrap().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].status, SendStatus::Cancelled);
+        assert!(!sender.transfers().cancel(&message_id));
+
+        let on_sender = tokio::time::timeout(Duration::from_secs(1), sender_cancelled.recv()).await.unwrap().unwrap();
+        let on_receiver = tokio::time::timeout(Duration::from_secs(1), receiver_cancelled.recv()).await.unwrap().unwrap();
+        assert_eq!(on_sender.message_id, message_id);
+        assert_eq!(on_sender.direction, Direction::Sent);
+        assert!(on_sender.chunks_done < on_sender.total_chunks);
+        assert_eq!(on_receiver.message_id, message_id);
+        assert_eq!(on_receiver.direction, Direction::Received);
+        assert_eq!(on_receiver.peer_id, sender.peer_id().unwrap());
+
+        assert!(receiver.reassembly_states.read().await.is_empty());
+        assert!(tokio::time::timeout(Duration::from_millis(100), received.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_listener_falls_back_when_port_taken() {
+        let network = MemoryNetwork::new();
+        let host = network.host(Ipv4Addr::new(10, 0, 0, 1));
+        let _occupied = host.bind(DISCOVERY_PORT).await.unwrap();
+
+        let mut service = DiscoveryService::with_transport(Duration::from_secs(30), host);
+        service.start(DISCOVERY_PORT).await.unwrap();
+
+        let port = service.listen_port().unwrap();
+        assert_ne!(port, DISCOVERY_PORT);
+        assert_ne!(port, 0);
+    }
+
+    #[tokio::test]
+    async fn test_two_instances_on_one_host() {
+        let network = MemoryNetwork::new();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        let first = start_memory_service(&network, ip).await;
+        let second = start_memory_service(&network, ip).await;
+        assert_eq!(first.listen_port(), Some(DISCOVERY_PORT));
+        let second_port = second.listen_port().unwrap();
+        assert_ne!(second_port, DISCOVERY_PORT);
+
+        // The first instance learns the fallback port from the broadcast,
+        // the second learns about the first from a direct announcement
+        let peers_of_first = wait_for_peers(&first, 1).await;
+        let peers_of_second = wait_for_peers(&second, 1).await;
+        assert_eq!(peers_of_first[0].socket_addr(), SocketAddr::new(IpAddr::V4(ip), second_port));
+        assert_eq!(peers_of_second[0].socket_addr(), SocketAddr::new(IpAddr::V4(ip), DISCOVERY_PORT));
+
+        let mut received_by_second = second.subscribe_text();
+        let mut received_by_first = first.subscribe_text();
+        first.send_text(&peers_of_first, "to the fallback port").await.unwrap();
+        second.send_text(&peers_of_second, "to the default port").await.unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(1), received_by_second.recv()).await.unwrap().unwrap();
+        assert_eq!(received.text, "to the fallback port");
+        let received = tokio::time::timeout(Duration::from_secs(1), received_by_first.recv()).await.unwrap().unwrap();
+        assert_eq!(received.text, "to the default port");
+        assert_eq!(received.source_addr.ip(), IpAddr::V4(ip));
+    }
+
+    #[tokio::test]
+    async fn test_sent_and_received_text_is_recorded_in_history() {
+        let network = MemoryNetwork::new();
+        let with_history = |service: &mut DiscoveryService| service.history = Some(Arc::new(HistoryStore::open_in_memory().unwrap()));
+        let sender = start_configured(&network, Ipv4Addr::new(10, 0, 0, 1), with_history).await;
+        let receiver = start_configured(&network, Ipv4Addr::new(10, 0, 0, 2), with_history).await;
+        let mut received = receiver.subscribe_text();
+
+        let peers = wait_for_peers(&sender, 1).await;
+        sender.send_text(&peers, "remember me").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), received.recv()).await.unwrap().unwrap();
+
+        let sent = sender.history.as_ref().unwrap().list(None, None).unwrap();
+        assert_eq!(sent.entries.len(), 1);
+        assert_eq!(sent.entries[0].direction, Direction::Sent);
+        assert_eq!(sent.entries[0].recipients, vec![receiver.peer_id().unwrap()]);
+
+        let got = receiver.history.as_ref().unwrap().list(None, None).unwrap();
+        assert_eq!(got.entries.len(), 1);
+        assert_eq!(got.entries[0].direction, Direction::Received);
+        assert_eq!(got.entries[0].sender_id, sender.peer_id().unwrap());
+        assert_eq!(got.entries[0].content, "remember me");
+    }
+}
@@ -0,0 +1,165 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+/// A saved, named set of recipients
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RecipientGroup {
+    pub name: String,
+    pub peer_ids: Vec<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// SQLite-backed recipient groups, keyed by name
+pub struct GroupStore {
+    conn: Mutex<Connection>,
+}
+
+impl GroupStore {
+    /// Open (or create) the groups database at `path`
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create groups directory {}", dir.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open groups database {}", path.display()))?;
+        Self::init(conn)
+    }
+
+    /// Open a throwaway store that lives only as long as the process
+    pub fn open_in_memory() -> Result<Self> {
+        let conn = Connection::open_in_memory()
+            .context("Failed to open in-memory groups database")?;
+        Self::init(conn)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS recipient_groups (
+                name TEXT PRIMARY KEY,
+                peer_ids TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            );",
+        ).context("Failed to initialize groups schema")?;
+
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    /// Create or replace the group `name`
+    ///
+    /// The name is trimmed and duplicate peer ids are dropped, keeping the
+    /// first occurrence so the send order stays as given.
+    pub fn save(&self, name: &str, peer_ids: &[String]) -> Result<RecipientGroup> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(anyhow::anyhow!("Group name must not be empty"));
+        }
+        let mut unique: Vec<String> = Vec::with_capacity(peer_ids.len());
+        for id in peer_ids {
+            if !unique.contains(id) {
+                unique.push(id.clone());
+            }
+        }
+        if unique.is_empty() {
+            return Err(anyhow::anyhow!("Group {} has no recipients", name));
+        }
+
+        let group = RecipientGroup {
+            name: name.to_string(),
+            peer_ids: unique,
+            updated_at: Utc::now(),
+        };
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO recipient_groups (name, peer_ids, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(name) DO UPDATE SET peer_ids = excluded.peer_ids, updated_at = excluded.updated_at",
+            params![
+                group.name,
+                serde_json::to_string(&group.peer_ids)?,
+                group.updated_at.to_rfc3339_opts(SecondsFormat::Micros, true),
+            ],
+        )?;
+        Ok(group)
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<RecipientGroup>> {
+        let conn = self.conn.lock().unwrap();
+        let group = conn.query_row(
+            "SELECT name, peer_ids, updated_at FROM recipient_groups WHERE name = ?1",
+            params![name.trim()],
+            group_from_row,
+        ).optional()?;
+        Ok(group)
+    }
+
+    /// Every group, ordered by name
+    pub fn list(&self) -> Result<Vec<RecipientGroup>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT name, peer_ids, updated_at FROM recipient_groups ORDER BY name")?;
+        let groups = stmt.query_map([], group_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(groups)
+    }
+
+    /// Delete a group; returns whether it existed
+    pub fn delete(&self, name: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.execute("DELETE FROM recipient_groups WHERE name = ?1", params![name.trim()])? > 0)
+    }
+}
+
+fn group_from_row(row: &Row<'_>) -> rusqlite::Result<RecipientGroup> {
+    let peer_ids: String = row.get(1)?;
+    let updated_at: String = row.get(2)?;
+    let to_sql_error = |e: anyhow::Error| rusqlite::Error::FromSqlConversionFailure(
+        0, rusqlite::types::Type::Text, e.into(),
+    );
+
+    Ok(RecipientGroup {
+        name: row.get(0)?,
+        peer_ids: serde_json::from_str(&peer_ids).map_err(|e| to_sql_error(e.into()))?,
+        updated_at: DateTime::parse_from_rfc3339(&updated_at)
+            .map_err(|e| to_sql_error(e.into()))?
+            .with_timezone(&Utc),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_save_replaces_and_dedupes() {
+        let store = GroupStore::open_in_memory().unwrap();
+        let saved = store.save(" team ", &ids(&["b", "a", "b"])).unwrap();
+        assert_eq!(saved.name, "team");
+        assert_eq!(saved.peer_ids, ids(&["b", "a"]));
+
+        store.save("team", &ids(&["c"])).unwrap();
+        store.save("alpha", &ids(&["a"])).unwrap();
+        let groups = store.list().unwrap();
+        assert_eq!(groups.iter().map(|g| g.name.as_str()).collect::<Vec<_>>(), vec!["alpha", "team"]);
+        assert_eq!(store.get("team").unwrap().unwrap().peer_ids, ids(&["c"]));
+
+        assert!(store.save("  ", &ids(&["a"])).is_err());
+        assert!(store.save("empty", &[]).is_err());
+    }
+
+    #[test]
+    fn test_delete_group() {
+        let store = GroupStore::open_in_memory().unwrap();
+        store.save("team", &ids(&["a"])).unwrap();
+        assert!(store.delete("team").unwrap());
+        assert!(!store.delete("team").unwrap());
+        assert!(store.get("team").unwrap().is_none());
+    }
+}
@@ -0,0 +1,649 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+// Configuration constants for history
+const DEFAULT_PAGE_SIZE: u32 = 50;           // Entries per page when the caller doesn't say
+const MAX_PAGE_SIZE: u32 = 500;              // Upper bound for a single page
+const COALESCE_WINDOW_SECS: i64 = 5;         // Live typing within this window updates one entry
+
+/// Whether we sent or received a message
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self> {
+        match value {
+            "sent" => Ok(Direction::Sent),
+            "received" => Ok(Direction::Received),
+            other => Err(anyhow::anyhow!("Unknown history direction: {}", other)),
+        }
+    }
+}
+
+/// A persisted sent or received message
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub message_id: String,
+    pub direction: Direction,
+    pub sender_id: String,
+    pub sender_hostname: Option<String>,
+    pub recipients: Vec<String>,
+    pub timestamp: DateTime<Utc>,
+    pub content: String,
+}
+
+impl HistoryEntry {
+    /// A new, not yet stored entry stamped with the current time
+    pub fn new(
+        message_id: String,
+        direction: Direction,
+        sender_id: String,
+        sender_hostname: Option<String>,
+        recipients: Vec<String>,
+        content: String,
+    ) -> Self {
+        Self {
+            id: 0,
+            message_id,
+            direction,
+            sender_id,
+            sender_hostname,
+            recipients,
+            timestamp: Utc::now(),
+            content,
+        }
+    }
+}
+
+/// One page of history, newest first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    pub total: u64,
+    pub offset: u32,
+    pub limit: u32,
+}
+
+/// File formats for exporting history
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One JSON object per line; can be imported again
+    Jsonl,
+    /// Human-readable notes; export only
+    Markdown,
+}
+
+/// Which entries to export: a conversation with one peer and/or a time range
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HistoryFilter {
+    pub peer_id: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// Portable form of an entry; the local row id is not carried across machines
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct ExportRecord {
+    message_id: String,
+    direction: Direction,
+    sender_id: String,
+    sender_hostname: Option<String>,
+    recipients: Vec<String>,
+    timestamp: DateTime<Utc>,
+    content: String,
+}
+
+impl From<HistoryEntry> for ExportRecord {
+    fn from(entry: HistoryEntry) -> Self {
+        Self {
+            message_id: entry.message_id,
+            direction: entry.direction,
+            sender_id: entry.sender_id,
+            sender_hostname: entry.sender_hostname,
+            recipients: entry.recipients,
+            timestamp: entry.timestamp,
+            content: entry.content,
+        }
+    }
+}
+
+/// Outcome of importing an export
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub imported: usize,
+    /// Entries already present (same message, direction and sender)
+    pub skipped: usize,
+}
+
+/// SQLite-backed message history with full-text search
+pub struct HistoryStore {
+    conn: Mutex<Connection>,
+}
+
+impl HistoryStore {
+    /// Open (or create) the history database at `path`
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create history directory {}", dir.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open history database {}", path.display()))?;
+        Self::init(conn)
+    }
+
+    /// Open a throwaway history that lives only as long as the process
+    pub fn open_in_memory() -> Result<Self> {
+        let conn = Connection::open_in_memory()
+            .context("Failed to open in-memory history database")?;
+        Self::init(conn)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message_id TEXT NOT NULL,
+                direction TEXT NOT NULL,
+                sender_id TEXT NOT NULL,
+                sender_hostname TEXT,
+                recipients TEXT NOT NULL,
+                timestamp TEXT NOT NULL,
+                content TEXT NOT NULL,
+                UNIQUE(message_id, direction, sender_id)
+            );
+            CREATE INDEX IF NOT EXISTS idx_messages_timestamp ON messages(timestamp);
+            CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts
+                USING fts5(content, content='messages', content_rowid='id');
+            CREATE TRIGGER IF NOT EXISTS messages_ai AFTER INSERT ON messages BEGIN
+                INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
+            END;
+            CREATE TRIGGER IF NOT EXISTS messages_ad AFTER DELETE ON messages BEGIN
+                INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+            END;
+            CREATE TRIGGER IF NOT EXISTS messages_au AFTER UPDATE ON messages BEGIN
+                INSERT INTO messages_fts(messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+                INSERT INTO messages_fts(rowid, content) VALUES (new.id, new.content);
+            END;",
+        ).context("Failed to initialize history schema")?;
+
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    /// Persist a message, returning its row id
+    ///
+    /// Live mirroring sends the whole text on every keystroke, so a message that
+    /// follows the newest entry from the same sender to the same recipients
+    /// within a few seconds replaces that entry instead of adding a new one.
+    pub fn record(&self, entry: &HistoryEntry) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        let recipients = serde_json::to_string(&entry.recipients)?;
+
+        let latest = conn.query_row(
+            "SELECT id, direction, sender_id, recipients, timestamp FROM messages ORDER BY id DESC LIMIT 1",
+            [],
+            |row| Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+            )),
+        ).optional()?;
+
+        if let Some((id, direction, sender_id, latest_recipients, timestamp)) = latest {
+            let latest_time = DateTime::parse_from_rfc3339(&timestamp)?.with_timezone(&Utc);
+            let same_stream = direction == entry.direction.as_str()
+                && sender_id == entry.sender_id
+                && latest_recipients == recipients;
+            let elapsed = entry.timestamp.signed_duration_since(latest_time).num_seconds();
+            if same_stream && (0..=COALESCE_WINDOW_SECS).contains(&elapsed) {
+                conn.execute(
+                    "UPDATE messages SET message_id = ?1, timestamp = ?2, content = ?3 WHERE id = ?4",
+                    params![entry.message_id, format_timestamp(&entry.timestamp), entry.content, id],
+                )?;
+                return Ok(id);
+            }
+        }
+
+        conn.execute(
+            "INSERT INTO messages (message_id, direction, sender_id, sender_hostname, recipients, timestamp, content)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                entry.message_id,
+                entry.direction.as_str(),
+                entry.sender_id,
+                entry.sender_hostname,
+                recipients,
+                format_timestamp(&entry.timestamp),
+                entry.content,
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// List entries newest first
+    pub fn list(&self, limit: Option<u32>, offset: Option<u32>) -> Result<HistoryPage> {
+        let (limit, offset) = page_bounds(limit, offset);
+        let conn = self.conn.lock().unwrap();
+
+        let total: u64 = conn.query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))?;
+        let mut stmt = conn.prepare(
+            "SELECT id, message_id, direction, sender_id, sender_hostname, recipients, timestamp, content
+             FROM messages ORDER BY timestamp DESC, id DESC LIMIT ?1 OFFSET ?2",
+        )?;
+        let entries = stmt.query_map(params![limit, offset], entry_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(HistoryPage { entries, total, offset, limit })
+    }
+
+    /// Full-text search over message content, newest first
+    ///
+    /// Every whitespace-separated word must appear; FTS operators in the query
+    /// are treated as plain text.
+    pub fn search(&self, query: &str, limit: Option<u32>, offset: Option<u32>) -> Result<HistoryPage> {
+        let (limit, offset) = page_bounds(limit, offset);
+        let match_expr = fts_match_expression(query);
+        if match_expr.is_empty() {
+            return Ok(HistoryPage { entries: Vec::new(), total: 0, offset, limit });
+        }
+
+        let conn = self.conn.lock().unwrap();
+        let total: u64 = conn.query_row(
+            "SELECT COUNT(*) FROM messages_fts WHERE messages_fts MATCH ?1",
+            params![match_expr],
+            |row| row.get(0),
+        )?;
+        let mut stmt = conn.prepare(
+            "SELECT m.id, m.message_id, m.direction, m.sender_id, m.sender_hostname, m.recipients, m.timestamp, m.content
+             FROM messages_fts JOIN messages m ON m.id = messages_fts.rowid
+             WHERE messages_fts MATCH ?1
+             ORDER BY m.timestamp DESC, m.id DESC LIMIT ?2 OFFSET ?3",
+        )?;
+        let entries = stmt.query_map(params![match_expr, limit, offset], entry_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(HistoryPage { entries, total, offset, limit })
+    }
+
+    /// Delete a single entry; returns whether it existed
+    pub fn delete(&self, id: i64) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.execute("DELETE FROM messages WHERE id = ?1", params![id])? > 0)
+    }
+
+    /// Delete every entry, returning how many were removed
+    pub fn clear(&self) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.execute("DELETE FROM messages", [])?)
+    }
+
+    /// Entries matching `filter`, oldest first
+    pub fn entries(&self, filter: &HistoryFilter) -> Result<Vec<HistoryEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, message_id, direction, sender_id, sender_hostname, recipients, timestamp, content
+             FROM messages m
+             WHERE (?1 IS NULL OR m.sender_id = ?1
+                    OR EXISTS (SELECT 1 FROM json_each(m.recipients) WHERE json_each.value = ?1))
+               AND (?2 IS NULL OR m.timestamp >= ?2)
+               AND (?3 IS NULL OR m.timestamp <= ?3)
+             ORDER BY m.timestamp ASC, m.id ASC",
+        )?;
+        let entries = stmt.query_map(
+            params![
+                filter.peer_id,
+                filter.since.as_ref().map(format_timestamp),
+                filter.until.as_ref().map(format_timestamp),
+            ],
+            entry_from_row,
+        )?.collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(entries)
+    }
+
+    /// Render the entries matching `filter` in the given format
+    pub fn export(&self, filter: &HistoryFilter, format: ExportFormat) -> Result<(String, usize)> {
+        let entries = self.entries(filter)?;
+        let count = entries.len();
+        let output = match format {
+            ExportFormat::Jsonl => export_jsonl(entries)?,
+            ExportFormat::Markdown => export_markdown(&entries),
+        };
+        Ok((output, count))
+    }
+
+    /// Import a JSON Lines export, keeping the original ids, senders and timestamps
+    ///
+    /// Entries that already exist are skipped, so importing the same file twice
+    /// is harmless. A malformed line aborts the whole import.
+    pub fn import_jsonl(&self, data: &str) -> Result<ImportSummary> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut summary = ImportSummary::default();
+
+        for (index, line) in data.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: ExportRecord = serde_json::from_str(line)
+                .with_context(|| format!("Invalid history record on line {}", index + 1))?;
+            let inserted = tx.execute(
+                "INSERT OR IGNORE INTO messages (message_id, direction, sender_id, sender_hostname, recipients, timestamp, content)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    record.message_id,
+                    record.direction.as_str(),
+                    record.sender_id,
+                    record.sender_hostname,
+                    serde_json::to_string(&record.recipients)?,
+                    format_timestamp(&record.timestamp),
+                    record.content,
+                ],
+            )?;
+            if inserted > 0 {
+                summary.imported += 1;
+            } else {
+                summary.skipped += 1;
+            }
+        }
+
+        tx.commit()?;
+        Ok(summary)
+    }
+}
+
+fn export_jsonl(entries: Vec<HistoryEntry>) -> Result<String> {
+    let mut output = String::new();
+    for entry in entries {
+        output.push_str(&serde_json::to_string(&ExportRecord::from(entry))?);
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+fn export_markdown(entries: &[HistoryEntry]) -> String {
+    let mut output = String::from("# LanShare history\n");
+    let mut current_day = None;
+
+    for entry in entries {
+        let day = entry.timestamp.date_naive();
+        if current_day != Some(day) {
+            output.push_str(&format!("\n## {}\n", day));
+            current_day = Some(day);
+        }
+
+        let sender = match &entry.sender_hostname {
+            Some(hostname) => format!("{} ({})", hostname, entry.sender_id),
+            None => entry.sender_id.clone(),
+        };
+        let direction = match entry.direction {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        };
+        output.push_str(&format!(
+            "\n### {} UTC, {} from {} to {}\n\n",
+            entry.timestamp.format("%H:%M:%S"),
+            direction,
+            sender,
+            entry.recipients.join(", "),
+        ));
+
+        // Use a fence longer than any backtick run inside the message
+        let fence = "`".repeat(longest_backtick_run(&entry.content).max(2) + 1);
+        output.push_str(&format!("{}text\n{}\n{}\n", fence, entry.content, fence));
+    }
+    output
+}
+
+fn longest_backtick_run(text: &str) -> usize {
+    text.split(|c| c != '`').map(str::len).max().unwrap_or(0)
+}
+
+/// Clamp caller-supplied paging to sane bounds
+fn page_bounds(limit: Option<u32>, offset: Option<u32>) -> (u32, u32) {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    (limit, offset.unwrap_or(0))
+}
+
+/// Fixed-width RFC 3339 so timestamps sort correctly as text
+fn format_timestamp(timestamp: &DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+/// Quote every word so user input can't inject FTS5 syntax
+fn fts_match_expression(query: &str) -> String {
+    query.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn entry_from_row(row: &Row<'_>) -> rusqlite::Result<HistoryEntry> {
+    let direction: String = row.get(2)?;
+    let recipients: String = row.get(5)?;
+    let timestamp: String = row.get(6)?;
+    let to_sql_error = |e: anyhow::Error| rusqlite::Error::FromSqlConversionFailure(
+        0, rusqlite::types::Type::Text, e.into(),
+    );
+
+    Ok(HistoryEntry {
+        id: row.get(0)?,
+        message_id: row.get(1)?,
+        direction: Direction::parse(&direction).map_err(to_sql_error)?,
+        sender_id: row.get(3)?,
+        sender_hostname: row.get(4)?,
+        recipients: serde_json::from_str(&recipients).map_err(|e| to_sql_error(e.into()))?,
+        timestamp: DateTime::parse_from_rfc3339(&timestamp)
+            .map_err(|e| to_sql_error(e.into()))?
+            .with_timezone(&Utc),
+        content: row.get(7)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(message_id: &str, direction: Direction, sender: &str, content: &str) -> HistoryEntry {
+        HistoryEntry::new(
+            message_id.to_string(),
+            direction,
+            sender.to_string(),
+            Some(format!("{}-host", sender)),
+            vec!["peer-b".to_string()],
+            content.to_string(),
+        )
+    }
+
+    /// Entries spaced further apart than the coalescing window
+    fn spaced(mut entry: HistoryEntry, minutes_ago: i64) -> HistoryEntry {
+        entry.timestamp = Utc::now() - chrono::Duration::minutes(minutes_ago);
+        entry
+    }
+
+    #[test]
+    fn test_record_and_list_newest_first() {
+        let store = HistoryStore::open_in_memory().unwrap();
+        store.record(&spaced(entry("m1", Direction::Sent, "me", "first"), 10)).unwrap();
+        store.record(&spaced(entry("m2", Direction::Received, "peer-a", "second"), 5)).unwrap();
+
+        let page = store.list(None, None).unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.entries[0].content, "second");
+        assert_eq!(page.entries[0].direction, Direction::Received);
+        assert_eq!(page.entries[0].sender_hostname.as_deref(), Some("peer-a-host"));
+        assert_eq!(page.entries[1].recipients, vec!["peer-b".to_string()]);
+    }
+
+    #[test]
+    fn test_paging() {
+        let store = HistoryStore::open_in_memory().unwrap();
+        for i in 0..5 {
+            store.record(&spaced(entry(&format!("m{}", i), Direction::Sent, "me", &format!("text {}", i)), 60 - i)).unwrap();
+        }
+
+        let page = store.list(Some(2), Some(2)).unwrap();
+        assert_eq!(page.total, 5);
+        assert_eq!(page.entries.len(), 2);
+        assert_eq!(page.entries[0].content, "text 2");
+        assert_eq!(page.entries[1].content, "text 1");
+    }
+
+    #[test]
+    fn test_live_typing_coalesces_into_one_entry() {
+        let store = HistoryStore::open_in_memory().unwrap();
+        let first = store.record(&entry("m1", Direction::Sent, "me", "h")).unwrap();
+        let second = store.record(&entry("m2", Direction::Sent, "me", "hello")).unwrap();
+        assert_eq!(first, second);
+
+        // A different sender starts a new entry
+        store.record(&entry("m3", Direction::Received, "peer-a", "hi")).unwrap();
+
+        let page = store.list(None, None).unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.entries[1].content, "hello");
+        assert_eq!(page.entries[1].message_id, "m2");
+    }
+
+    #[test]
+    fn test_search_matches_all_words() {
+        let store = HistoryStore::open_in_memory().unwrap();
+        store.record(&spaced(entry("m1", Direction::Sent, "me", "deploy the staging server"), 10)).unwrap();
+        store.record(&spaced(entry("m2", Direction::Received, "peer-a", "staging is down"), 5)).unwrap();
+
+        assert_eq!(store.search("staging", None, None).unwrap().total, 2);
+        let page = store.search("staging server", None, None).unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.entries[0].message_id, "m1");
+        assert_eq!(store.search("   ", None, None).unwrap().total, 0);
+    }
+
+    #[test]
+    fn test_search_treats_operators_as_text() {
+        let store = HistoryStore::open_in_memory().unwrap();
+        store.record(&entry("m1", Direction::Sent, "me", "say \"hi\" OR bye")).unwrap();
+
+        assert!(store.search("\"hi\" OR", None, None).is_ok());
+        assert!(store.search("NEAR(", None, None).is_ok());
+    }
+
+    #[test]
+    fn test_delete_and_clear() {
+        let store = HistoryStore::open_in_memory().unwrap();
+        let id = store.record(&spaced(entry("m1", Direction::Sent, "me", "secret token"), 10)).unwrap();
+        store.record(&spaced(entry("m2", Direction::Sent, "me", "other"), 5)).unwrap();
+
+        assert!(store.delete(id).unwrap());
+        assert!(!store.delete(id).unwrap());
+        assert_eq!(store.search("secret", None, None).unwrap().total, 0);
+
+        assert_eq!(store.clear().unwrap(), 1);
+        assert_eq!(store.list(None, None).unwrap().total, 0);
+    }
+
+    fn filled_store() -> HistoryStore {
+        let store = HistoryStore::open_in_memory().unwrap();
+        let mut a = spaced(entry("m1", Direction::Sent, "me", "to b"), 30);
+        a.recipients = vec!["peer-b".to_string()];
+        let mut b = spaced(entry("m2", Direction::Received, "peer-c", "from c ``` fenced"), 20);
+        b.recipients = vec!["me".to_string()];
+        let mut c = spaced(entry("m3", Direction::Received, "peer-b", "from b"), 10);
+        c.recipients = vec!["me".to_string()];
+        for e in [a, b, c] {
+            store.record(&e).unwrap();
+        }
+        store
+    }
+
+    fn without_ids(entries: Vec<HistoryEntry>) -> Vec<HistoryEntry> {
+        entries.into_iter().map(|mut e| { e.id = 0; e }).collect()
+    }
+
+    #[test]
+    fn test_filter_by_peer_and_time_range() {
+        let store = filled_store();
+
+        let with_b = store.entries(&HistoryFilter { peer_id: Some("peer-b".to_string()), ..HistoryFilter::default() }).unwrap();
+        assert_eq!(with_b.iter().map(|e| e.message_id.as_str()).collect::<Vec<_>>(), vec!["m1", "m3"]);
+
+        let recent = store.entries(&HistoryFilter {
+            since: Some(Utc::now() - chrono::Duration::minutes(25)),
+            until: Some(Utc::now() - chrono::Duration::minutes(15)),
+            ..HistoryFilter::default()
+        }).unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].message_id, "m2");
+    }
+
+    #[test]
+    fn test_jsonl_round_trip_preserves_entries() {
+        let source = filled_store();
+        let (jsonl, count) = source.export(&HistoryFilter::default(), ExportFormat::Jsonl).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(jsonl.lines().count(), 3);
+
+        let target = HistoryStore::open_in_memory().unwrap();
+        let summary = target.import_jsonl(&jsonl).unwrap();
+        assert_eq!(summary, ImportSummary { imported: 3, skipped: 0 });
+
+        let original = without_ids(source.entries(&HistoryFilter::default()).unwrap());
+        let imported = without_ids(target.entries(&HistoryFilter::default()).unwrap());
+        assert_eq!(original, imported);
+
+        // Exporting the imported copy yields the same file
+        let (again, _) = target.export(&HistoryFilter::default(), ExportFormat::Jsonl).unwrap();
+        assert_eq!(again, jsonl);
+    }
+
+    #[test]
+    fn test_reimport_skips_existing_entries() {
+        let store = filled_store();
+        let (jsonl, _) = store.export(&HistoryFilter::default(), ExportFormat::Jsonl).unwrap();
+
+        let summary = store.import_jsonl(&jsonl).unwrap();
+        assert_eq!(summary, ImportSummary { imported: 0, skipped: 3 });
+        assert_eq!(store.list(None, None).unwrap().total, 3);
+    }
+
+    #[test]
+    fn test_import_rejects_malformed_line_atomically() {
+        let store = filled_store();
+        let (jsonl, _) = store.export(&HistoryFilter::default(), ExportFormat::Jsonl).unwrap();
+
+        let target = HistoryStore::open_in_memory().unwrap();
+        let broken = format!("{}not json\n", jsonl);
+        let err = target.import_jsonl(&broken).unwrap_err();
+        assert!(err.to_string().contains("line 4"));
+        assert_eq!(target.list(None, None).unwrap().total, 0);
+    }
+
+    #[test]
+    fn test_markdown_export() {
+        let store = filled_store();
+        let (markdown, count) = store.export(&HistoryFilter::default(), ExportFormat::Markdown).unwrap();
+
+        assert_eq!(count, 3);
+        assert!(markdown.starts_with("# LanShare history"));
+        assert!(markdown.contains("received from peer-c-host (peer-c) to me"));
+        assert!(markdown.contains("````text\nfrom c ``` fenced\n````"));
+    }
+}
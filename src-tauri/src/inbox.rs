@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+use std::sync::{Mutex, RwLock};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::discovery::{ReceivedText, TextSink};
+
+/// Most messages held at once; the oldest is dropped to make room
+pub const INBOX_CAPACITY: usize = 100;
+
+/// A received text waiting to be accepted or rejected
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PendingMessage {
+    /// Id used to accept or reject it, made here: the message id is the
+    /// sender's to choose, so two senders could pick the same one
+    pub id: String,
+    pub received_at: DateTime<Utc>,
+    pub message: ReceivedText,
+}
+
+/// Texts from peers that aren't auto-accepted, held until the user decides
+///
+/// Nothing held here has touched the textarea, the clipboard or the history.
+/// A newer live-mirror update replaces one still pending from the same
+/// sender, since only the latest mirrored text matters. Accepted texts go
+/// to the sink the listener delivers to, so deciding doesn't wait on the
+/// discovery service.
+#[derive(Default)]
+pub struct Inbox {
+    pending: Mutex<VecDeque<PendingMessage>>,
+    sink: RwLock<Option<TextSink>>,
+}
+
+impl Inbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Deliver accepted texts to `sink` from now on
+    pub fn deliver_to(&self, sink: TextSink) {
+        *self.sink.write().unwrap() = Some(sink);
+    }
+
+    /// Hold `message` until it is accepted or rejected
+    pub fn push(&self, message: ReceivedText) -> PendingMessage {
+        let entry = PendingMessage {
+            id: Uuid::new_v4().to_string(),
+            received_at: Utc::now(),
+            message,
+        };
+        let mut pending = self.pending.lock().unwrap();
+        if entry.message.mirror_version.is_some() {
+            pending.retain(|held| {
+                held.message.mirror_version.is_none() || held.message.sender_id != entry.message.sender_id
+            });
+        }
+        if pending.len() >= INBOX_CAPACITY {
+            if let Some(dropped) = pending.pop_front() {
+                warn!("Inbox full, dropping pending message {} from {}", dropped.id, dropped.message.sender_id);
+            }
+        }
+        pending.push_back(entry.clone());
+        entry
+    }
+
+    /// Remove and return the pending message `id`
+    pub fn take(&self, id: &str) -> Option<PendingMessage> {
+        let mut pending = self.pending.lock().unwrap();
+        let index = pending.iter().position(|held| held.id == id)?;
+        pending.remove(index)
+    }
+
+    /// Pending messages, oldest first
+    pub fn pending(&self) -> Vec<PendingMessage> {
+        self.pending.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Deliver the pending text `id` as if it had just arrived
+    pub fn accept(&self, id: &str) -> Result<ReceivedText> {
+        let sink = self.sink.read().unwrap().clone()
+            .ok_or_else(|| anyhow::anyhow!("Discovery service not running"))?;
+        let pending = self.take(id)
+            .ok_or_else(|| anyhow::anyhow!("No pending message {}", id))?;
+        info!("Accepted text {} from {}", pending.id, pending.message.sender_id);
+        sink.deliver(pending.message.clone());
+        Ok(pending.message)
+    }
+
+    /// Discard the pending text `id` without delivering it
+    pub fn reject(&self, id: &str) -> Result<()> {
+        let pending = self.take(id)
+            .ok_or_else(|| anyhow::anyhow!("No pending message {}", id))?;
+        info!("Rejected text {} from {}", pending.id, pending.message.sender_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(sender: &str, message_id: &str, mirror_version: Option<u64>) -> ReceivedText {
+        ReceivedText {
+            sender_id: sender.to_string(),
+            hostname: None,
+            source_addr: "10.0.0.2:7878".parse().unwrap(),
+            message_id: Some(message_id.to_string()),
+            sent_at: Utc::now(),
+            size: 5,
+            chunked: false,
+            room: None,
+            mirror_version,
+            seq: None,
+            text: "hello".to_string(),
+        }
+    }
+
+    fn message_ids(inbox: &Inbox) -> Vec<String> {
+        inbox.pending().into_iter().filter_map(|held| held.message.message_id).collect()
+    }
+
+    #[test]
+    fn test_take_removes_only_the_named_message() {
+        let inbox = Inbox::new();
+        let first = inbox.push(text("a", "m1", None));
+        inbox.push(text("b", "m2", None));
+        assert_eq!(inbox.take(&first.id).unwrap().message.sender_id, "a");
+        assert!(inbox.take(&first.id).is_none());
+        assert_eq!(inbox.pending().len(), 1);
+    }
+
+    #[test]
+    fn test_same_message_id_from_two_senders_stays_apart() {
+        let inbox = Inbox::new();
+        let from_a = inbox.push(text("a", "m1", None));
+        let from_b = inbox.push(text("b", "m1", None));
+        assert_ne!(from_a.id, from_b.id);
+        assert_eq!(inbox.take(&from_b.id).unwrap().message.sender_id, "b");
+        assert_eq!(inbox.take(&from_a.id).unwrap().message.sender_id, "a");
+        assert!(inbox.pending().is_empty());
+    }
+
+    #[test]
+    fn test_newer_mirror_update_replaces_pending_one() {
+        let inbox = Inbox::new();
+        inbox.push(text("a", "m1", Some(1)));
+        inbox.push(text("a", "m2", None));
+        inbox.push(text("b", "m3", Some(1)));
+        inbox.push(text("a", "m4", Some(2)));
+        assert_eq!(message_ids(&inbox), ["m2", "m3", "m4"]);
+    }
+
+    #[test]
+    fn test_oldest_is_dropped_when_full() {
+        let inbox = Inbox::new();
+        for i in 0..=INBOX_CAPACITY {
+            inbox.push(text("a", &format!("m{}", i), None));
+        }
+        let ids = message_ids(&inbox);
+        assert_eq!(ids.len(), INBOX_CAPACITY);
+        assert_eq!(ids[0], "m1");
+    }
+}
@@ -1,6 +1,7 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use tauri::Manager;
@@ -8,8 +9,31 @@ use log::{info, error};
 use anyhow::Result;
 
 
+mod access;
+mod auth;
 mod discovery;
-use discovery::{DiscoveryService, PeerRegistry};
+mod groups;
+mod history;
+mod inbox;
+mod mirror;
+#[cfg(test)]
+mod netsim;
+mod progress;
+mod ratelimit;
+mod rooms;
+mod shared_text;
+mod transfer;
+mod transport;
+use access::{AccessList, AccessStore, PeerPolicy, PeerRule, Permissions};
+use auth::MessageAuth;
+use discovery::{DiscoveryService, PeerRegistry, ReceivedText};
+use inbox::{Inbox, PendingMessage};
+use groups::{GroupStore, RecipientGroup};
+use ratelimit::{RateLimitStats, RateLimiter};
+use rooms::Rooms;
+use shared_text::{SharedDoc, SharedTextView};
+use transfer::{ActiveTransfers, OutboundQueue, PeerSendResult, SendMode};
+use history::{ExportFormat, HistoryFilter, HistoryPage, HistoryStore, ImportSummary};
 
 // WebSocket server for text sharing
 
@@ -18,16 +42,33 @@ use discovery::{DiscoveryService, PeerRegistry};
 struct AppState {
     discovery_service: Arc<tokio::sync::Mutex<Option<DiscoveryService>>>,
     peer_registry: Arc<PeerRegistry>,
+    transfers: Arc<ActiveTransfers>,
+    mirror_queue: Arc<OutboundQueue>,
+    rate_limiter: Arc<RateLimiter>,
+    auth: Arc<MessageAuth>,
+    inbox: Arc<Inbox>,
+    rooms: Arc<Rooms>,
+    shared: Arc<SharedDoc>,
+}
+
+fn is_blocked(access: &AccessStore, peer: &discovery::Peer) -> bool {
+    access.is_blocked(&peer.id, Some(peer.ip), peer.hostname.as_deref())
+}
+
+/// Known peers that aren't blocked
+async fn visible_peers(state: &AppState, access: &AccessStore) -> Vec<discovery::Peer> {
+    let peers = state.peer_registry.get_peers().await;
+    peers.into_iter().filter(|peer| !is_blocked(access, peer)).collect()
 }
 
 #[tauri::command]
-async fn get_peers(state: tauri::State<'_, AppState>) -> Result<Vec<discovery::Peer>, String> {
-    Ok(state.peer_registry.get_peers().await)
+async fn get_peers(state: tauri::State<'_, AppState>, access: tauri::State<'_, Arc<AccessStore>>) -> Result<Vec<discovery::Peer>, String> {
+    Ok(visible_peers(&state, &access).await)
 }
 
 #[tauri::command]
-async fn get_peer_count(state: tauri::State<'_, AppState>) -> Result<usize, String> {
-    Ok(state.peer_registry.peer_count().await)
+async fn get_peer_count(state: tauri::State<'_, AppState>, access: tauri::State<'_, Arc<AccessStore>>) -> Result<usize, String> {
+    Ok(visible_peers(&state, &access).await.len())
 }
 
 #[tauri::command]
@@ -47,107 +88,422 @@ async fn debug_peer_structure(state: tauri::State<'_, AppState>) -> Result<Strin
 }
 
 #[tauri::command]
-async fn send_text_to_peer(state: tauri::State<'_, AppState>, peer_id: String, text: String) -> Result<(), String> {
+async fn send_text_to_peer(state: tauri::State<'_, AppState>, peer_id: String, text: String) -> Result<Option<PeerSendResult>, String> {
     // Validate size before sending (use new chunking limit)
     if text.len() > discovery::MAX_MESSAGE_BYTES {
         return Err(format!("Text too large ({} chars). Max allowed: {}", text.len(), discovery::MAX_MESSAGE_BYTES));
     }
     let peers = state.peer_registry.get_peers().await;
     
-    if let Some(_peer) = peers.iter().find(|p| p.id == peer_id) {
+    if let Some(peer) = peers.iter().find(|p| p.id == peer_id) {
         let discovery_service = state.discovery_service.lock().await;
         if let Some(ds) = discovery_service.as_ref() {
-            // Use chunking for large messages
-            match ds.chunk_text_to_messages(&text, &ds.peer_id().unwrap_or_default(), 7878, None) {
-                Ok(messages) => {
-                    // Send all messages (single or multiple chunks)
-                    if let Ok(socket) = tokio::net::UdpSocket::bind("0.0.0.0:0").await {
-                        let peer_addr = format!("{}:{}", _peer.ip, 7878);
-                        if let Ok(addr) = peer_addr.parse::<std::net::SocketAddr>() {
-                            let message_count = messages.len();
-                            for (i, message) in messages.into_iter().enumerate() {
-                                if let Ok(message_bytes) = serde_json::to_vec(&message) {
-                                    if let Err(e) = socket.send_to(&message_bytes, addr).await {
-                                        error!("Failed to send message chunk to peer {}: {}", peer_id, e);
-                                    } else {
-                                        info!("Sent message chunk {}/{} to peer {}: {} bytes", 
-                                              i + 1, message_count, peer_id, message_bytes.len());
-                                    }
-                                }
-                                
-                                // Rate limiting: 2ms delay between chunks (except for the last one)
-                                if i < message_count - 1 {
-                                    tokio::time::sleep(tokio::time::Duration::from_millis(2)).await;
-                                }
-                            }
-                            info!("Sent text to peer {}: {} chars in {} messages", peer_id, text.len(), message_count);
-                        }
-                    }
-                }
+            // Chunking and pacing are handled by the discovery service
+            match ds.send_text(std::slice::from_ref(peer), &text).await {
+                Ok(mut results) => return Ok(results.pop()),
                 Err(e) => {
-                    error!("Failed to chunk text for peer {}: {}", peer_id, e);
+                    error!("Failed to send text to peer {}: {}", peer_id, e);
+                    return Err(e.to_string());
                 }
             }
         }
-        Ok(())
+        Ok(None)
     } else {
         Err(format!("Peer {} not found", peer_id))
     }
 }
 
 #[tauri::command]
-async fn send_text_to_all_peers(state: tauri::State<'_, AppState>, text: String) -> Result<(), String> {
+async fn send_text_to_all_peers(state: tauri::State<'_, AppState>, text: String, mode: Option<SendMode>) -> Result<Vec<PeerSendResult>, String> {
     // Validate size before sending (use new chunking limit)
     if text.len() > discovery::MAX_MESSAGE_BYTES {
         return Err(format!("Text too large ({} chars). Max allowed: {}", text.len(), discovery::MAX_MESSAGE_BYTES));
     }
-    let peers = state.peer_registry.get_peers().await;
+    // Everyone means everyone in the current room, when one is joined
+    let room = state.rooms.current();
+    let peers = state.peer_registry.get_peers_for(room.as_deref()).await;
     
     if peers.is_empty() {
         info!("No peers available to send text to: {}", text);
-        return Ok(()); // Return success instead of error
+        return Ok(Vec::new()); // Return success instead of error
     }
     
     let discovery_service = state.discovery_service.lock().await;
     if let Some(ds) = discovery_service.as_ref() {
-        // Use chunking for large messages
-        match ds.chunk_text_to_messages(&text, &ds.peer_id().unwrap_or_default(), 7878, None) {
-            Ok(messages) => {
-                // Send all messages (single or multiple chunks) to all peers
-                if let Ok(socket) = tokio::net::UdpSocket::bind("0.0.0.0:0").await {
-                    let message_count = messages.len();
-                    for peer in peers {
-                        let peer_addr = format!("{}:{}", peer.ip, 7878);
-                        if let Ok(addr) = peer_addr.parse::<std::net::SocketAddr>() {
-                            for (i, message) in messages.iter().enumerate() {
-                                if let Ok(message_bytes) = serde_json::to_vec(message) {
-                                    if let Err(e) = socket.send_to(&message_bytes, addr).await {
-                                        error!("Failed to send message chunk to peer {}: {}", peer.id, e);
-                                    } else {
-                                        info!("Sent message chunk {}/{} to peer {}: {} bytes", 
-                                              i + 1, message_count, peer.id, message_bytes.len());
-                                    }
-                                }
-                                
-                                // Rate limiting: 2ms delay between chunks (except for the last one)
-                                if i < message_count - 1 {
-                                    tokio::time::sleep(tokio::time::Duration::from_millis(2)).await;
-                                }
-                            }
-                        }
-                    }
-                    info!("Broadcasted text to all peers: {} chars in {} messages", text.len(), messages.len());
-                }
+        let mode = mode.unwrap_or_default();
+        let sent = match &room {
+            Some(room) => ds.send_text_to_room(room, &text, mode).await,
+            None => ds.send_text_with_mode(&peers, &text, mode).await,
+        };
+        match sent {
+            Ok(results) => {
+                let reached = results.iter().filter(|result| result.status.reached_peer()).count();
+                info!("Broadcasted text to {}/{} peers: {} chars", reached, results.len(), text.len());
+                return Ok(results);
             }
             Err(e) => {
-                error!("Failed to chunk text for broadcast: {}", e);
+                error!("Failed to broadcast text: {}", e);
+                return Err(e.to_string());
             }
         }
     }
     
+    Ok(Vec::new())
+}
+
+/// Live-mirror the textarea to all peers, sending only the edit where a peer
+/// already holds an earlier version
+#[tauri::command]
+async fn mirror_text_to_all_peers(state: tauri::State<'_, AppState>, text: String) -> Result<Vec<PeerSendResult>, String> {
+    if text.len() > discovery::MAX_MESSAGE_BYTES {
+        return Err(format!("Text too large ({} chars). Max allowed: {}", text.len(), discovery::MAX_MESSAGE_BYTES));
+    }
+    let peers = state.peer_registry.get_peers_for(state.rooms.current().as_deref()).await;
+    if peers.is_empty() {
+        return Ok(Vec::new());
+    }
+    // Claim the peers' slots before waiting for the service, so an older
+    // update still queued or being sent stops and this one goes out next
+    let slots = state.mirror_queue.supersede(peers.iter().map(|peer| peer.id.as_str()));
+    let discovery_service = state.discovery_service.lock().await;
+    let Some(ds) = discovery_service.as_ref() else {
+        return Ok(Vec::new());
+    };
+    ds.mirror_text(&peers, &text, slots).await.map_err(|e| {
+        error!("Failed to mirror text: {}", e);
+        e.to_string()
+    })
+}
+
+/// The shared text edited together with peers, and the view version to edit it from
+#[tauri::command]
+async fn get_shared_text(state: tauri::State<'_, AppState>) -> Result<SharedTextView, String> {
+    Ok(state.shared.view())
+}
+
+/// Peers the shared text is edited with; only peers set to auto-accept with
+/// live mirroring allowed take part, so on default settings there are none
+#[tauri::command]
+async fn get_shared_text_peers(state: tauri::State<'_, AppState>) -> Result<Vec<discovery::Peer>, String> {
+    let discovery_service = state.discovery_service.lock().await;
+    match discovery_service.as_ref() {
+        Some(ds) => Ok(ds.shared_text_peers().await),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Apply an edit of the shared text made on view `version`; returns the view
+/// to show next, which includes any concurrent edits from peers
+#[tauri::command]
+async fn edit_shared_text(state: tauri::State<'_, AppState>, version: u64, text: String) -> Result<SharedTextView, String> {
+    if text.len() > discovery::MAX_MESSAGE_BYTES {
+        return Err(format!("Text too large ({} chars). Max allowed: {}", text.len(), discovery::MAX_MESSAGE_BYTES));
+    }
+    let discovery_service = state.discovery_service.lock().await;
+    let Some(ds) = discovery_service.as_ref() else {
+        return Err("Discovery service not running".to_string());
+    };
+    let (view, results) = ds.edit_shared_text(version, &text).await.map_err(|e| {
+        error!("Failed to edit shared text: {}", e);
+        e.to_string()
+    })?;
+    for result in results.iter().filter(|result| !result.status.reached_peer()) {
+        // The peer catches up from its version vector once it is reachable
+        info!("Shared text edit not delivered to {}: {:?}", result.peer_id, result.error);
+    }
+    Ok(view)
+}
+
+/// Send to an explicit set of peers; one result per id, in the order given
+#[tauri::command]
+async fn send_text_to_peers(state: tauri::State<'_, AppState>, peer_ids: Vec<String>, text: String, mode: Option<SendMode>) -> Result<Vec<PeerSendResult>, String> {
+    send_to_peer_ids(&state, &peer_ids, &text, mode).await
+}
+
+/// Send to the members of a saved recipient group
+#[tauri::command]
+async fn send_text_to_group(
+    state: tauri::State<'_, AppState>,
+    groups: tauri::State<'_, Arc<GroupStore>>,
+    group: String,
+    text: String,
+    mode: Option<SendMode>,
+) -> Result<Vec<PeerSendResult>, String> {
+    let group = groups.get(&group)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Group {} not found", group))?;
+    send_to_peer_ids(&state, &group.peer_ids, &text, mode).await
+}
+
+async fn send_to_peer_ids(state: &AppState, peer_ids: &[String], text: &str, mode: Option<SendMode>) -> Result<Vec<PeerSendResult>, String> {
+    if text.len() > discovery::MAX_MESSAGE_BYTES {
+        return Err(format!("Text too large ({} chars). Max allowed: {}", text.len(), discovery::MAX_MESSAGE_BYTES));
+    }
+    let discovery_service = state.discovery_service.lock().await;
+    let Some(ds) = discovery_service.as_ref() else {
+        return Ok(Vec::new());
+    };
+    match ds.send_text_to_peer_ids(peer_ids, text, mode.unwrap_or_default()).await {
+        Ok(results) => {
+            let reached = results.iter().filter(|result| result.status.reached_peer()).count();
+            info!("Sent text to {}/{} selected peers: {} chars", reached, results.len(), text.len());
+            Ok(results)
+        }
+        Err(e) => {
+            error!("Failed to send text to selected peers: {}", e);
+            Err(e.to_string())
+        }
+    }
+}
+
+#[tauri::command]
+async fn save_recipient_group(groups: tauri::State<'_, Arc<GroupStore>>, name: String, peer_ids: Vec<String>) -> Result<RecipientGroup, String> {
+    groups.save(&name, &peer_ids).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_recipient_groups(groups: tauri::State<'_, Arc<GroupStore>>) -> Result<Vec<RecipientGroup>, String> {
+    groups.list().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn delete_recipient_group(groups: tauri::State<'_, Arc<GroupStore>>, name: String) -> Result<bool, String> {
+    groups.delete(&name).map_err(|e| e.to_string())
+}
+
+/// Stop an outgoing chunked transfer; returns false if it already finished
+#[tauri::command]
+async fn cancel_transfer(state: tauri::State<'_, AppState>, message_id: String) -> Result<bool, String> {
+    Ok(state.transfers.cancel(&message_id))
+}
+
+/// Texts from peers that aren't auto-accepted, waiting to be accepted or rejected
+#[tauri::command]
+async fn get_pending_messages(state: tauri::State<'_, AppState>) -> Result<Vec<PendingMessage>, String> {
+    Ok(state.inbox.pending())
+}
+
+/// Deliver a pending text; it then arrives as a normal `text-received` event
+#[tauri::command]
+async fn accept_message(state: tauri::State<'_, AppState>, id: String) -> Result<ReceivedText, String> {
+    state.inbox.accept(&id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn reject_message(state: tauri::State<'_, AppState>, id: String) -> Result<(), String> {
+    state.inbox.reject(&id).map_err(|e| e.to_string())
+}
+
+/// Put a peer on the block or allow list; `ip` and `hostname` keep the rule
+/// matching after the peer restarts with a new id
+#[tauri::command]
+async fn add_peer_rule(
+    state: tauri::State<'_, AppState>,
+    access: tauri::State<'_, Arc<AccessStore>>,
+    list: AccessList,
+    peer_id: String,
+    ip: Option<IpAddr>,
+    hostname: Option<String>,
+) -> Result<PeerRule, String> {
+    let rule = access.add(list, &peer_id, ip, hostname.as_deref()).map_err(|e| e.to_string())?;
+    // The listener ignores blocked peers from now on; forget the ones we know
+    if list == AccessList::Block {
+        for peer in state.peer_registry.get_peers().await {
+            if rule.matches(&peer.id, Some(peer.ip), peer.hostname.as_deref()) {
+                state.peer_registry.remove_peer(&peer.id).await;
+            }
+        }
+    }
+    Ok(rule)
+}
+
+#[tauri::command]
+async fn remove_peer_rule(access: tauri::State<'_, Arc<AccessStore>>, list: AccessList, peer_id: String) -> Result<bool, String> {
+    access.remove(list, &peer_id).map_err(|e| e.to_string())
+}
+
+/// Rules on `list`, or on both lists if none is given
+#[tauri::command]
+async fn get_peer_rules(access: tauri::State<'_, Arc<AccessStore>>, list: Option<AccessList>) -> Result<Vec<PeerRule>, String> {
+    Ok(access.rules(list))
+}
+
+/// Give a peer its own permissions; `ip` and `hostname` keep them applying
+/// after the peer restarts with a new id
+#[tauri::command]
+async fn set_peer_policy(
+    access: tauri::State<'_, Arc<AccessStore>>,
+    peer_id: String,
+    ip: Option<IpAddr>,
+    hostname: Option<String>,
+    permissions: Permissions,
+) -> Result<PeerPolicy, String> {
+    access.set_policy(&peer_id, ip, hostname.as_deref(), permissions).map_err(|e| e.to_string())
+}
+
+/// Return a peer to the default permissions
+#[tauri::command]
+async fn remove_peer_policy(access: tauri::State<'_, Arc<AccessStore>>, peer_id: String) -> Result<bool, String> {
+    access.remove_policy(&peer_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_peer_policies(access: tauri::State<'_, Arc<AccessStore>>) -> Result<Vec<PeerPolicy>, String> {
+    Ok(access.policies())
+}
+
+/// What a peer may do right now, whether from its own policy or the defaults
+#[tauri::command]
+async fn get_peer_permissions(
+    state: tauri::State<'_, AppState>,
+    access: tauri::State<'_, Arc<AccessStore>>,
+    peer_id: String,
+) -> Result<Permissions, String> {
+    let peer = state.peer_registry.get_peer(&peer_id).await;
+    Ok(access.permissions(&peer_id, peer.as_ref().map(|peer| peer.ip), peer.as_ref().and_then(|peer| peer.hostname.as_deref())))
+}
+
+/// Set the key shared by the peers we talk to, or turn authentication off
+/// with None; peers without the same key can no longer reach us
+#[tauri::command]
+async fn set_network_key(
+    state: tauri::State<'_, AppState>,
+    access: tauri::State<'_, Arc<AccessStore>>,
+    key: Option<String>,
+) -> Result<(), String> {
+    access.set_network_key(key.as_deref()).map_err(|e| e.to_string())?;
+    state.auth.set_key(key.as_deref());
     Ok(())
 }
 
+/// Whether messages are authenticated with a network key
+#[tauri::command]
+async fn has_network_key(state: tauri::State<'_, AppState>) -> Result<bool, String> {
+    Ok(state.auth.has_key())
+}
+
+/// Incoming traffic dropped for exceeding rate limits, and who is blocked for it
+#[tauri::command]
+async fn get_rate_limit_stats(state: tauri::State<'_, AppState>) -> Result<RateLimitStats, String> {
+    Ok(state.rate_limiter.stats())
+}
+
+/// Join a room and make it the current one; returns the name as stored (trimmed)
+#[tauri::command]
+async fn join_room(state: tauri::State<'_, AppState>, room: String) -> Result<String, String> {
+    state.rooms.join(&room).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn leave_room(state: tauri::State<'_, AppState>, room: String) -> Result<bool, String> {
+    Ok(state.rooms.leave(&room))
+}
+
+#[tauri::command]
+async fn get_joined_rooms(state: tauri::State<'_, AppState>) -> Result<Vec<String>, String> {
+    Ok(state.rooms.joined().into_iter().collect())
+}
+
+/// Rooms advertised by peers on the network
+#[tauri::command]
+async fn get_known_rooms(state: tauri::State<'_, AppState>) -> Result<Vec<String>, String> {
+    Ok(state.peer_registry.known_rooms().await.into_iter().collect())
+}
+
+#[tauri::command]
+async fn get_room_peers(state: tauri::State<'_, AppState>, access: tauri::State<'_, Arc<AccessStore>>, room: String) -> Result<Vec<discovery::Peer>, String> {
+    let peers = state.peer_registry.get_peers_in_room(room.trim()).await;
+    Ok(peers.into_iter().filter(|peer| !is_blocked(&access, peer)).collect())
+}
+
+#[tauri::command]
+async fn send_text_to_room(state: tauri::State<'_, AppState>, room: String, text: String, mode: Option<SendMode>) -> Result<Vec<PeerSendResult>, String> {
+    if text.len() > discovery::MAX_MESSAGE_BYTES {
+        return Err(format!("Text too large ({} chars). Max allowed: {}", text.len(), discovery::MAX_MESSAGE_BYTES));
+    }
+    let discovery_service = state.discovery_service.lock().await;
+    let Some(ds) = discovery_service.as_ref() else {
+        return Ok(Vec::new());
+    };
+    match ds.send_text_to_room(&room, &text, mode.unwrap_or_default()).await {
+        Ok(results) => {
+            let reached = results.iter().filter(|result| result.status.reached_peer()).count();
+            info!("Sent text to {}/{} peers in room {}: {} chars", reached, results.len(), room, text.len());
+            Ok(results)
+        }
+        Err(e) => {
+            error!("Failed to send text to room {}: {}", room, e);
+            Err(e.to_string())
+        }
+    }
+}
+
+#[tauri::command]
+async fn get_history(history: tauri::State<'_, Arc<HistoryStore>>, limit: Option<u32>, offset: Option<u32>) -> Result<HistoryPage, String> {
+    history.list(limit, offset).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn search_history(history: tauri::State<'_, Arc<HistoryStore>>, query: String, limit: Option<u32>, offset: Option<u32>) -> Result<HistoryPage, String> {
+    history.search(&query, limit, offset).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn delete_history_entry(history: tauri::State<'_, Arc<HistoryStore>>, id: i64) -> Result<bool, String> {
+    history.delete(id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn clear_history(history: tauri::State<'_, Arc<HistoryStore>>) -> Result<usize, String> {
+    let removed = history.clear().map_err(|e| e.to_string())?;
+    info!("Cleared {} history entries", removed);
+    Ok(removed)
+}
+
+#[tauri::command]
+async fn export_history(
+    history: tauri::State<'_, Arc<HistoryStore>>,
+    path: String,
+    format: ExportFormat,
+    peer_id: Option<String>,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    until: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<usize, String> {
+    let filter = HistoryFilter { peer_id, since, until };
+    let (output, count) = history.export(&filter, format).map_err(|e| e.to_string())?;
+    std::fs::write(&path, output).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    info!("Exported {} history entries to {}", count, path);
+    Ok(count)
+}
+
+#[tauri::command]
+async fn import_history(history: tauri::State<'_, Arc<HistoryStore>>, path: String) -> Result<ImportSummary, String> {
+    let data = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let summary = history.import_jsonl(&data).map_err(|e| e.to_string())?;
+    info!("Imported history from {}: {} new, {} already present", path, summary.imported, summary.skipped);
+    Ok(summary)
+}
+
+/// Open a database in the app data directory, falling back to an
+/// in-memory store so the app still runs if the disk is unavailable
+fn open_app_store<T>(
+    app: &tauri::App,
+    file_name: &str,
+    open: impl FnOnce(&std::path::Path) -> Result<T>,
+    open_in_memory: impl FnOnce() -> Result<T>,
+) -> T {
+    let opened = app.path().app_data_dir()
+        .map_err(anyhow::Error::from)
+        .and_then(|dir| open(&dir.join(file_name)));
+    match opened {
+        Ok(store) => store,
+        Err(e) => {
+            error!("Failed to open {}, keeping it in memory: {}", file_name, e);
+            open_in_memory().unwrap_or_else(|e| panic!("failed to open in-memory {}: {}", file_name, e))
+        }
+    }
+}
+
 fn main() -> Result<()> {
     // Initialize logging
     env_logger::init();
@@ -155,9 +511,23 @@ fn main() -> Result<()> {
     // Create the discovery service
     let discovery_service = DiscoveryService::new(Duration::from_secs(8)); // 8 second timeout for faster cleanup
     let peer_registry = discovery_service.registry();
+    let transfers = discovery_service.transfers();
+    let mirror_queue = discovery_service.mirror_queue();
+    let rate_limiter = discovery_service.rate_limiter();
+    let auth = discovery_service.auth();
+    let inbox = discovery_service.inbox();
+    let rooms = discovery_service.rooms();
+    let shared = discovery_service.shared();
     let app_state = AppState {
         discovery_service: Arc::new(tokio::sync::Mutex::new(Some(discovery_service))),
         peer_registry,
+        transfers,
+        mirror_queue,
+        rate_limiter,
+        auth,
+        inbox,
+        rooms,
+        shared,
     };
     tauri::Builder::default()
         .manage(app_state)
@@ -167,20 +537,66 @@ fn main() -> Result<()> {
             get_peer_id,
             debug_peer_structure,
             send_text_to_peer,
-            send_text_to_all_peers
+            send_text_to_all_peers,
+            mirror_text_to_all_peers,
+            send_text_to_peers,
+            send_text_to_group,
+            save_recipient_group,
+            get_recipient_groups,
+            delete_recipient_group,
+            cancel_transfer,
+            get_rate_limit_stats,
+            get_pending_messages,
+            accept_message,
+            reject_message,
+            add_peer_rule,
+            remove_peer_rule,
+            get_peer_rules,
+            set_peer_policy,
+            remove_peer_policy,
+            get_peer_policies,
+            get_peer_permissions,
+            set_network_key,
+            has_network_key,
+            join_room,
+            leave_room,
+            get_joined_rooms,
+            get_known_rooms,
+            get_room_peers,
+            send_text_to_room,
+            get_shared_text,
+            get_shared_text_peers,
+            edit_shared_text,
+            get_history,
+            search_history,
+            delete_history_entry,
+            clear_history,
+            export_history,
+            import_history
         ])
         .setup(|app| {
+            let history = Arc::new(open_app_store(app, "history.db", HistoryStore::open, HistoryStore::open_in_memory));
+            app.manage(history.clone());
+            app.manage(Arc::new(open_app_store(app, "groups.db", GroupStore::open, GroupStore::open_in_memory)));
+            let access = Arc::new(open_app_store(app, "access.db", AccessStore::open, AccessStore::open_in_memory));
+            app.manage(access.clone());
+            match access.network_key() {
+                Ok(key) => app.state::<AppState>().auth.set_key(key.as_deref()),
+                Err(e) => error!("Failed to load network key: {}", e),
+            }
             let discovery_service = app.state::<AppState>().discovery_service.clone();
             let app_handle = app.app_handle();
             tauri::async_runtime::block_on(async move {
                 let mut discovery_service_guard = discovery_service.lock().await;
                 if let Some(ref mut ds) = *discovery_service_guard {
                     ds.app_handle = Some(app_handle.clone());
+                    ds.history = Some(history);
+                    ds.access = Some(access);
                     match ds.start(7878).await {
                         Ok(()) => {
-                            info!("Discovery service initialized successfully");
+                            info!("Discovery service initialized successfully on port {}", ds.listen_port().unwrap_or(7878));
                             if let Some(peer_id) = ds.peer_id() {
-                                if let Ok(_broadcaster_handle) = ds.get_broadcaster_task(7878) {
+                                if let Ok(_broadcaster_handle) = ds.get_broadcaster_task() {
                                     info!("Broadcaster task spawned");
                                 } else {
                                     error!("Failed to spawn broadcaster task");
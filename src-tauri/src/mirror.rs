@@ -0,0 +1,288 @@
+//! Delta synchronization for live mirroring.
+//!
+//! Every text the sender mirrors gets a new version number. Once a peer has
+//! acknowledged a version, later updates to that peer carry only the edit
+//! against it; a peer that hasn't acknowledged anything yet, or whose copy
+//! has diverged, gets a full snapshot instead.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+// Sent versions remembered so a late acknowledgement can still be matched to its text
+const RECENT_VERSIONS: usize = 32;
+
+/// Versioning carried by a mirrored text; the message text is the whole
+/// snapshot, or the inserted text if `delta` is set
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MirrorHeader {
+    pub version: u64,
+    pub delta: Option<DeltaHeader>,
+}
+
+/// Where the inserted text goes in the base version
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DeltaHeader {
+    pub base_version: u64,
+    /// Byte offset of the edit in the base text
+    pub start: usize,
+    /// Bytes of the base text replaced by the inserted text
+    pub delete_len: usize,
+}
+
+/// A single contiguous edit: replace `delete_len` bytes at `start` with `insert`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextDelta {
+    pub start: usize,
+    pub delete_len: usize,
+    pub insert: String,
+}
+
+impl TextDelta {
+    /// The smallest single edit turning `old` into `new`
+    ///
+    /// Typing and pasting change one region at a time, so trimming the common
+    /// prefix and suffix is enough to keep updates small.
+    pub fn between(old: &str, new: &str) -> Self {
+        let prefix = common_prefix_len(old, new);
+        let suffix = common_suffix_len(&old[prefix..], &new[prefix..]);
+        Self {
+            start: prefix,
+            delete_len: old.len() - prefix - suffix,
+            insert: new[prefix..new.len() - suffix].to_string(),
+        }
+    }
+
+    pub fn apply(&self, base: &str) -> Result<String> {
+        let end = self.start.checked_add(self.delete_len)
+            .filter(|end| *end <= base.len())
+            .ok_or_else(|| anyhow::anyhow!("Edit {}+{} is outside the {} byte base text", self.start, self.delete_len, base.len()))?;
+        if !base.is_char_boundary(self.start) || !base.is_char_boundary(end) {
+            return Err(anyhow::anyhow!("Edit {}+{} splits a character", self.start, self.delete_len));
+        }
+        let mut text = String::with_capacity(base.len() - self.delete_len + self.insert.len());
+        text.push_str(&base[..self.start]);
+        text.push_str(&self.insert);
+        text.push_str(&base[end..]);
+        Ok(text)
+    }
+}
+
+fn common_prefix_len(a: &str, b: &str) -> usize {
+    a.chars().zip(b.chars())
+        .take_while(|(x, y)| x == y)
+        .map(|(c, _)| c.len_utf8())
+        .sum()
+}
+
+fn common_suffix_len(a: &str, b: &str) -> usize {
+    a.chars().rev().zip(b.chars().rev())
+        .take_while(|(x, y)| x == y)
+        .map(|(c, _)| c.len_utf8())
+        .sum()
+}
+
+#[derive(Debug, Default)]
+struct OutboxState {
+    version: u64,
+    recent: VecDeque<(u64, Arc<str>)>,
+    /// Last version each peer confirmed, with its text
+    acked: HashMap<String, (u64, Arc<str>)>,
+}
+
+/// Sender side: the versions we mirrored and what each peer has confirmed
+#[derive(Debug, Default)]
+pub struct MirrorOutbox {
+    state: Mutex<OutboxState>,
+    acked_changed: Notify,
+}
+
+impl MirrorOutbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Assign the next version to `text`
+    pub fn publish(&self, text: &str) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.version += 1;
+        let version = state.version;
+        state.recent.push_back((version, Arc::from(text)));
+        if state.recent.len() > RECENT_VERSIONS {
+            state.recent.pop_front();
+        }
+        version
+    }
+
+    /// The version and text `peer_id` last confirmed, to compute a delta against
+    pub fn base_for(&self, peer_id: &str) -> Option<(u64, Arc<str>)> {
+        self.state.lock().unwrap().acked.get(peer_id).cloned()
+    }
+
+    /// `peer_id` reported the version it now holds; None means it holds nothing
+    ///
+    /// A version we no longer remember can't be diffed against, so the peer
+    /// is treated as holding nothing and gets a snapshot next time.
+    pub fn on_ack(&self, peer_id: &str, version: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        let known = version.and_then(|version| {
+            state.recent.iter().find(|(v, _)| *v == version).cloned()
+        });
+        match known {
+            Some(acked) => {
+                debug!("Peer {} mirrors version {}", peer_id, acked.0);
+                state.acked.insert(peer_id.to_string(), acked);
+            }
+            None => {
+                debug!("Peer {} needs a mirror snapshot (reported {:?})", peer_id, version);
+                state.acked.remove(peer_id);
+            }
+        }
+        drop(state);
+        self.acked_changed.notify_waiters();
+    }
+
+    fn has_acked(&self, peer_id: &str, version: u64) -> bool {
+        self.state.lock().unwrap().acked.get(peer_id).is_some_and(|(v, _)| *v >= version)
+    }
+
+    /// Wait until every one of `peer_ids` confirmed `version`, or `timeout`
+    /// passes; returns the peers that didn't
+    pub async fn wait_for_acks(&self, peer_ids: &[String], version: u64, timeout: Duration) -> Vec<String> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let notified = self.acked_changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let pending: Vec<String> = peer_ids.iter()
+                .filter(|id| !self.has_acked(id, version))
+                .cloned()
+                .collect();
+            if pending.is_empty() || tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return pending;
+            }
+        }
+    }
+}
+
+/// Receiver side: the text each sender mirrors to us, by version
+#[derive(Debug, Default)]
+pub struct MirrorInbox {
+    texts: Mutex<HashMap<String, (u64, String)>>,
+}
+
+impl MirrorInbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply a mirrored update from `sender_id`; returns the full text to
+    /// deliver, or None if the update is stale or doesn't fit our copy
+    pub fn receive(&self, sender_id: &str, header: &MirrorHeader, text: &str) -> Option<String> {
+        let mut texts = self.texts.lock().unwrap();
+        let current = texts.get(sender_id);
+        if current.is_some_and(|(version, _)| *version >= header.version) {
+            debug!("Ignoring stale mirror version {} from {}", header.version, sender_id);
+            return None;
+        }
+        let full = match &header.delta {
+            None => text.to_string(),
+            Some(delta) => {
+                let Some((version, base)) = current.filter(|(version, _)| *version == delta.base_version) else {
+                    debug!("Mirror delta from {} is against version {}, we hold {:?}",
+                           sender_id, delta.base_version, current.map(|(version, _)| version));
+                    return None;
+                };
+                let edit = TextDelta { start: delta.start, delete_len: delta.delete_len, insert: text.to_string() };
+                match edit.apply(base) {
+                    Ok(full) => full,
+                    Err(e) => {
+                        warn!("Failed to apply mirror delta {} -> {} from {}: {}", version, header.version, sender_id, e);
+                        return None;
+                    }
+                }
+            }
+        };
+        texts.insert(sender_id.to_string(), (header.version, full.clone()));
+        Some(full)
+    }
+
+    /// The version of `sender_id`'s text we hold
+    pub fn version(&self, sender_id: &str) -> Option<u64> {
+        self.texts.lock().unwrap().get(sender_id).map(|(version, _)| *version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_round_trips_edits() {
+        for (old, new) in [
+            ("hello world", "hello brave world"),
+            ("hello world", "hello"),
+            ("", "fresh"),
+            ("same", "same"),
+            ("aaaa", "aaaaa"),
+            ("café 🌍", "café 🌎"),
+            ("abc", "xyz"),
+        ] {
+            let delta = TextDelta::between(old, new);
+            assert_eq!(delta.apply(old).unwrap(), new, "{:?} -> {:?}", old, new);
+        }
+        let delta = TextDelta::between("hello world", "hello brave world");
+        assert_eq!(delta, TextDelta { start: 6, delete_len: 0, insert: "brave ".to_string() });
+    }
+
+    #[test]
+    fn test_delta_rejects_bad_ranges() {
+        let outside = TextDelta { start: 3, delete_len: 5, insert: String::new() };
+        assert!(outside.apply("short").is_err());
+        let split = TextDelta { start: 4, delete_len: 1, insert: String::new() };
+        assert!(split.apply("café").is_err());
+    }
+
+    #[test]
+    fn test_inbox_applies_deltas_only_to_matching_version() {
+        let inbox = MirrorInbox::new();
+        let snapshot = MirrorHeader { version: 1, delta: None };
+        assert_eq!(inbox.receive("a", &snapshot, "hello").as_deref(), Some("hello"));
+
+        let delta = |base_version, version| MirrorHeader {
+            version,
+            delta: Some(DeltaHeader { base_version, start: 5, delete_len: 0 }),
+        };
+        assert_eq!(inbox.receive("a", &delta(1, 2), "!").as_deref(), Some("hello!"));
+        // Against a version we don't hold, and a replay of an old version
+        assert!(inbox.receive("a", &delta(1, 3), "?").is_none());
+        assert!(inbox.receive("a", &delta(1, 2), "!").is_none());
+        assert_eq!(inbox.version("a"), Some(2));
+        assert!(inbox.receive("b", &delta(1, 2), "!").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_outbox_tracks_acked_versions() {
+        let outbox = MirrorOutbox::new();
+        let v1 = outbox.publish("one");
+        let v2 = outbox.publish("two");
+        assert!(outbox.base_for("peer").is_none());
+
+        outbox.on_ack("peer", Some(v1));
+        assert_eq!(outbox.base_for("peer").map(|(v, text)| (v, text.to_string())), Some((v1, "one".to_string())));
+        let pending = outbox.wait_for_acks(&["peer".to_string()], v2, Duration::from_millis(10)).await;
+        assert_eq!(pending, vec!["peer".to_string()]);
+
+        outbox.on_ack("peer", Some(v2));
+        assert!(outbox.wait_for_acks(&["peer".to_string()], v2, Duration::from_millis(10)).await.is_empty());
+
+        // Unknown versions and empty acks fall back to snapshots
+        outbox.on_ack("peer", Some(999));
+        assert!(outbox.base_for("peer").is_none());
+    }
+}
@@ -0,0 +1,319 @@
+//! Lossy link simulation for the in-memory transport.
+//!
+//! A `LossyLink` decides the fate of every datagram crossing a `MemoryNetwork`:
+//! it may be dropped, duplicated, delayed, held back so later datagrams overtake
+//! it, or have a bit flipped. All decisions come from a seeded RNG so a failing
+//! scenario can be replayed exactly.
+
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Impairments applied to every datagram on a simulated network
+#[derive(Debug, Clone)]
+pub struct LinkConditions {
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    pub reorder_rate: f64,
+    pub corrupt_rate: f64,
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    /// Extra delay for datagrams picked for reordering
+    pub reorder_delay: Duration,
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        Self {
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            reorder_rate: 0.0,
+            corrupt_rate: 0.0,
+            min_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            reorder_delay: Duration::from_millis(20),
+        }
+    }
+}
+
+/// Counters for what the link did to the traffic it carried
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LinkStats {
+    pub sent: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub corrupted: u64,
+}
+
+/// One copy of a datagram scheduled for delivery
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub data: Vec<u8>,
+    pub delay: Duration,
+}
+
+/// Seeded impairment model shared by all endpoints of a network
+pub struct LossyLink {
+    conditions: LinkConditions,
+    rng: StdRng,
+    stats: LinkStats,
+}
+
+impl LossyLink {
+    pub fn new(conditions: LinkConditions, seed: u64) -> Self {
+        Self {
+            conditions,
+            rng: StdRng::seed_from_u64(seed),
+            stats: LinkStats::default(),
+        }
+    }
+
+    /// Decide what happens to one datagram; an empty result means it was lost
+    pub fn transmit(&mut self, data: &[u8]) -> Vec<Delivery> {
+        self.stats.sent += 1;
+
+        if self.rng.gen_bool(self.conditions.drop_rate) {
+            self.stats.dropped += 1;
+            return Vec::new();
+        }
+
+        let copies = if self.rng.gen_bool(self.conditions.duplicate_rate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+
+        (0..copies).map(|_| {
+            let mut bytes = data.to_vec();
+            if !bytes.is_empty() && self.rng.gen_bool(self.conditions.corrupt_rate) {
+                let index = self.rng.gen_range(0..bytes.len());
+                let bit = self.rng.gen_range(0..8);
+                bytes[index] ^= 1 << bit;
+                self.stats.corrupted += 1;
+            }
+
+            let mut delay = if self.conditions.max_delay > self.conditions.min_delay {
+                self.rng.gen_range(self.conditions.min_delay..=self.conditions.max_delay)
+            } else {
+                self.conditions.min_delay
+            };
+            if self.rng.gen_bool(self.conditions.reorder_rate) {
+                delay += self.conditions.reorder_delay;
+                self.stats.reordered += 1;
+            }
+
+            Delivery { data: bytes, delay }
+        }).collect()
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::{DiscoveryService, Peer, ReceivedText};
+    use crate::transfer::{SendMode, SendStatus};
+    use crate::transport::memory::MemoryNetwork;
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio::sync::broadcast;
+
+    /// Start a service that only listens; peers are wired up with `connect`
+    /// so the RNG sees nothing but data traffic, in send order
+    async fn start_listening_service(network: &MemoryNetwork, ip: Ipv4Addr) -> DiscoveryService {
+        let mut service = DiscoveryService::with_transport(Duration::from_secs(30), network.host(ip));
+        service.start(7878).await.unwrap();
+        service.get_listener_task(service.peer_id().unwrap()).unwrap();
+        service
+    }
+
+    async fn connect(from: &DiscoveryService, to: &DiscoveryService, to_ip: Ipv4Addr) {
+        from.registry().add_peer(Peer::new(to.peer_id().unwrap(), IpAddr::V4(to_ip), 7878, None)).await;
+    }
+
+    /// Drain every text delivered until the receiver has been quiet for `quiet`
+    async fn collect_texts(rx: &mut broadcast::Receiver<ReceivedText>, quiet: Duration) -> Vec<String> {
+        let mut texts = Vec::new();
+        while let Ok(Ok(received)) = tokio::time::timeout(quiet, rx.recv()).await {
+            texts.push(received.text);
+        }
+        texts
+    }
+
+    #[test]
+    fn test_perfect_link_delivers_unchanged() {
+        let mut link = LossyLink::new(LinkConditions::default(), 1);
+        let deliveries = link.transmit(b"datagram");
+        assert_eq!(deliveries, vec![Delivery { data: b"datagram".to_vec(), delay: Duration::ZERO }]);
+        assert_eq!(link.stats(), LinkStats { sent: 1, ..LinkStats::default() });
+    }
+
+    #[test]
+    fn test_same_seed_replays_same_decisions() {
+        let conditions = LinkConditions {
+            drop_rate: 0.2,
+            duplicate_rate: 0.2,
+            reorder_rate: 0.2,
+            corrupt_rate: 0.2,
+            max_delay: Duration::from_millis(10),
+            ..LinkConditions::default()
+        };
+        let mut a = LossyLink::new(conditions.clone(), 42);
+        let mut b = LossyLink::new(conditions, 42);
+
+        for i in 0..200u32 {
+            let data = i.to_be_bytes();
+            assert_eq!(a.transmit(&data), b.transmit(&data));
+        }
+        assert_eq!(a.stats(), b.stats());
+        assert!(a.stats().dropped > 0);
+        assert!(a.stats().duplicated > 0);
+        assert!(a.stats().corrupted > 0);
+        assert!(a.stats().reordered > 0);
+    }
+
+    #[test]
+    fn test_full_corruption_flips_exactly_one_bit() {
+        let conditions = LinkConditions { corrupt_rate: 1.0, ..LinkConditions::default() };
+        let mut link = LossyLink::new(conditions, 7);
+        let original = vec![0u8; 64];
+
+        let deliveries = link.transmit(&original);
+        assert_eq!(deliveries.len(), 1);
+        let flipped_bits: u32 = deliveries[0].data.iter().map(|b| b.count_ones()).sum();
+        assert_eq!(flipped_bits, 1);
+    }
+
+    #[tokio::test]
+    async fn test_reordered_duplicated_chunks_reassemble_once() {
+        let network = MemoryNetwork::with_link(LinkConditions {
+            duplicate_rate: 0.3,
+            reorder_rate: 0.3,
+            max_delay: Duration::from_millis(15),
+            ..LinkConditions::default()
+        }, 2024);
+        let sender = start_listening_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let receiver_a = start_listening_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let receiver_b = start_listening_service(&network, Ipv4Addr::new(10, 0, 0, 3)).await;
+        connect(&sender, &receiver_a, Ipv4Addr::new(10, 0, 0, 2)).await;
+        connect(&sender, &receiver_b, Ipv4Addr::new(10, 0, 0, 3)).await;
+        let mut rx_a = receiver_a.subscribe_text();
+        let mut rx_b = receiver_b.subscribe_text();
+
+        let text = "reordered 🚚 ".repeat(400);
+        sender.send_text(&sender.registry().get_peers().await, &text).await.unwrap();
+
+        let stats = network.link_stats().unwrap();
+        assert!(stats.duplicated > 0 && stats.reordered > 0);
+        for rx in [&mut rx_a, &mut rx_b] {
+            assert_eq!(collect_texts(rx, Duration::from_millis(200)).await, vec![text.clone()]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dropped_link_delivers_nothing() {
+        let network = MemoryNetwork::with_link(LinkConditions { drop_rate: 1.0, ..LinkConditions::default() }, 3);
+        let sender = start_listening_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let receiver = start_listening_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        connect(&sender, &receiver, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let mut rx = receiver.subscribe_text();
+
+        let peers = sender.registry().get_peers().await;
+        sender.send_text(&peers, "short").await.unwrap();
+        sender.send_text(&peers, &"long ".repeat(500)).await.unwrap();
+
+        assert!(collect_texts(&mut rx, Duration::from_millis(100)).await.is_empty());
+        let stats = network.link_stats().unwrap();
+        assert_eq!(stats.sent, stats.dropped);
+    }
+
+    #[tokio::test]
+    async fn test_partial_loss_is_repaired_by_retransmission() {
+        let network = MemoryNetwork::with_link(LinkConditions { drop_rate: 0.05, ..LinkConditions::default() }, 99);
+        let sender = start_listening_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let receiver = start_listening_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        connect(&sender, &receiver, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let mut rx = receiver.subscribe_text();
+
+        let peers = sender.registry().get_peers().await;
+        let texts: Vec<String> = (0..5).map(|i| format!("message {} ", i).repeat(300)).collect();
+        for text in &texts {
+            sender.send_text(&peers, text).await.unwrap();
+        }
+
+        let delivered = collect_texts(&mut rx, Duration::from_millis(200)).await;
+        assert!(network.link_stats().unwrap().dropped > 0);
+        assert_eq!(delivered, texts);
+    }
+
+    #[tokio::test]
+    async fn test_congested_link_delivers_large_text() {
+        let network = MemoryNetwork::with_link(LinkConditions {
+            drop_rate: 0.2,
+            reorder_rate: 0.1,
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+            ..LinkConditions::default()
+        }, 11);
+        let sender = start_listening_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let receiver = start_listening_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        connect(&sender, &receiver, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let mut rx = receiver.subscribe_text();
+
+        let text = "congestion ".repeat(10_000);
+        sender.send_text(&sender.registry().get_peers().await, &text).await.unwrap();
+
+        assert_eq!(collect_texts(&mut rx, Duration::from_millis(200)).await, vec![text]);
+        // Every chunk needed at least one datagram; losses forced some to be resent
+        let stats = network.link_stats().unwrap();
+        assert!(stats.dropped > 0);
+    }
+
+    #[tokio::test]
+    async fn test_lossy_multicast_is_repaired_for_every_receiver() {
+        let network = MemoryNetwork::with_link(LinkConditions { drop_rate: 0.1, ..LinkConditions::default() }, 36);
+        let sender = start_listening_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let mut receivers = Vec::new();
+        for host in 2..5 {
+            let ip = Ipv4Addr::new(10, 0, 0, host);
+            let receiver = start_listening_service(&network, ip).await;
+            connect(&sender, &receiver, ip).await;
+            let rx = receiver.subscribe_text();
+            receivers.push((receiver, rx));
+        }
+
+        let text = "multicast ".repeat(2_000);
+        let peers = sender.registry().get_peers().await;
+        let results = sender.send_text_with_mode(&peers, &text, SendMode::Multicast).await.unwrap();
+
+        assert!(results.iter().all(|result| result.status == SendStatus::Delivered));
+        assert!(network.link_stats().unwrap().dropped > 0);
+        for (_, rx) in &mut receivers {
+            assert_eq!(collect_texts(rx, Duration::from_millis(200)).await, vec![text.clone()]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_chunks_are_rejected_and_repaired() {
+        let network = MemoryNetwork::with_link(LinkConditions { corrupt_rate: 0.1, ..LinkConditions::default() }, 5);
+        let sender = start_listening_service(&network, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let receiver = start_listening_service(&network, Ipv4Addr::new(10, 0, 0, 2)).await;
+        connect(&sender, &receiver, Ipv4Addr::new(10, 0, 0, 2)).await;
+        let mut rx = receiver.subscribe_text();
+        let mut failed = receiver.subscribe_failed();
+
+        let text = "corrupt me ".repeat(300);
+        let results = sender.send_text(&sender.registry().get_peers().await, &text).await.unwrap();
+
+        assert!(network.link_stats().unwrap().corrupted > 0);
+        assert_eq!(results[0].status, SendStatus::Delivered);
+        assert_eq!(collect_texts(&mut rx, Duration::from_millis(100)).await, vec![text]);
+        // Damaged chunks were turned away on arrival, so reassembly never saw one
+        assert!(failed.try_recv().is_err());
+    }
+}
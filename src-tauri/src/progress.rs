@@ -0,0 +1,187 @@
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter};
+use tokio::sync::broadcast;
+
+use crate::history::Direction;
+
+/// Minimum time between progress events for one transfer (first and last chunk always report)
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+const PROGRESS_CHANNEL_CAPACITY: usize = 256;
+
+/// Payload of the `transfer-progress` event for one chunked message to or from one peer
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TransferProgress {
+    pub message_id: String,
+    /// The peer on the other end of the transfer
+    pub peer_id: String,
+    pub direction: Direction,
+    pub chunks_done: u32,
+    pub total_chunks: u32,
+    pub bytes_done: u64,
+    /// Only known to the sender; the receiver learns the size once reassembled
+    pub total_bytes: Option<u64>,
+    pub bytes_per_sec: f64,
+    /// Estimated seconds left, once at least one chunk has been measured
+    pub eta_secs: Option<f64>,
+}
+
+impl TransferProgress {
+    pub fn is_complete(&self) -> bool {
+        self.chunks_done >= self.total_chunks
+    }
+}
+
+/// Payload of the `transfer-cancelled` event, reported on both ends of a cancelled transfer
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TransferCancelled {
+    pub message_id: String,
+    pub peer_id: String,
+    pub direction: Direction,
+    /// Chunks that had been sent or received before the transfer stopped
+    pub chunks_done: u32,
+    pub total_chunks: u32,
+}
+
+/// Payload of the `transfer-failed` event: a received message that was rejected
+/// because it didn't pass its integrity checks
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TransferFailed {
+    pub message_id: String,
+    pub peer_id: String,
+    pub direction: Direction,
+    pub reason: String,
+}
+
+/// Transfer rate and remaining time, extrapolated from the chunks done so far
+pub fn estimate(chunks_done: u32, total_chunks: u32, bytes_done: u64, elapsed: Duration) -> (f64, Option<f64>) {
+    let secs = elapsed.as_secs_f64();
+    let bytes_per_sec = if secs > 0.0 { bytes_done as f64 / secs } else { 0.0 };
+    let eta_secs = if chunks_done == 0 {
+        None
+    } else if chunks_done >= total_chunks {
+        Some(0.0)
+    } else {
+        Some(secs * (total_chunks - chunks_done) as f64 / chunks_done as f64)
+    };
+    (bytes_per_sec, eta_secs)
+}
+
+/// Rate limits progress reports so large transfers don't flood the frontend
+#[derive(Debug, Default)]
+pub struct ProgressThrottle {
+    last_report: Option<Instant>,
+}
+
+impl ProgressThrottle {
+    /// Whether a report is due; the first and the final report always are
+    pub fn ready(&mut self, is_final: bool) -> bool {
+        let due = match self.last_report {
+            None => true,
+            Some(last) => is_final || last.elapsed() >= PROGRESS_INTERVAL,
+        };
+        if due {
+            self.last_report = Some(Instant::now());
+        }
+        due
+    }
+}
+
+/// Delivers progress, cancellations and failures to the frontend and any in-process subscribers
+#[derive(Clone)]
+pub struct ProgressReporter {
+    app_handle: Option<AppHandle>,
+    tx: broadcast::Sender<TransferProgress>,
+    cancelled_tx: broadcast::Sender<TransferCancelled>,
+    failed_tx: broadcast::Sender<TransferFailed>,
+}
+
+impl ProgressReporter {
+    pub fn new(app_handle: Option<AppHandle>) -> Self {
+        Self {
+            app_handle,
+            tx: broadcast::channel(PROGRESS_CHANNEL_CAPACITY).0,
+            cancelled_tx: broadcast::channel(PROGRESS_CHANNEL_CAPACITY).0,
+            failed_tx: broadcast::channel(PROGRESS_CHANNEL_CAPACITY).0,
+        }
+    }
+
+    /// Same channels, emitting to `app_handle` as well
+    pub fn with_app_handle(&self, app_handle: Option<AppHandle>) -> Self {
+        Self { app_handle, ..self.clone() }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TransferProgress> {
+        self.tx.subscribe()
+    }
+
+    pub fn subscribe_cancelled(&self) -> broadcast::Receiver<TransferCancelled> {
+        self.cancelled_tx.subscribe()
+    }
+
+    pub fn subscribe_failed(&self) -> broadcast::Receiver<TransferFailed> {
+        self.failed_tx.subscribe()
+    }
+
+    pub fn failed(&self, failed: TransferFailed) {
+        warn!("Transfer {} with peer {} ({:?}) failed: {}",
+              failed.message_id, failed.peer_id, failed.direction, failed.reason);
+        if let Some(app) = &self.app_handle {
+            let _ = app.emit("transfer-failed", &failed);
+        }
+        let _ = self.failed_tx.send(failed);
+    }
+
+    pub fn cancelled(&self, cancelled: TransferCancelled) {
+        info!("Transfer {} with peer {} ({:?}) cancelled after {}/{} chunks",
+              cancelled.message_id, cancelled.peer_id, cancelled.direction,
+              cancelled.chunks_done, cancelled.total_chunks);
+        if let Some(app) = &self.app_handle {
+            let _ = app.emit("transfer-cancelled", &cancelled);
+        }
+        let _ = self.cancelled_tx.send(cancelled);
+    }
+
+    pub fn report(&self, progress: TransferProgress) {
+        debug!("Transfer {} with peer {} ({:?}): {}/{} chunks, {} bytes",
+               progress.message_id, progress.peer_id, progress.direction,
+               progress.chunks_done, progress.total_chunks, progress.bytes_done);
+        if progress.is_complete() {
+            info!("Transfer {} with peer {} complete: {} bytes at {:.0} B/s",
+                  progress.message_id, progress.peer_id, progress.bytes_done, progress.bytes_per_sec);
+        }
+        if let Some(app) = &self.app_handle {
+            let _ = app.emit("transfer-progress", &progress);
+        }
+        let _ = self.tx.send(progress);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_rate_and_eta() {
+        let (rate, eta) = estimate(1, 4, 800, Duration::from_secs(2));
+        assert_eq!(rate, 400.0);
+        assert_eq!(eta, Some(6.0));
+
+        let (_, eta) = estimate(4, 4, 3200, Duration::from_secs(8));
+        assert_eq!(eta, Some(0.0));
+
+        let (rate, eta) = estimate(0, 4, 0, Duration::ZERO);
+        assert_eq!(rate, 0.0);
+        assert_eq!(eta, None);
+    }
+
+    #[test]
+    fn test_throttle_always_reports_first_and_final() {
+        let mut throttle = ProgressThrottle::default();
+        assert!(throttle.ready(false));
+        assert!(!throttle.ready(false));
+        assert!(throttle.ready(true));
+    }
+}
@@ -0,0 +1,398 @@
+//! Rate limiting for traffic arriving at the listener.
+//!
+//! Every datagram is first charged to a token bucket for its source address,
+//! before any work goes into decoding it. Once decoded, it is charged to a
+//! bucket for its address and one for the peer id it claims, per kind of
+//! traffic. Datagrams that find a bucket empty are dropped and counted; a
+//! source that keeps being over its limit is blocked outright for a while.
+//! That is the peer id when messages are authenticated, as several instances
+//! can share an address; without a key anyone can claim any peer id, so it is
+//! the address instead.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+// Drops within STRIKE_WINDOW after which a source is blocked
+const STRIKES_TO_BLOCK: u32 = 100;
+const STRIKE_WINDOW: Duration = Duration::from_secs(10);
+const BLOCK_DURATION: Duration = Duration::from_secs(60);
+// Sources not heard from for this long are forgotten
+const IDLE_EXPIRY: Duration = Duration::from_secs(60);
+// Instances one address may carry at full rate
+const INSTANCES_PER_ADDR: f64 = 4.0;
+
+/// Kinds of listener traffic, each with its own budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrafficClass {
+    /// Any datagram, before it is decoded
+    Datagram,
+    /// Peer announcements, normally one every two seconds
+    Discovery,
+    /// Texts and the control messages that go with them
+    Text,
+    /// Chunks of large texts, which arrive in bursts
+    Chunk,
+}
+
+impl TrafficClass {
+    /// Sustained messages per second, and the burst allowed on top, for one instance
+    fn limit(self) -> (f64, f64) {
+        match self {
+            // All of the others together
+            TrafficClass::Datagram => (3022.0, 1060.0),
+            TrafficClass::Discovery => (2.0, 10.0),
+            TrafficClass::Text => (20.0, 50.0),
+            TrafficClass::Chunk => (3000.0, 1000.0),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(burst: f64, now: Instant) -> Self {
+        Self { tokens: burst, updated: now }
+    }
+
+    /// Add what has accrued since the last refill; whether a message fits
+    fn refill(&mut self, (rate, burst): (f64, f64), now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+        self.tokens >= 1.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Source {
+    Addr(IpAddr),
+    Peer(String),
+}
+
+impl Source {
+    fn limit(&self, class: TrafficClass) -> (f64, f64) {
+        let (rate, burst) = class.limit();
+        match self {
+            Source::Addr(_) => (rate * INSTANCES_PER_ADDR, burst * INSTANCES_PER_ADDR),
+            Source::Peer(_) => (rate, burst),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SourceState {
+    buckets: HashMap<TrafficClass, TokenBucket>,
+    strikes: u32,
+    strikes_since: Instant,
+    blocked_until: Option<Instant>,
+    last_seen: Instant,
+}
+
+impl SourceState {
+    fn new(now: Instant) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            strikes: 0,
+            strikes_since: now,
+            blocked_until: None,
+            last_seen: now,
+        }
+    }
+
+    fn is_blocked(&self, now: Instant) -> bool {
+        self.blocked_until.is_some_and(|until| now < until)
+    }
+
+    /// Whether a `class` message fits, without charging it
+    fn has_room(&mut self, class: TrafficClass, limit: (f64, f64), now: Instant) -> bool {
+        self.last_seen = now;
+        let bucket = self.buckets.entry(class).or_insert_with(|| TokenBucket::full(limit.1, now));
+        bucket.refill(limit, now) && !self.is_blocked(now)
+    }
+
+    /// Charge a message `has_room` let through
+    fn charge(&mut self, class: TrafficClass) {
+        if let Some(bucket) = self.buckets.get_mut(&class) {
+            bucket.tokens -= 1.0;
+        }
+    }
+
+    /// Count a message over the limit; true if that got the source blocked
+    fn strike(&mut self, now: Instant) -> bool {
+        if now.saturating_duration_since(self.strikes_since) > STRIKE_WINDOW {
+            self.strikes = 0;
+            self.strikes_since = now;
+        }
+        self.strikes += 1;
+        if self.strikes < STRIKES_TO_BLOCK {
+            return false;
+        }
+        self.blocked_until = Some(now + BLOCK_DURATION);
+        self.strikes = 0;
+        true
+    }
+}
+
+/// Traffic dropped since startup and the sources blocked right now
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RateLimitStats {
+    pub dropped_datagrams: u64,
+    pub dropped_discovery: u64,
+    pub dropped_text: u64,
+    pub dropped_chunks: u64,
+    pub blocked_addrs: Vec<IpAddr>,
+    pub blocked_peers: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    sources: HashMap<Source, SourceState>,
+    dropped: HashMap<TrafficClass, u64>,
+}
+
+/// Token-bucket limits per source address and per peer id
+#[derive(Debug)]
+pub struct RateLimiter {
+    state: Mutex<LimiterState>,
+    clock: fn() -> Instant,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self { state: Mutex::default(), clock: Instant::now }
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A limiter that reads the time from `clock`
+    #[cfg(test)]
+    pub(crate) fn with_clock(clock: fn() -> Instant) -> Self {
+        Self { clock, ..Self::default() }
+    }
+
+    /// Whether a datagram arriving from `addr` may be decoded at all
+    pub fn check_addr(&self, addr: IpAddr) -> bool {
+        self.check_addr_at(addr, (self.clock)())
+    }
+
+    fn check_addr_at(&self, addr: IpAddr, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let source = Source::Addr(addr);
+        let limit = source.limit(TrafficClass::Datagram);
+        let source_state = state.sources.entry(source).or_insert_with(|| SourceState::new(now));
+        if source_state.has_room(TrafficClass::Datagram, limit, now) {
+            source_state.charge(TrafficClass::Datagram);
+            return true;
+        }
+        debug!("Dropping datagram from {}: over rate limit", addr);
+        *state.dropped.entry(TrafficClass::Datagram).or_insert(0) += 1;
+        false
+    }
+
+    /// Whether a `class` message claiming to be from `peer_id` and arriving
+    /// from `addr` may be processed; `authenticated` if the network key
+    /// vouches for the peer id
+    pub fn check(&self, addr: IpAddr, peer_id: &str, class: TrafficClass, authenticated: bool) -> bool {
+        self.check_at(addr, peer_id, class, authenticated, (self.clock)())
+    }
+
+    fn check_at(&self, addr: IpAddr, peer_id: &str, class: TrafficClass, authenticated: bool, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (addr_source, peer_source) = (Source::Addr(addr), Source::Peer(peer_id.to_string()));
+        let addr_room = state.sources.entry(addr_source.clone())
+            .or_insert_with(|| SourceState::new(now))
+            .has_room(class, addr_source.limit(class), now);
+        // An address over its limit gets no state kept for the ids it claims.
+        // Both must have room before either is charged, so what one refuses
+        // doesn't use up the other's budget.
+        let peer_room = addr_room && state.sources.entry(peer_source.clone())
+            .or_insert_with(|| SourceState::new(now))
+            .has_room(class, peer_source.limit(class), now);
+        if peer_room {
+            for source in [&addr_source, &peer_source] {
+                state.sources.get_mut(source).unwrap().charge(class);
+            }
+            return true;
+        }
+
+        // A peer id pays for going over its own limit only if it is vouched
+        // for; otherwise the address it came from does, or anyone could get
+        // another peer blocked by flooding in its name
+        let offender = match authenticated {
+            true if addr_room => Some(&peer_source),
+            true => None,
+            false => Some(&addr_source),
+        };
+        if let Some(offender) = offender {
+            let offender_state = state.sources.get_mut(offender).unwrap();
+            if !offender_state.is_blocked(now) && offender_state.strike(now) {
+                warn!("Blocking {:?} for {}s: {} messages over its limit", offender, BLOCK_DURATION.as_secs(), STRIKES_TO_BLOCK);
+            }
+        }
+        debug!("Dropping {:?} message from {} ({}): over rate limit", class, peer_id, addr);
+        *state.dropped.entry(class).or_insert(0) += 1;
+        false
+    }
+
+    /// Forget sources that went quiet and aren't blocked; returns how many
+    pub fn prune(&self) -> usize {
+        self.prune_at((self.clock)())
+    }
+
+    fn prune_at(&self, now: Instant) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let before = state.sources.len();
+        state.sources.retain(|_, source| {
+            source.is_blocked(now) || now.saturating_duration_since(source.last_seen) < IDLE_EXPIRY
+        });
+        before - state.sources.len()
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        let state = self.state.lock().unwrap();
+        let now = (self.clock)();
+        let dropped = |class| state.dropped.get(&class).copied().unwrap_or(0);
+        let mut stats = RateLimitStats {
+            dropped_datagrams: dropped(TrafficClass::Datagram),
+            dropped_discovery: dropped(TrafficClass::Discovery),
+            dropped_text: dropped(TrafficClass::Text),
+            dropped_chunks: dropped(TrafficClass::Chunk),
+            ..RateLimitStats::default()
+        };
+        for (source, source_state) in &state.sources {
+            if source_state.is_blocked(now) {
+                match source {
+                    Source::Addr(addr) => stats.blocked_addrs.push(*addr),
+                    Source::Peer(peer_id) => stats.blocked_peers.push(peer_id.clone()),
+                }
+            }
+        }
+        stats.blocked_addrs.sort();
+        stats.blocked_peers.sort();
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn test_bucket_allows_burst_then_refills() {
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+        let admitted = (0..20).filter(|_| limiter.check_at(ADDR, "peer", TrafficClass::Discovery, true, start)).count();
+        assert_eq!(admitted, 10);
+        assert_eq!(limiter.stats().dropped_discovery, 10);
+
+        // Two per second come back; other kinds of traffic have their own budget
+        assert!(limiter.check_at(ADDR, "peer", TrafficClass::Discovery, true, start + Duration::from_millis(600)));
+        assert!(!limiter.check_at(ADDR, "peer", TrafficClass::Discovery, true, start + Duration::from_millis(600)));
+        assert!(limiter.check_at(ADDR, "peer", TrafficClass::Text, true, start));
+    }
+
+    #[test]
+    fn test_limits_apply_per_address_and_per_peer() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        // Rotating peer ids from one address doesn't get around the address limit
+        let admitted = (0..200)
+            .filter(|i| limiter.check_at(ADDR, &format!("peer-{}", i), TrafficClass::Discovery, true, now))
+            .count();
+        assert_eq!(admitted, 10 * INSTANCES_PER_ADDR as usize);
+
+        // Nor does one peer id sent from many addresses
+        let admitted = (1..=20u8)
+            .filter(|i| limiter.check_at(IpAddr::V4(Ipv4Addr::new(10, 0, 1, *i)), "roaming", TrafficClass::Discovery, true, now))
+            .count();
+        assert_eq!(admitted, 10);
+    }
+
+    #[test]
+    fn test_address_over_its_limit_keeps_no_state_for_claimed_ids() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        for i in 0..1000 {
+            limiter.check_at(ADDR, &format!("peer-{}", i), TrafficClass::Discovery, true, now);
+        }
+        // The address and the ids it got through with
+        assert_eq!(limiter.state.lock().unwrap().sources.len(), 1 + 10 * INSTANCES_PER_ADDR as usize);
+    }
+
+    #[test]
+    fn test_refused_message_charges_neither_bucket() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        // What the peer bucket refuses leaves the address budget to others
+        let admitted = (0..100).filter(|_| limiter.check_at(ADDR, "greedy", TrafficClass::Discovery, true, now)).count();
+        assert_eq!(admitted, 10);
+        let admitted = (0..100).filter(|_| limiter.check_at(ADDR, "quiet", TrafficClass::Discovery, true, now)).count();
+        assert_eq!(admitted, 10);
+    }
+
+    #[test]
+    fn test_datagrams_are_limited_per_address_before_decoding() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        let burst = (TrafficClass::Datagram.limit().1 * INSTANCES_PER_ADDR) as usize;
+        let admitted = (0..burst + 100).filter(|_| limiter.check_addr_at(ADDR, now)).count();
+        assert_eq!(admitted, burst);
+        assert_eq!(limiter.stats().dropped_datagrams, 100);
+        assert!(limiter.check_addr_at(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), now));
+    }
+
+    #[test]
+    fn test_repeat_offender_is_blocked_temporarily() {
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+        for _ in 0..(10 + STRIKES_TO_BLOCK) {
+            limiter.check_at(ADDR, "flood", TrafficClass::Discovery, true, start);
+        }
+        assert_eq!(limiter.stats().blocked_peers, vec!["flood".to_string()]);
+
+        // Blocked even once the bucket has refilled, and for every kind of
+        // traffic, while another instance on the same address gets through
+        let later = start + Duration::from_secs(30);
+        assert!(!limiter.check_at(ADDR, "flood", TrafficClass::Discovery, true, later));
+        assert!(!limiter.check_at(ADDR, "flood", TrafficClass::Text, true, later));
+        assert!(limiter.check_at(ADDR, "other", TrafficClass::Discovery, true, later));
+        assert_eq!(limiter.prune_at(later), 0);
+
+        let after_block = start + BLOCK_DURATION + Duration::from_secs(1);
+        assert!(limiter.check_at(ADDR, "flood", TrafficClass::Discovery, true, after_block));
+        assert_eq!(limiter.prune_at(after_block + IDLE_EXPIRY), 3);
+    }
+
+    #[test]
+    fn test_unauthenticated_flood_blocks_its_address_not_the_claimed_peer() {
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+        for _ in 0..(10 + STRIKES_TO_BLOCK) {
+            limiter.check_at(ADDR, "victim", TrafficClass::Discovery, false, start);
+        }
+        let stats = limiter.stats();
+        assert_eq!(stats.blocked_addrs, vec![ADDR]);
+        assert!(stats.blocked_peers.is_empty());
+
+        // The real peer still gets through from its own address
+        let later = start + Duration::from_secs(30);
+        assert!(limiter.check_at(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), "victim", TrafficClass::Discovery, false, later));
+        assert!(!limiter.check_at(ADDR, "other", TrafficClass::Discovery, false, later));
+    }
+}
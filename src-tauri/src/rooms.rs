@@ -0,0 +1,149 @@
+use std::collections::BTreeSet;
+use std::sync::RwLock;
+
+use anyhow::Result;
+use log::info;
+
+/// Longest room name accepted, in characters
+pub const MAX_ROOM_NAME_CHARS: usize = 64;
+
+/// Trim `name` and check it is usable as a room name
+pub fn normalize_room_name(name: &str) -> Result<String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(anyhow::anyhow!("Room name must not be empty"));
+    }
+    if name.chars().count() > MAX_ROOM_NAME_CHARS {
+        return Err(anyhow::anyhow!("Room name too long (max {} characters)", MAX_ROOM_NAME_CHARS));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(anyhow::anyhow!("Room name must not contain control characters"));
+    }
+    Ok(name.to_string())
+}
+
+/// The named rooms this instance has joined
+///
+/// Rooms are advertised in every `PeerDiscovery` message. Text sent to a room
+/// is only delivered by peers that joined it; text sent without a room
+/// reaches everyone, as before rooms existed. The room joined last is the
+/// current one: sends to everyone, live mirroring and the shared text stay
+/// within it.
+#[derive(Debug, Default)]
+pub struct Rooms {
+    joined: RwLock<BTreeSet<String>>,
+    current: RwLock<Option<String>>,
+}
+
+impl Rooms {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Join `name` and make it the current room; returns the normalized name
+    pub fn join(&self, name: &str) -> Result<String> {
+        let name = normalize_room_name(name)?;
+        let mut joined = self.joined.write().unwrap();
+        if joined.insert(name.clone()) {
+            info!("Joined room {}", name);
+        }
+        *self.current.write().unwrap() = Some(name.clone());
+        Ok(name)
+    }
+
+    /// Leave `name`; false if it wasn't joined. Leaving the current room
+    /// makes another joined room current, if there is one.
+    pub fn leave(&self, name: &str) -> bool {
+        let mut joined = self.joined.write().unwrap();
+        let left = joined.remove(name.trim());
+        if left {
+            info!("Left room {}", name.trim());
+            let mut current = self.current.write().unwrap();
+            if current.as_deref() == Some(name.trim()) {
+                *current = joined.iter().next_back().cloned();
+            }
+        }
+        left
+    }
+
+    /// The room texts not addressed to anyone in particular are scoped to
+    pub fn current(&self) -> Option<String> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Whether a peer that joined `peer_rooms` is in our current room, or
+    /// neither of us uses rooms; only such peers share our text by default
+    pub fn shares_room(&self, peer_rooms: &BTreeSet<String>) -> bool {
+        match self.current() {
+            Some(room) => peer_rooms.contains(&room),
+            None => peer_rooms.is_empty(),
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.joined.read().unwrap().contains(name)
+    }
+
+    /// Whether text scoped to `room` should be delivered here
+    pub fn accepts(&self, room: Option<&str>) -> bool {
+        room.is_none_or(|room| self.contains(room))
+    }
+
+    pub fn joined(&self) -> BTreeSet<String> {
+        self.joined.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_and_leave_normalize_names() {
+        let rooms = Rooms::new();
+        assert_eq!(rooms.join("  design ").unwrap(), "design");
+        rooms.join("ops").unwrap();
+        assert_eq!(rooms.joined().into_iter().collect::<Vec<_>>(), vec!["design", "ops"]);
+
+        assert!(rooms.leave(" design"));
+        assert!(!rooms.leave("design"));
+        assert!(!rooms.contains("design"));
+
+        assert!(rooms.join("   ").is_err());
+        assert!(rooms.join(&"x".repeat(MAX_ROOM_NAME_CHARS + 1)).is_err());
+        assert!(rooms.join("tab\there").is_err());
+    }
+
+    #[test]
+    fn test_last_joined_room_is_current() {
+        let rooms = Rooms::new();
+        let peer = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<BTreeSet<_>>();
+        assert_eq!(rooms.current(), None);
+        assert!(rooms.shares_room(&peer(&[])));
+        assert!(!rooms.shares_room(&peer(&["design"])));
+
+        rooms.join("design").unwrap();
+        rooms.join("ops").unwrap();
+        assert_eq!(rooms.current().as_deref(), Some("ops"));
+        assert!(rooms.shares_room(&peer(&["ops", "qa"])));
+        assert!(!rooms.shares_room(&peer(&["design"])));
+        assert!(!rooms.shares_room(&peer(&[])));
+
+        // Joining again switches back; leaving the current room falls back to another
+        rooms.join("design").unwrap();
+        assert_eq!(rooms.current().as_deref(), Some("design"));
+        rooms.leave("design");
+        assert_eq!(rooms.current().as_deref(), Some("ops"));
+        rooms.leave("ops");
+        assert_eq!(rooms.current(), None);
+    }
+
+    #[test]
+    fn test_accepts_unscoped_and_joined_rooms_only() {
+        let rooms = Rooms::new();
+        rooms.join("design").unwrap();
+        assert!(rooms.accepts(None));
+        assert!(rooms.accepts(Some("design")));
+        assert!(!rooms.accepts(Some("ops")));
+    }
+}
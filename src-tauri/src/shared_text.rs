@@ -0,0 +1,619 @@
+//! Conflict-free shared text.
+//!
+//! The shared text is a replicated growable array (RGA): every character has
+//! a unique id and remembers the character it was typed after, and deleted
+//! characters stay behind as tombstones. Concurrent inserts at the same spot
+//! are ordered by id, so replicas that applied the same operations hold the
+//! same text whatever order the operations arrived in.
+//!
+//! Each replica numbers its own operations 1, 2, 3, ...; the highest number
+//! applied per replica is its version vector, which peers compare to find
+//! and resend operations the other side missed.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::mirror::TextDelta;
+
+// Longest run of characters in one insert operation
+const MAX_RUN_CHARS: usize = 16 * 1024;
+// Views handed to the frontend that an edit may still be based on
+const RECENT_VIEWS: usize = 16;
+// Highest character counter accepted from a peer, leaving our clock room to grow
+const MAX_COUNTER: u64 = u64::MAX / 2;
+// Operations from one replica held back waiting for earlier ones
+const MAX_PENDING_PER_ORIGIN: usize = 1024;
+// Operations sent to a peer per catch-up; it asks for the rest with its next
+// advertised version vector
+pub const MAX_CATCH_UP_OPS: usize = 1024;
+
+/// Highest operation number applied from each replica
+pub type VersionVector = HashMap<String, u64>;
+
+/// Unique id of one character; ordered by Lamport counter, then replica
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct CharId {
+    pub counter: u64,
+    pub replica: String,
+}
+
+/// Consecutive character ids from one replica
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdRange {
+    pub replica: String,
+    pub start: u64,
+    pub len: u64,
+}
+
+impl IdRange {
+    /// Whether the range is non-empty and its counters don't overflow
+    fn is_valid(&self) -> bool {
+        self.len > 0 && self.start.checked_add(self.len).is_some_and(|end| end <= MAX_COUNTER)
+    }
+
+    fn contains(&self, id: &CharId) -> bool {
+        id.replica == self.replica && id.counter >= self.start && id.counter - self.start < self.len
+    }
+
+    fn last(&self) -> u64 {
+        self.start + self.len - 1
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Op {
+    /// `text` typed after `left` (None: at the very start); character `i`
+    /// of the run gets counter `id.counter + i`
+    Insert { id: CharId, left: Option<CharId>, text: String },
+    Delete { ranges: Vec<IdRange> },
+}
+
+/// An operation with the replica that made it and its number there
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StampedOp {
+    pub origin: String,
+    pub seq: u64,
+    pub op: Op,
+}
+
+impl StampedOp {
+    /// Why an operation received from a peer can't be applied, if it can't
+    fn check(&self) -> Result<()> {
+        match &self.op {
+            Op::Insert { id, text, .. } => {
+                let len = text.chars().count() as u64;
+                if id.replica != self.origin {
+                    return Err(anyhow::anyhow!("insert by {} claims ids of {}", self.origin, id.replica));
+                }
+                if len == 0 || len > MAX_RUN_CHARS as u64 {
+                    return Err(anyhow::anyhow!("insert of {} characters", len));
+                }
+                if id.counter == 0 || id.counter.checked_add(len).is_none_or(|end| end > MAX_COUNTER) {
+                    return Err(anyhow::anyhow!("insert at counter {} is out of range", id.counter));
+                }
+            }
+            Op::Delete { ranges } => {
+                if let Some(range) = ranges.iter().find(|range| !range.is_valid()) {
+                    return Err(anyhow::anyhow!("delete of {} characters from {}", range.len, range.start));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Element {
+    id: CharId,
+    ch: char,
+    deleted: bool,
+}
+
+/// The replicated character sequence
+#[derive(Debug, Default)]
+pub struct Sequence {
+    replica: String,
+    clock: u64,
+    elements: Vec<Element>,
+    applied: VersionVector,
+    /// Highest character counter integrated per replica; a replica's
+    /// counters only grow, so every id up to it is present
+    max_counter: HashMap<String, u64>,
+    log: Vec<StampedOp>,
+    /// Remote operations waiting for ones they depend on
+    pending: Vec<StampedOp>,
+}
+
+impl Sequence {
+    #[allow(dead_code)]
+    pub fn new(replica: &str) -> Self {
+        Self { replica: replica.to_string(), ..Self::default() }
+    }
+
+    pub fn text(&self) -> String {
+        self.elements.iter().filter(|e| !e.deleted).map(|e| e.ch).collect()
+    }
+
+    fn visible_ids(&self) -> Vec<CharId> {
+        self.elements.iter().filter(|e| !e.deleted).map(|e| e.id.clone()).collect()
+    }
+
+    pub fn version_vector(&self) -> VersionVector {
+        self.applied.clone()
+    }
+
+    /// Operations the holder of `seen` hasn't applied yet
+    pub fn ops_since(&self, seen: &VersionVector) -> Vec<StampedOp> {
+        self.log.iter()
+            .filter(|op| op.seq > seen.get(&op.origin).copied().unwrap_or(0))
+            .cloned()
+            .collect()
+    }
+
+    fn has_char(&self, id: &CharId) -> bool {
+        self.max_counter.get(&id.replica).is_some_and(|max| id.counter <= *max)
+    }
+
+    fn is_ready(&self, op: &StampedOp) -> bool {
+        if op.seq != self.applied.get(&op.origin).copied().unwrap_or(0) + 1 {
+            return false;
+        }
+        match &op.op {
+            Op::Insert { left, .. } => left.as_ref().is_none_or(|left| self.has_char(left)),
+            Op::Delete { ranges } => ranges.iter().all(|range| {
+                self.max_counter.get(&range.replica).is_some_and(|max| range.last() <= *max)
+            }),
+        }
+    }
+
+    fn integrate(&mut self, op: StampedOp) {
+        match &op.op {
+            Op::Insert { id, left, text } => {
+                let mut index = match left {
+                    Some(left) => self.elements.iter().position(|e| e.id == *left).map_or(0, |i| i + 1),
+                    None => 0,
+                };
+                // Later concurrent inserts at the same spot (and whatever was
+                // typed after them) come first
+                while index < self.elements.len() && self.elements[index].id > *id {
+                    index += 1;
+                }
+                let run: Vec<Element> = text.chars().enumerate().map(|(i, ch)| Element {
+                    id: CharId { counter: id.counter + i as u64, replica: id.replica.clone() },
+                    ch,
+                    deleted: false,
+                }).collect();
+                let last = id.counter + run.len() as u64 - 1;
+                self.elements.splice(index..index, run);
+                self.clock = self.clock.max(last);
+                let max = self.max_counter.entry(id.replica.clone()).or_insert(0);
+                *max = (*max).max(last);
+            }
+            Op::Delete { ranges } => {
+                for element in self.elements.iter_mut().filter(|e| !e.deleted) {
+                    if ranges.iter().any(|range| range.contains(&element.id)) {
+                        element.deleted = true;
+                    }
+                }
+            }
+        }
+        self.applied.insert(op.origin.clone(), op.seq);
+        self.log.push(op);
+    }
+
+    /// Apply operations from other replicas, in any order and possibly more
+    /// than once; returns whether the text changed
+    pub fn apply_remote(&mut self, ops: Vec<StampedOp>) -> bool {
+        let before = self.text();
+        for op in ops {
+            if let Err(e) = op.check() {
+                warn!("Dropping malformed shared-text operation {} from {}: {}", op.seq, op.origin, e);
+                continue;
+            }
+            let applied = self.applied.get(&op.origin).copied().unwrap_or(0);
+            if op.seq <= applied || self.pending.iter().any(|p| p.origin == op.origin && p.seq == op.seq) {
+                continue;
+            }
+            // The next operation always gets in so the queue can drain; what
+            // doesn't fit is sent again in a later catch-up
+            if op.seq != applied + 1
+                && self.pending.iter().filter(|p| p.origin == op.origin).count() >= MAX_PENDING_PER_ORIGIN
+            {
+                debug!("Too many shared-text operations from {} waiting; dropping {}", op.origin, op.seq);
+                continue;
+            }
+            self.pending.push(op);
+        }
+        while let Some(index) = self.pending.iter().position(|op| self.is_ready(op)) {
+            let op = self.pending.swap_remove(index);
+            self.integrate(op);
+        }
+        if !self.pending.is_empty() {
+            debug!("{} shared-text operations waiting for earlier ones", self.pending.len());
+        }
+        self.text() != before
+    }
+
+    fn stamp(&mut self, op: Op) -> StampedOp {
+        let seq = self.applied.get(&self.replica).copied().unwrap_or(0) + 1;
+        let op = StampedOp { origin: self.replica.clone(), seq, op };
+        self.integrate(op.clone());
+        op
+    }
+
+    /// Record a local edit that turned `base_text` (whose characters have
+    /// `base_ids`) into `new_text`; returns the operations to send to peers
+    ///
+    /// Positions come from the base the user saw, so edits that arrived from
+    /// other replicas in the meantime are kept.
+    fn edit(&mut self, base_ids: &[CharId], base_text: &str, new_text: &str) -> Vec<StampedOp> {
+        let delta = TextDelta::between(base_text, new_text);
+        let start = base_text[..delta.start].chars().count();
+        let deleted = base_text[delta.start..delta.start + delta.delete_len].chars().count();
+        let mut ops = Vec::new();
+
+        if deleted > 0 {
+            let mut ranges: Vec<IdRange> = Vec::new();
+            for id in &base_ids[start..start + deleted] {
+                match ranges.last_mut() {
+                    Some(range) if range.replica == id.replica && range.start + range.len == id.counter => range.len += 1,
+                    _ => ranges.push(IdRange { replica: id.replica.clone(), start: id.counter, len: 1 }),
+                }
+            }
+            ops.push(self.stamp(Op::Delete { ranges }));
+        }
+
+        let mut left = start.checked_sub(1).map(|i| base_ids[i].clone());
+        let chars: Vec<char> = delta.insert.chars().collect();
+        for run in chars.chunks(MAX_RUN_CHARS) {
+            let id = CharId { counter: self.clock + 1, replica: self.replica.clone() };
+            let run_end = CharId { counter: id.counter + run.len() as u64 - 1, replica: id.replica.clone() };
+            ops.push(self.stamp(Op::Insert { id, left: left.take(), text: run.iter().collect() }));
+            left = Some(run_end);
+        }
+        ops
+    }
+}
+
+/// Serialize `ops` into JSON arrays of at most `max_bytes` each
+pub fn encode_batches(ops: &[StampedOp], max_bytes: usize) -> Result<Vec<String>> {
+    let mut batches = Vec::new();
+    let mut current: Vec<String> = Vec::new();
+    let mut current_len = 2;
+    for op in ops {
+        let encoded = serde_json::to_string(op)?;
+        if !current.is_empty() && current_len + encoded.len() + 1 > max_bytes {
+            batches.push(format!("[{}]", current.join(",")));
+            current.clear();
+            current_len = 2;
+        }
+        current_len += encoded.len() + 1;
+        current.push(encoded);
+    }
+    if !current.is_empty() {
+        batches.push(format!("[{}]", current.join(",")));
+    }
+    Ok(batches)
+}
+
+/// What the frontend shows: the text at a view version its next edit refers to
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SharedTextView {
+    pub version: u64,
+    pub text: String,
+}
+
+#[derive(Debug)]
+struct View {
+    version: u64,
+    ids: Vec<CharId>,
+    text: String,
+}
+
+#[derive(Debug, Default)]
+struct DocState {
+    sequence: Sequence,
+    views: VecDeque<View>,
+    next_view: u64,
+    /// Peers that take part in the shared text
+    collaborators: HashSet<String>,
+    /// Peers currently being sent operations they missed
+    syncing: HashSet<String>,
+    /// Version vector each peer advertised when it was last sent a catch-up
+    caught_up: HashMap<String, VersionVector>,
+}
+
+impl DocState {
+    fn current_view(&mut self) -> SharedTextView {
+        let ids = self.sequence.visible_ids();
+        if let Some(view) = self.views.back().filter(|view| view.ids == ids) {
+            return SharedTextView { version: view.version, text: view.text.clone() };
+        }
+        self.next_view += 1;
+        let text = self.sequence.text();
+        self.views.push_back(View { version: self.next_view, ids, text: text.clone() });
+        if self.views.len() > RECENT_VIEWS {
+            self.views.pop_front();
+        }
+        SharedTextView { version: self.next_view, text }
+    }
+}
+
+/// The shared text of this instance, safe to use from the listener and commands
+#[derive(Debug)]
+pub struct SharedDoc {
+    state: Mutex<DocState>,
+    tx: watch::Sender<SharedTextView>,
+}
+
+impl Default for SharedDoc {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(DocState::default()),
+            tx: watch::channel(SharedTextView::default()).0,
+        }
+    }
+}
+
+impl SharedDoc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Name the replica once the peer id is known; only before any local edit
+    pub fn set_replica(&self, replica: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.sequence.applied.contains_key(&state.sequence.replica) {
+            warn!("Shared text already has local edits; keeping replica {}", state.sequence.replica);
+            return;
+        }
+        state.sequence.replica = replica.to_string();
+    }
+
+    pub fn view(&self) -> SharedTextView {
+        self.state.lock().unwrap().current_view()
+    }
+
+    /// Apply the user's edit from the text at `base_version` to `text`
+    ///
+    /// Returns the view the frontend should show next (it differs from `text`
+    /// if peers edited concurrently) and the operations to send to peers.
+    pub fn edit(&self, base_version: u64, text: &str) -> Result<(SharedTextView, Vec<StampedOp>)> {
+        let mut state = self.state.lock().unwrap();
+        if state.sequence.replica.is_empty() {
+            return Err(anyhow::anyhow!("Shared text is not ready yet"));
+        }
+        if !state.views.iter().any(|view| view.version == base_version) {
+            warn!("Shared text view {} expired, applying edit to the current text", base_version);
+            state.current_view();
+        }
+        let index = state.views.iter().rposition(|view| view.version == base_version)
+            .unwrap_or(state.views.len() - 1);
+        let View { ids, text: base_text, .. } = &state.views[index];
+        let (ids, base_text) = (ids.clone(), base_text.clone());
+        let ops = state.sequence.edit(&ids, &base_text, text);
+        let view = state.current_view();
+        drop(state);
+        self.tx.send_replace(view.clone());
+        Ok((view, ops))
+    }
+
+    /// Apply operations received from `from`; returns the new view if the text changed
+    pub fn apply_remote(&self, from: &str, ops: Vec<StampedOp>) -> Option<SharedTextView> {
+        let mut state = self.state.lock().unwrap();
+        state.collaborators.insert(from.to_string());
+        if !state.sequence.apply_remote(ops) {
+            return None;
+        }
+        let view = state.current_view();
+        drop(state);
+        self.tx.send_replace(view.clone());
+        Some(view)
+    }
+
+    pub fn version_vector(&self) -> VersionVector {
+        self.state.lock().unwrap().sequence.version_vector()
+    }
+
+    /// Operations the holder of `seen` hasn't applied yet, oldest first and at
+    /// most `MAX_CATCH_UP_OPS`; each depends only on earlier ones or on ones
+    /// already seen, so the peer can apply them all
+    pub fn ops_since(&self, seen: &VersionVector) -> Vec<StampedOp> {
+        let mut ops = self.state.lock().unwrap().sequence.ops_since(seen);
+        ops.truncate(MAX_CATCH_UP_OPS);
+        ops
+    }
+
+    pub fn add_collaborator(&self, peer_id: &str) {
+        self.state.lock().unwrap().collaborators.insert(peer_id.to_string());
+    }
+
+    pub fn collaborators(&self) -> HashSet<String> {
+        self.state.lock().unwrap().collaborators.clone()
+    }
+
+    /// Mark a catch-up to `peer_id`, which advertised `seen`, as running;
+    /// false if one already is, or if the peer got one for `seen` already and
+    /// didn't advance, as it would just drop the same operations again
+    pub fn begin_sync(&self, peer_id: &str, seen: &VersionVector) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.caught_up.get(peer_id) == Some(seen) || !state.syncing.insert(peer_id.to_string()) {
+            return false;
+        }
+        state.caught_up.insert(peer_id.to_string(), seen.clone());
+        true
+    }
+
+    /// Mark the catch-up to `peer_id` as done; one that didn't reach it may be tried again
+    pub fn end_sync(&self, peer_id: &str, delivered: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.syncing.remove(peer_id);
+        if !delivered {
+            state.caught_up.remove(peer_id);
+        }
+    }
+
+    /// Watch the text as edits from either side are applied
+    #[allow(dead_code)]
+    pub fn subscribe(&self) -> watch::Receiver<SharedTextView> {
+        self.tx.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Edit `sequence` as if the user changed its current text to `text`
+    fn type_text(sequence: &mut Sequence, text: &str) -> Vec<StampedOp> {
+        let (ids, base) = (sequence.visible_ids(), sequence.text());
+        sequence.edit(&ids, &base, text)
+    }
+
+    #[test]
+    fn test_concurrent_inserts_converge_in_any_order() {
+        let mut a = Sequence::new("a");
+        let mut b = Sequence::new("b");
+        let base = type_text(&mut a, "hello world");
+        b.apply_remote(base);
+
+        let from_a = type_text(&mut a, "hello brave world");
+        let from_b = type_text(&mut b, "hello new world");
+        let mut c = Sequence::new("c");
+        c.apply_remote(from_b.iter().rev().cloned().collect());
+        c.apply_remote(a.ops_since(&VersionVector::new()));
+        a.apply_remote(from_b);
+        b.apply_remote(from_a);
+
+        assert_eq!(a.text(), b.text());
+        assert_eq!(a.text(), c.text());
+        assert_eq!(a.text(), "hello new brave world");
+    }
+
+    #[test]
+    fn test_out_of_order_and_duplicate_ops_wait_for_dependencies() {
+        let mut a = Sequence::new("a");
+        let mut ops = type_text(&mut a, "one");
+        ops.extend(type_text(&mut a, "one two"));
+        ops.extend(type_text(&mut a, "two"));
+
+        let mut b = Sequence::new("b");
+        assert!(!b.apply_remote(vec![ops[2].clone(), ops[1].clone()]));
+        assert_eq!(b.text(), "");
+        assert!(b.apply_remote(ops.clone()));
+        assert!(!b.apply_remote(ops));
+        assert_eq!(b.text(), "two");
+        assert_eq!(b.version_vector(), a.version_vector());
+    }
+
+    #[test]
+    fn test_edit_from_stale_view_keeps_concurrent_changes() {
+        let doc = SharedDoc::new();
+        doc.set_replica("a");
+        let start = doc.view();
+        let (typed, _) = doc.edit(start.version, "shared notes").unwrap();
+
+        let mut b = Sequence::new("b");
+        b.apply_remote(doc.ops_since(&VersionVector::new()));
+        let remote = type_text(&mut b, "shared notes, from b");
+        assert_eq!(doc.apply_remote("b", remote).unwrap().text, "shared notes, from b");
+        assert!(doc.collaborators().contains("b"));
+
+        // The user typed at the start before seeing b's edit
+        let (merged, ops) = doc.edit(typed.version, "my shared notes").unwrap();
+        assert_eq!(merged.text, "my shared notes, from b");
+        assert!(b.apply_remote(ops));
+        assert_eq!(b.text(), merged.text);
+    }
+
+    #[test]
+    fn test_concurrent_delete_and_insert_converge() {
+        let mut a = Sequence::new("a");
+        let mut b = Sequence::new("b");
+        b.apply_remote(type_text(&mut a, "abcdef"));
+
+        let delete = type_text(&mut a, "af");
+        assert!(matches!(&delete[0].op, Op::Delete { ranges } if ranges.len() == 1 && ranges[0].len == 4));
+        let insert = type_text(&mut b, "abcXdef");
+        a.apply_remote(insert);
+        b.apply_remote(delete);
+
+        assert_eq!(a.text(), "aXf");
+        assert_eq!(b.text(), "aXf");
+    }
+
+    #[test]
+    fn test_malformed_remote_ops_are_dropped() {
+        let mut b = Sequence::new("b");
+        let op = |seq, op| StampedOp { origin: "a".to_string(), seq, op };
+        let id = |counter| CharId { counter, replica: "a".to_string() };
+        let delete = |start, len| Op::Delete { ranges: vec![IdRange { replica: "a".to_string(), start, len }] };
+        let insert = |counter, text: &str| Op::Insert { id: id(counter), left: None, text: text.to_string() };
+        assert!(!b.apply_remote(vec![
+            op(1, delete(0, 0)),
+            op(1, delete(u64::MAX, 2)),
+            op(1, insert(u64::MAX - 1, "overflow")),
+            op(1, Op::Insert { id: CharId { counter: 1, replica: "c".to_string() }, left: None, text: "x".to_string() }),
+        ]));
+        assert!(b.pending.is_empty());
+
+        // Well-formed operations still apply afterwards
+        assert!(b.apply_remote(vec![op(1, insert(1, "ok"))]));
+        assert_eq!(b.text(), "ok");
+    }
+
+    #[test]
+    fn test_pending_ops_are_capped_per_origin() {
+        let mut a = Sequence::new("a");
+        let mut ops = Vec::new();
+        for i in 0..MAX_PENDING_PER_ORIGIN + 10 {
+            ops.extend(type_text(&mut a, &"x".repeat(i + 1)));
+        }
+        // Without the first operation nothing can apply, and only so much waits
+        let mut b = Sequence::new("b");
+        b.apply_remote(ops[1..].to_vec());
+        assert_eq!(b.pending.len(), MAX_PENDING_PER_ORIGIN);
+        b.apply_remote(vec![ops[0].clone()]);
+        b.apply_remote(a.ops_since(&b.version_vector()));
+        assert_eq!(b.text(), a.text());
+    }
+
+    #[test]
+    fn test_catch_up_is_bounded_and_applies_in_rounds() {
+        let doc = SharedDoc::new();
+        doc.set_replica("a");
+        for i in 0..MAX_CATCH_UP_OPS + 10 {
+            doc.edit(doc.view().version, &"x".repeat(i + 1)).unwrap();
+        }
+        let mut b = Sequence::new("b");
+        let first = doc.ops_since(&b.version_vector());
+        assert_eq!(first.len(), MAX_CATCH_UP_OPS);
+        b.apply_remote(first);
+        assert!(b.pending.is_empty());
+        b.apply_remote(doc.ops_since(&b.version_vector()));
+        assert_eq!(b.text(), doc.view().text);
+    }
+
+    #[test]
+    fn test_encode_batches_respects_size() {
+        let mut a = Sequence::new("a");
+        let mut ops = Vec::new();
+        for i in 0..20 {
+            let text = format!("{}{}", a.text(), i);
+            ops.extend(type_text(&mut a, &text));
+        }
+        let batches = encode_batches(&ops, 400).unwrap();
+        assert!(batches.len() > 1);
+        assert!(batches.iter().all(|batch| batch.len() <= 400));
+        let decoded: Vec<StampedOp> = batches.iter()
+            .flat_map(|batch| serde_json::from_str::<Vec<StampedOp>>(batch).unwrap())
+            .collect();
+        assert_eq!(decoded, ops);
+    }
+}
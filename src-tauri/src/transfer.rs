@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+// Send window limits, in chunks in flight
+const INITIAL_WINDOW: f64 = 4.0;
+const MIN_WINDOW: f64 = 2.0;
+const MAX_WINDOW: f64 = 256.0;
+// Retransmission timeout bounds
+const MIN_RTO: Duration = Duration::from_millis(20);
+const MAX_RTO: Duration = Duration::from_secs(1);
+const INITIAL_RTO: Duration = Duration::from_millis(200);
+
+/// How a text reaches several recipients
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SendMode {
+    /// A separate transfer to each recipient
+    #[default]
+    Unicast,
+    /// Chunks sent once to a multicast group; receivers ask for what they missed
+    Multicast,
+}
+
+/// How sending a text to one recipient ended
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SendStatus {
+    /// Every chunk was acknowledged
+    Delivered,
+    /// Single-packet message handed to the network; these are not acknowledged
+    Sent,
+    Cancelled,
+    Failed,
+    /// The user blocked the peer; nothing was sent
+    Blocked,
+}
+
+impl SendStatus {
+    /// Whether the recipient got (or most likely got) the whole text
+    pub fn reached_peer(self) -> bool {
+        matches!(self, SendStatus::Delivered | SendStatus::Sent)
+    }
+}
+
+/// Outcome of sending a text to one recipient
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PeerSendResult {
+    pub peer_id: String,
+    pub status: SendStatus,
+    pub chunks_confirmed: u32,
+    pub total_chunks: u32,
+    pub error: Option<String>,
+}
+
+/// Cancellation flag for one outgoing transfer, checked by the send loop between chunks
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn is_same(&self, other: &CancelToken) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Outgoing chunked transfers that are still being sent, keyed by message id
+///
+/// Shared outside the discovery service so a transfer can be cancelled while
+/// the service is busy sending it.
+#[derive(Debug, Default)]
+pub struct ActiveTransfers {
+    transfers: Mutex<HashMap<String, CancelToken>>,
+}
+
+impl ActiveTransfers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track a transfer until `finish` is called; returns the token the send loop polls
+    pub fn register(&self, message_id: &str) -> CancelToken {
+        let token = CancelToken::default();
+        self.transfers.lock().unwrap().insert(message_id.to_string(), token.clone());
+        token
+    }
+
+    pub fn finish(&self, message_id: &str) {
+        self.transfers.lock().unwrap().remove(message_id);
+    }
+
+    /// Ask the send loop for `message_id` to stop; false if no such transfer is running
+    pub fn cancel(&self, message_id: &str) -> bool {
+        match self.transfers.lock().unwrap().get(message_id) {
+            Some(token) => {
+                info!("Cancelling transfer {}", message_id);
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Message ids of transfers currently being sent
+    #[allow(dead_code)]
+    pub fn active(&self) -> Vec<String> {
+        self.transfers.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+/// Latest-wins outbound slot per peer for updates that replace each other
+///
+/// Claiming a peer's slot cancels the update claimed before it, whether it
+/// is still waiting to be sent or partway through, so a busy link only
+/// carries the newest state.
+#[derive(Debug, Default)]
+pub struct OutboundQueue {
+    latest: Mutex<HashMap<String, CancelToken>>,
+}
+
+impl OutboundQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claim the slots of `peer_ids`; returns the token each send polls
+    pub fn supersede<'a>(&self, peer_ids: impl IntoIterator<Item = &'a str>) -> HashMap<String, CancelToken> {
+        let mut latest = self.latest.lock().unwrap();
+        peer_ids.into_iter().map(|peer_id| {
+            let token = CancelToken::default();
+            if let Some(older) = latest.insert(peer_id.to_string(), token.clone()) {
+                if !older.is_cancelled() {
+                    debug!("Superseding the pending update to {}", peer_id);
+                    older.cancel();
+                }
+            }
+            (peer_id.to_string(), token)
+        }).collect()
+    }
+
+    /// Release the slots claimed by `tokens` that no newer update took over
+    pub fn finish(&self, tokens: &HashMap<String, CancelToken>) {
+        let mut latest = self.latest.lock().unwrap();
+        for (peer_id, token) in tokens {
+            if latest.get(peer_id).is_some_and(|current| current.is_same(token)) {
+                latest.remove(peer_id);
+            }
+        }
+    }
+}
+
+/// Congestion control for one chunked transfer to one peer
+///
+/// The window of unacknowledged chunks grows by one per ACK until the first
+/// loss (slow start), then by one per round trip; each loss event halves it,
+/// at most once per round trip. Fast links therefore fill up quickly while a
+/// lossy link backs off instead of adding to its own congestion.
+#[derive(Debug)]
+pub struct RateController {
+    window: f64,
+    slow_start_threshold: f64,
+    smoothed_rtt: Option<Duration>,
+    rtt_variance: Duration,
+    last_decrease: Option<Instant>,
+    sent: u64,
+    lost: u64,
+}
+
+impl Default for RateController {
+    fn default() -> Self {
+        Self {
+            window: INITIAL_WINDOW,
+            slow_start_threshold: MAX_WINDOW,
+            smoothed_rtt: None,
+            rtt_variance: Duration::ZERO,
+            last_decrease: None,
+            sent: 0,
+            lost: 0,
+        }
+    }
+}
+
+impl RateController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Chunks that may be in flight right now
+    pub fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    pub fn on_send(&mut self) {
+        self.sent += 1;
+    }
+
+    /// A chunk was acknowledged; `rtt` is only given for chunks sent once
+    pub fn on_ack(&mut self, rtt: Option<Duration>) {
+        if let Some(sample) = rtt {
+            self.update_rtt(sample);
+        }
+        if self.window < self.slow_start_threshold {
+            self.window += 1.0;
+        } else {
+            self.window += 1.0 / self.window;
+        }
+        self.window = self.window.min(MAX_WINDOW);
+    }
+
+    /// A chunk was reported missing or timed out
+    pub fn on_loss(&mut self) {
+        self.lost += 1;
+        // Losses from one window are one congestion event
+        let now = Instant::now();
+        if self.last_decrease.is_some_and(|at| now.duration_since(at) < self.rtt()) {
+            return;
+        }
+        self.last_decrease = Some(now);
+        self.slow_start_threshold = (self.window / 2.0).max(MIN_WINDOW);
+        self.window = self.slow_start_threshold;
+        debug!("Loss detected, send window reduced to {:.1} (loss rate {:.1}%)", self.window, self.loss_rate() * 100.0);
+    }
+
+    /// Time after which an unacknowledged chunk is considered lost
+    pub fn retransmit_timeout(&self) -> Duration {
+        match self.smoothed_rtt {
+            Some(srtt) => (srtt + self.rtt_variance * 4).clamp(MIN_RTO, MAX_RTO),
+            None => INITIAL_RTO,
+        }
+    }
+
+    /// Fraction of sent chunks that were lost
+    pub fn loss_rate(&self) -> f64 {
+        if self.sent == 0 {
+            0.0
+        } else {
+            self.lost as f64 / self.sent as f64
+        }
+    }
+
+    fn rtt(&self) -> Duration {
+        self.smoothed_rtt.unwrap_or(INITIAL_RTO)
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.smoothed_rtt {
+            None => {
+                self.smoothed_rtt = Some(sample);
+                self.rtt_variance = sample / 2;
+            }
+            Some(srtt) => {
+                let deviation = srtt.abs_diff(sample);
+                self.rtt_variance = (self.rtt_variance * 3 + deviation) / 4;
+                self.smoothed_rtt = Some((srtt * 7 + sample) / 8);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_only_affects_registered_transfer() {
+        let transfers = ActiveTransfers::new();
+        let first = transfers.register("first");
+        let second = transfers.register("second");
+
+        assert!(transfers.cancel("first"));
+        assert!(first.is_cancelled());
+        assert!(!second.is_cancelled());
+        assert!(!transfers.cancel("unknown"));
+
+        transfers.finish("first");
+        assert_eq!(transfers.active(), vec!["second".to_string()]);
+        assert!(!transfers.cancel("first"));
+    }
+
+    #[test]
+    fn test_newer_update_supersedes_older_per_peer() {
+        let queue = OutboundQueue::new();
+        let first = queue.supersede(["a", "b"]);
+        let second = queue.supersede(["a"]);
+        assert!(first["a"].is_cancelled());
+        assert!(!first["b"].is_cancelled());
+        assert!(!second["a"].is_cancelled());
+
+        // Finishing the older update doesn't free the slot the newer one holds
+        queue.finish(&first);
+        assert_eq!(queue.latest.lock().unwrap().len(), 1);
+        queue.finish(&second);
+        assert!(queue.latest.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_window_grows_with_acks() {
+        let mut controller = RateController::new();
+        let start = controller.window();
+        for _ in 0..10 {
+            controller.on_send();
+            controller.on_ack(Some(Duration::from_millis(1)));
+        }
+        assert_eq!(controller.window(), start + 10);
+        assert_eq!(controller.loss_rate(), 0.0);
+    }
+
+    #[test]
+    fn test_loss_halves_window_once_per_round_trip() {
+        let mut controller = RateController::new();
+        for _ in 0..28 {
+            controller.on_send();
+            controller.on_ack(Some(Duration::from_millis(50)));
+        }
+        assert_eq!(controller.window(), 32);
+
+        controller.on_loss();
+        assert_eq!(controller.window(), 16);
+        // Further losses from the same window don't shrink it again
+        controller.on_loss();
+        assert_eq!(controller.window(), 16);
+        assert!(controller.loss_rate() > 0.0);
+
+        // Past the threshold growth is roughly one chunk per window of ACKs
+        for _ in 0..16 {
+            controller.on_ack(Some(Duration::from_millis(50)));
+        }
+        assert_eq!(controller.window(), 16);
+    }
+
+    #[test]
+    fn test_window_never_drops_below_minimum() {
+        let mut controller = RateController::new();
+        for _ in 0..10 {
+            controller.on_loss();
+            controller.last_decrease = None;
+        }
+        assert_eq!(controller.window(), MIN_WINDOW as usize);
+    }
+
+    #[test]
+    fn test_retransmit_timeout_tracks_rtt() {
+        let mut controller = RateController::new();
+        assert_eq!(controller.retransmit_timeout(), INITIAL_RTO);
+        for _ in 0..20 {
+            controller.on_ack(Some(Duration::from_millis(100)));
+        }
+        let rto = controller.retransmit_timeout();
+        assert!(rto >= Duration::from_millis(100) && rto < Duration::from_millis(200));
+
+        controller.on_ack(Some(Duration::from_secs(10)));
+        assert_eq!(controller.retransmit_timeout(), MAX_RTO);
+    }
+}
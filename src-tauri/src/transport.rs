@@ -0,0 +1,400 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use get_if_addrs::{get_if_addrs, IfAddr};
+use tokio::net::UdpSocket;
+
+/// A bound datagram endpoint used for discovery and text traffic
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Send a single datagram to `target`
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize>;
+
+    /// Receive a single datagram, returning its length and source address
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)>;
+
+    /// Address this endpoint is bound to
+    fn local_addr(&self) -> Result<SocketAddr>;
+
+    /// Addresses that reach every listener on `port` in the local network
+    fn broadcast_targets(&self, port: u16) -> Vec<SocketAddr>;
+
+    /// Also receive datagrams sent to the IPv4 multicast `group`
+    fn join_multicast(&self, group: Ipv4Addr) -> Result<()>;
+}
+
+/// Creates bound transports; one per broadcaster, listener or sender
+#[async_trait]
+pub trait TransportFactory: Send + Sync {
+    /// Bind a new endpoint on `port` (0 picks an ephemeral port)
+    async fn bind(&self, port: u16) -> Result<Arc<dyn Transport>>;
+}
+
+/// Transport backed by a real `tokio::net::UdpSocket`
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+#[async_trait]
+impl Transport for UdpTransport {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize> {
+        Ok(self.socket.send_to(buf, target).await?)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        Ok(self.socket.recv_from(buf).await?)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    fn broadcast_targets(&self, port: u16) -> Vec<SocketAddr> {
+        ipv4_broadcast_targets(port)
+    }
+
+    fn join_multicast(&self, group: Ipv4Addr) -> Result<()> {
+        self.socket.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED)
+            .with_context(|| format!("Failed to join multicast group {}", group))
+    }
+}
+
+/// Factory binding UDP sockets on all IPv4 interfaces
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UdpTransportFactory;
+
+#[async_trait]
+impl TransportFactory for UdpTransportFactory {
+    async fn bind(&self, port: u16) -> Result<Arc<dyn Transport>> {
+        let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))
+            .await
+            .with_context(|| format!("Failed to bind UDP socket on port {}", port))?;
+
+        socket.set_broadcast(true)
+            .context("Failed to enable broadcast on UDP socket")?;
+
+        Ok(Arc::new(UdpTransport { socket }))
+    }
+}
+
+/// Compute broadcast targets for all non-loopback IPv4 interfaces.
+fn ipv4_broadcast_targets(port: u16) -> Vec<SocketAddr> {
+    let mut targets: Vec<SocketAddr> = Vec::new();
+    if let Ok(ifaces) = get_if_addrs() {
+        for iface in ifaces {
+            // Only consider IPv4 addresses
+            let (ip, mask, broadcast_opt) = match iface.addr {
+                IfAddr::V4(v4) => (v4.ip, v4.netmask, v4.broadcast),
+                _ => continue,
+            };
+            // Skip loopback and link-local 169.254.x.x
+            let oct0 = ip.octets()[0];
+            if ip.is_loopback() || oct0 == 169 { continue; }
+
+            let bcast = if let Some(b) = broadcast_opt { b } else {
+                let bcast_u32 = u32::from(ip) | !u32::from(mask);
+                Ipv4Addr::from(bcast_u32)
+            };
+            targets.push(SocketAddr::new(IpAddr::V4(bcast), port));
+        }
+    }
+    targets.sort();
+    targets.dedup();
+    // Always include 255.255.255.255 as a last resort
+    targets.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), port));
+    targets.sort();
+    targets.dedup();
+    targets
+}
+
+/// In-process network connecting several services without touching real sockets
+#[cfg(test)]
+pub mod memory {
+    use std::collections::{HashMap, HashSet};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::{anyhow, Result};
+    use async_trait::async_trait;
+    use tokio::sync::mpsc;
+
+    use super::{Transport, TransportFactory};
+    use crate::netsim::{LinkConditions, LinkStats, LossyLink};
+
+    type Datagram = (Vec<u8>, SocketAddr);
+
+    const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+    #[derive(Default)]
+    struct NetworkState {
+        endpoints: HashMap<SocketAddr, mpsc::UnboundedSender<Datagram>>,
+        groups: HashMap<Ipv4Addr, HashSet<SocketAddr>>,
+        next_ephemeral_port: u16,
+        link: Option<LossyLink>,
+    }
+
+    fn multicast_group(target: SocketAddr) -> Option<Ipv4Addr> {
+        match target.ip() {
+            IpAddr::V4(ip) if ip.is_multicast() => Some(ip),
+            _ => None,
+        }
+    }
+
+    /// Shared virtual LAN; every host created from it can reach every other
+    #[derive(Clone, Default)]
+    pub struct MemoryNetwork {
+        state: Arc<Mutex<NetworkState>>,
+    }
+
+    impl MemoryNetwork {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// A network whose datagrams pass through a seeded lossy link
+        pub fn with_link(conditions: LinkConditions, seed: u64) -> Self {
+            let network = Self::default();
+            network.state.lock().unwrap().link = Some(LossyLink::new(conditions, seed));
+            network
+        }
+
+        /// What the lossy link has done so far, if one is configured
+        pub fn link_stats(&self) -> Option<LinkStats> {
+            self.state.lock().unwrap().link.as_ref().map(|link| link.stats())
+        }
+
+        /// A transport factory whose endpoints live at `ip` on this network
+        pub fn host(&self, ip: Ipv4Addr) -> Arc<MemoryHost> {
+            Arc::new(MemoryHost {
+                network: self.clone(),
+                ip: IpAddr::V4(ip),
+            })
+        }
+
+        /// Deliver a datagram to every endpoint it is addressed to
+        pub(crate) fn deliver(&self, data: &[u8], from: SocketAddr, target: SocketAddr) {
+            let mut state = self.state.lock().unwrap();
+            let recipients: Vec<mpsc::UnboundedSender<Datagram>> = if target.ip() == IpAddr::V4(Ipv4Addr::BROADCAST) {
+                state.endpoints.iter()
+                    .filter(|(addr, _)| addr.port() == target.port())
+                    .map(|(_, tx)| tx.clone())
+                    .collect()
+            } else if let Some(members) = multicast_group(target).and_then(|group| state.groups.get(&group)) {
+                members.iter()
+                    .filter(|addr| addr.port() == target.port())
+                    .filter_map(|addr| state.endpoints.get(addr).cloned())
+                    .collect()
+            } else if multicast_group(target).is_some() {
+                // Nobody joined this group
+                Vec::new()
+            } else {
+                state.endpoints.get(&target).cloned().into_iter().collect()
+            };
+
+            for tx in recipients {
+                let Some(link) = state.link.as_mut() else {
+                    let _ = tx.send((data.to_vec(), from));
+                    continue;
+                };
+                for delivery in link.transmit(data) {
+                    if delivery.delay.is_zero() {
+                        let _ = tx.send((delivery.data, from));
+                    } else {
+                        let tx = tx.clone();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(delivery.delay).await;
+                            let _ = tx.send((delivery.data, from));
+                        });
+                    }
+                }
+            }
+        }
+
+        fn register(&self, ip: IpAddr, port: u16) -> Result<(SocketAddr, mpsc::UnboundedReceiver<Datagram>)> {
+            let mut state = self.state.lock().unwrap();
+            let port = if port == 0 {
+                if state.next_ephemeral_port < FIRST_EPHEMERAL_PORT {
+                    state.next_ephemeral_port = FIRST_EPHEMERAL_PORT;
+                }
+                let port = state.next_ephemeral_port;
+                state.next_ephemeral_port = state.next_ephemeral_port.checked_add(1)
+                    .ok_or_else(|| anyhow!("Out of ephemeral ports"))?;
+                port
+            } else {
+                port
+            };
+
+            let addr = SocketAddr::new(ip, port);
+            if state.endpoints.contains_key(&addr) {
+                return Err(anyhow!("Address {} already in use", addr));
+            }
+            let (tx, rx) = mpsc::unbounded_channel();
+            state.endpoints.insert(addr, tx);
+            Ok((addr, rx))
+        }
+
+        fn unregister(&self, addr: &SocketAddr) {
+            let mut state = self.state.lock().unwrap();
+            state.endpoints.remove(addr);
+            for members in state.groups.values_mut() {
+                members.remove(addr);
+            }
+        }
+
+        fn join(&self, group: Ipv4Addr, addr: SocketAddr) {
+            self.state.lock().unwrap().groups.entry(group).or_default().insert(addr);
+        }
+    }
+
+    /// One machine on a `MemoryNetwork`
+    pub struct MemoryHost {
+        network: MemoryNetwork,
+        ip: IpAddr,
+    }
+
+    #[async_trait]
+    impl TransportFactory for MemoryHost {
+        async fn bind(&self, port: u16) -> Result<Arc<dyn Transport>> {
+            let (local_addr, rx) = self.network.register(self.ip, port)?;
+            Ok(Arc::new(MemoryTransport {
+                network: self.network.clone(),
+                local_addr,
+                rx: tokio::sync::Mutex::new(rx),
+            }))
+        }
+    }
+
+    /// Endpoint bound on a `MemoryHost`
+    pub struct MemoryTransport {
+        network: MemoryNetwork,
+        local_addr: SocketAddr,
+        rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<Datagram>>,
+    }
+
+    #[async_trait]
+    impl Transport for MemoryTransport {
+        async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize> {
+            self.network.deliver(buf, self.local_addr, target);
+            Ok(buf.len())
+        }
+
+        async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+            let (data, from) = self.rx.lock().await.recv().await
+                .ok_or_else(|| anyhow!("Memory network closed"))?;
+            // Mirror UDP semantics: oversized datagrams are truncated
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok((len, from))
+        }
+
+        fn local_addr(&self) -> Result<SocketAddr> {
+            Ok(self.local_addr)
+        }
+
+        fn broadcast_targets(&self, port: u16) -> Vec<SocketAddr> {
+            vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), port)]
+        }
+
+        fn join_multicast(&self, group: Ipv4Addr) -> Result<()> {
+            if !group.is_multicast() {
+                return Err(anyhow!("{} is not a multicast address", group));
+            }
+            self.network.join(group, self.local_addr);
+            Ok(())
+        }
+    }
+
+    impl Drop for MemoryTransport {
+        fn drop(&mut self) {
+            self.network.unregister(&self.local_addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::memory::MemoryNetwork;
+    use super::*;
+
+    #[test]
+    fn test_broadcast_targets_use_requested_port() {
+        let targets = ipv4_broadcast_targets(9999);
+        assert!(!targets.is_empty());
+        assert!(targets.iter().all(|addr| addr.port() == 9999));
+        assert!(targets.contains(&SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), 9999)));
+    }
+
+    #[tokio::test]
+    async fn test_memory_unicast_delivery() {
+        let network = MemoryNetwork::new();
+        let a = network.host(Ipv4Addr::new(10, 0, 0, 1)).bind(0).await.unwrap();
+        let b = network.host(Ipv4Addr::new(10, 0, 0, 2)).bind(7878).await.unwrap();
+
+        a.send_to(b"hello", b.local_addr().unwrap()).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        let (len, from) = b.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(from, a.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_memory_broadcast_reaches_all_listeners_on_port() {
+        let network = MemoryNetwork::new();
+        let sender = network.host(Ipv4Addr::new(10, 0, 0, 1)).bind(0).await.unwrap();
+        let l1 = network.host(Ipv4Addr::new(10, 0, 0, 2)).bind(7878).await.unwrap();
+        let l2 = network.host(Ipv4Addr::new(10, 0, 0, 3)).bind(7878).await.unwrap();
+        let other = network.host(Ipv4Addr::new(10, 0, 0, 4)).bind(9000).await.unwrap();
+
+        for target in sender.broadcast_targets(7878) {
+            sender.send_to(b"announce", target).await.unwrap();
+        }
+
+        let mut buf = [0u8; 64];
+        for listener in [&l1, &l2] {
+            let (len, _) = listener.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"announce");
+        }
+        let pending = tokio::time::timeout(
+            std::time::Duration::from_millis(50),
+            other.recv_from(&mut buf),
+        ).await;
+        assert!(pending.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_memory_multicast_reaches_group_members_only() {
+        let network = MemoryNetwork::new();
+        let group = Ipv4Addr::new(239, 255, 78, 78);
+        let sender = network.host(Ipv4Addr::new(10, 0, 0, 1)).bind(0).await.unwrap();
+        let member = network.host(Ipv4Addr::new(10, 0, 0, 2)).bind(7878).await.unwrap();
+        let outsider = network.host(Ipv4Addr::new(10, 0, 0, 3)).bind(7878).await.unwrap();
+        member.join_multicast(group).unwrap();
+        assert!(outsider.join_multicast(Ipv4Addr::new(10, 0, 0, 1)).is_err());
+
+        sender.send_to(b"to the group", SocketAddr::new(IpAddr::V4(group), 7878)).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        let (len, _) = member.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"to the group");
+        let pending = tokio::time::timeout(
+            std::time::Duration::from_millis(50),
+            outsider.recv_from(&mut buf),
+        ).await;
+        assert!(pending.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_memory_port_conflict_and_release() {
+        let network = MemoryNetwork::new();
+        let host = network.host(Ipv4Addr::new(10, 0, 0, 1));
+
+        let first = host.bind(7878).await.unwrap();
+        assert!(host.bind(7878).await.is_err());
+
+        drop(first);
+        assert!(host.bind(7878).await.is_ok());
+    }
+}